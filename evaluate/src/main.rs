@@ -21,7 +21,7 @@ fn build_xpath(xpath_str: &str) -> XPath {
 
     factory
         .build(xpath_str)
-        .unwrap_or_else(|e| panic!("Unable to compile XPath {}: {}", xpath_str, e))
+        .unwrap_or_else(|e| panic!("Unable to compile XPath:\n{}", e.render(xpath_str)))
 }
 
 fn load_xml<R>(input: R) -> sxd_document::Package
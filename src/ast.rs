@@ -0,0 +1,729 @@
+//! An owned representation of an XPath expression.
+//!
+//! The [`Factory`][] parses strings into an [`Expr`][], which can be
+//! inspected, modified, or built by hand and then compiled into an
+//! [`XPath`][] for evaluation.
+//!
+//! # Examples
+//!
+//! ```
+//! use sxd_xpath::ast::{Axis, Expr, NameTest, NodeTest, Step};
+//! use sxd_xpath::{Factory, XPath};
+//!
+//! let parsed = Factory::new().parse("/book").expect("Could not parse XPath");
+//!
+//! let book = NameTest {
+//!     prefix: None,
+//!     local_part: "book".to_string(),
+//! };
+//! let built = Expr::Path {
+//!     start: Box::new(Expr::RootNode),
+//!     steps: vec![Step::new(Axis::Child, NodeTest::Name(book))],
+//! };
+//!
+//! assert_eq!(parsed, built);
+//!
+//! let xpath = XPath::from(built);
+//! assert_eq!(xpath.to_string(), "/child::book");
+//! ```
+//!
+//! # Stability
+//!
+//! New syntax is represented by new variants of [`Expr`][] and
+//! [`BinaryOperator`][]. As these break code that matches every
+//! variant, they are only added in releases that are not
+//! semver-compatible with the previous one.
+//!
+//! [`Factory`]: ../struct.Factory.html
+//! [`Expr`]: enum.Expr.html
+//! [`BinaryOperator`]: enum.BinaryOperator.html
+//! [`XPath`]: ../struct.XPath.html
+
+use crate::axis::{AxisLike, PrincipalNodeType};
+use crate::expression::{
+    self, Expression, MathOperator, Predicate, RelationalOperator, StepTest, SubExpression,
+};
+use crate::node_test::{self, SubNodeTest};
+use crate::visitor::{Visitable, Visitor};
+use crate::{LiteralValue, OwnedPrefixedName, Value};
+
+pub use crate::axis::Axis;
+pub use crate::expression::Quantifier;
+pub use crate::node_test::NameTest;
+
+/// An XPath expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Two expressions joined by an operator, such as `a + b`
+    Binary {
+        left: Box<Expr>,
+        operator: BinaryOperator,
+        right: Box<Expr>,
+    },
+    /// The numeric negation of an expression, such as `-a`
+    Negation(Box<Expr>),
+    /// The nodes of an expression that satisfy a predicate, such as
+    /// `$a[1]`
+    Filter {
+        expression: Box<Expr>,
+        predicate: Box<Expr>,
+    },
+    /// A series of steps taken from the nodes of an expression. The
+    /// start is usually `RootNode` or `ContextNode`, as in `/a` and
+    /// `a` respectively.
+    Path { start: Box<Expr>, steps: Vec<Step> },
+    /// A constant value, such as `'a'` or `42`
+    Literal(Literal),
+    /// A reference to a variable, such as `$a`
+    Variable(OwnedPrefixedName),
+    /// A call to a function, such as `count(a)`
+    Function {
+        name: OwnedPrefixedName,
+        arguments: Vec<Expr>,
+    },
+    /// One of two expressions, chosen by a condition, such as
+    /// `if ($a) then b else c`. Only available from XPath 2.0.
+    Conditional {
+        condition: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// A test of the items of a sequence, such as
+    /// `some $a in b satisfies $a = 1`. Only available from XPath 2.0.
+    Quantified {
+        quantifier: Quantifier,
+        variable: OwnedPrefixedName,
+        sequence: Box<Expr>,
+        condition: Box<Expr>,
+    },
+    /// The nodes selected by an expression for each item of a
+    /// sequence, such as `for $a in b return $a/c`. Only available
+    /// from XPath 2.0.
+    For {
+        variable: OwnedPrefixedName,
+        sequence: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// An expression that refers to a value by name, such as
+    /// `let $a := b return $a + 1`. Only available from XPath 3.0.
+    Let {
+        variable: OwnedPrefixedName,
+        value: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// The items of several expressions, such as `(1, a, $b)`, or
+    /// none at all, as in `()`. Only available from XPath 2.0.
+    Sequence(Vec<Expr>),
+    /// The root of the document containing the context node
+    RootNode,
+    /// The context node
+    ContextNode,
+}
+
+impl Expr {
+    /// Joins two expressions with an operator.
+    pub fn binary(left: Expr, operator: BinaryOperator, right: Expr) -> Expr {
+        Expr::Binary {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    pub(crate) fn compile(self) -> SubExpression {
+        match self {
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => operator.compile(left.compile(), right.compile()),
+            Expr::Negation(expression) => Box::new(expression::Negation {
+                expression: expression.compile(),
+            }),
+            Expr::Filter {
+                expression,
+                predicate,
+            } => expression::Filter::new(
+                expression.compile(),
+                expression::Predicate::new(*predicate),
+            ),
+            Expr::Path { start, steps } => {
+                let joined = Step::join_descendants(&steps);
+                let steps = steps.into_iter().map(Step::compile).collect();
+                expression::Path::new(start.compile(), steps, joined)
+            }
+            Expr::Literal(literal) => Box::new(expression::Literal::from(literal.into_value())),
+            Expr::Variable(name) => Box::new(expression::Variable { name }),
+            Expr::Function { name, arguments } => {
+                let arguments = arguments.into_iter().map(Expr::compile).collect();
+                Box::new(expression::Function { name, arguments })
+            }
+            Expr::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => Box::new(expression::Conditional {
+                condition: condition.compile(),
+                then: then.compile(),
+                otherwise: otherwise.compile(),
+            }),
+            Expr::Quantified {
+                quantifier,
+                variable,
+                sequence,
+                condition,
+            } => Box::new(expression::Quantified {
+                quantifier,
+                variable,
+                sequence: sequence.compile(),
+                condition: condition.compile(),
+            }),
+            Expr::For {
+                variable,
+                sequence,
+                body,
+            } => Box::new(expression::For {
+                variable,
+                sequence: sequence.compile(),
+                body: body.compile(),
+            }),
+            Expr::Let {
+                variable,
+                value,
+                body,
+            } => Box::new(expression::Let {
+                variable,
+                value: value.compile(),
+                body: body.compile(),
+            }),
+            Expr::Sequence(expressions) => Box::new(expression::Sequence {
+                expressions: expressions.into_iter().map(Expr::compile).collect(),
+            }),
+            Expr::RootNode => Box::new(expression::RootNode),
+            Expr::ContextNode => Box::new(expression::ContextNode),
+        }
+    }
+}
+
+/// The operators that join two expressions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryOperator {
+    /// `or`
+    Or,
+    /// `and`
+    And,
+    /// `=`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `<`
+    LessThan,
+    /// `<=`
+    LessThanOrEqual,
+    /// `>`
+    GreaterThan,
+    /// `>=`
+    GreaterThanOrEqual,
+    /// `+`
+    Addition,
+    /// `-`
+    Subtraction,
+    /// `*`
+    Multiplication,
+    /// `div`
+    Division,
+    /// `mod`
+    Remainder,
+    /// `|`
+    Union,
+    /// `intersect`, only available from XPath 2.0
+    Intersect,
+    /// `except`, only available from XPath 2.0
+    Except,
+    /// `to`, only available from XPath 2.0
+    Range,
+}
+
+impl BinaryOperator {
+    fn compile(self, left: SubExpression, right: SubExpression) -> SubExpression {
+        use self::BinaryOperator::*;
+
+        match self {
+            Or => expression::Or::new(left, right),
+            And => expression::And::new(left, right),
+            Equal => expression::Equal::new(left, right),
+            NotEqual => expression::NotEqual::new(left, right),
+            LessThan => expression::Relational::new(left, right, RelationalOperator::LessThan),
+            LessThanOrEqual => {
+                expression::Relational::new(left, right, RelationalOperator::LessThanOrEqual)
+            }
+            GreaterThan => {
+                expression::Relational::new(left, right, RelationalOperator::GreaterThan)
+            }
+            GreaterThanOrEqual => {
+                expression::Relational::new(left, right, RelationalOperator::GreaterThanOrEqual)
+            }
+            Addition => expression::Math::new(left, right, MathOperator::Addition),
+            Subtraction => expression::Math::new(left, right, MathOperator::Subtraction),
+            Multiplication => expression::Math::new(left, right, MathOperator::Multiplication),
+            Division => expression::Math::new(left, right, MathOperator::Division),
+            Remainder => expression::Math::new(left, right, MathOperator::Remainder),
+            Union => expression::Union::new(left, right),
+            Intersect => expression::Intersect::new(left, right),
+            Except => expression::Except::new(left, right),
+            Range => expression::Range::new(left, right),
+        }
+    }
+}
+
+/// A constant value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    /// XPath 1.0 has no syntax for boolean constants; these are written
+    /// as `true()` or `false()`.
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Literal {
+    fn into_value(self) -> Value<'static> {
+        match self {
+            Literal::Boolean(b) => Value::Boolean(b),
+            Literal::Number(n) => Value::Number(n),
+            Literal::String(s) => Value::String(s),
+        }
+    }
+}
+
+/// One step of a location path, such as `child::a[1]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub axis: Axis,
+    pub node_test: NodeTest,
+    pub predicates: Vec<Expr>,
+}
+
+impl Step {
+    /// Creates a step without any predicates.
+    pub fn new(axis: Axis, node_test: NodeTest) -> Step {
+        Step {
+            axis,
+            node_test,
+            predicates: Vec::new(),
+        }
+    }
+
+    /// Finds each `descendant-or-self::node()/child::a` in the steps,
+    /// giving the `descendant::a` step that selects the same nodes
+    /// along with the index of the first step of the pair.
+    fn join_descendants(steps: &[Step]) -> Vec<(usize, expression::Step)> {
+        let any_descendant = |step: &Step| {
+            step.axis == Axis::DescendantOrSelf
+                && step.node_test == NodeTest::Node
+                && step.predicates.is_empty()
+        };
+        // Positions in a predicate would count from a different node
+        let joinable = |step: &Step| step.axis == Axis::Child && step.predicates.is_empty();
+
+        steps
+            .windows(2)
+            .enumerate()
+            .filter(|&(_, pair)| any_descendant(&pair[0]) && joinable(&pair[1]))
+            .map(|(index, pair)| {
+                let step = Step::new(Axis::Descendant, pair[1].node_test.clone());
+                (index, step.compile())
+            })
+            .collect()
+    }
+
+    pub(crate) fn compile(self) -> expression::Step {
+        let node_test = self.node_test.compile(self.axis);
+        let predicates = self
+            .predicates
+            .into_iter()
+            .map(expression::Predicate::new)
+            .collect();
+        expression::Step::new(self.axis, node_test, predicates)
+    }
+}
+
+/// Selects which of the nodes along an axis are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeTest {
+    /// Nodes of the axis' principal node type with a matching name,
+    /// such as `a`, `p:a`, `p:*`, or `*`. Attributes are selected on
+    /// the `attribute` axis, namespaces on the `namespace` axis, and
+    /// elements everywhere else.
+    Name(NameTest),
+    /// `node()`
+    Node,
+    /// `text()`
+    Text,
+    /// `comment()`
+    Comment,
+    /// `processing-instruction()`, optionally with a target
+    ProcessingInstruction(Option<String>),
+}
+
+impl NodeTest {
+    pub(crate) fn compile(self, axis: Axis) -> SubNodeTest {
+        match self {
+            NodeTest::Name(name) => match axis.principal_node_type() {
+                PrincipalNodeType::Attribute => Box::new(node_test::Attribute::new(name)),
+                PrincipalNodeType::Element => Box::new(node_test::Element::new(name)),
+                PrincipalNodeType::Namespace => Box::new(node_test::Namespace::new(name)),
+            },
+            NodeTest::Node => Box::new(node_test::Node),
+            NodeTest::Text => Box::new(node_test::Text),
+            NodeTest::Comment => Box::new(node_test::Comment),
+            NodeTest::ProcessingInstruction(target) => {
+                Box::new(node_test::ProcessingInstruction::new(target))
+            }
+        }
+    }
+}
+
+/// Rebuilds the `Expr` that a compiled expression came from.
+pub(crate) fn decompile(expression: &dyn Expression) -> Expr {
+    expr_of(expression)
+}
+
+#[derive(Default)]
+struct Decompiler {
+    expr: Option<Expr>,
+    step: Option<Step>,
+    node_test: Option<NodeTest>,
+}
+
+fn expr_of<V>(visitable: &V) -> Expr
+where
+    V: Visitable + ?Sized,
+{
+    let mut decompiler = Decompiler::default();
+    visitable.visit(&mut decompiler);
+    decompiler
+        .expr
+        .take()
+        .expect("Expression was not decompiled")
+}
+
+fn step_of(step: &expression::Step) -> Step {
+    let mut decompiler = Decompiler::default();
+    step.visit(&mut decompiler);
+    decompiler.step.take().expect("Step was not decompiled")
+}
+
+fn node_test_of(node_test: &StepTest) -> NodeTest {
+    let mut decompiler = Decompiler::default();
+    node_test.visit(&mut decompiler);
+    decompiler
+        .node_test
+        .take()
+        .expect("Node test was not decompiled")
+}
+
+fn name_test(prefix: Option<&str>, local_part: &str) -> NodeTest {
+    NodeTest::Name(NameTest {
+        prefix: prefix.map(Into::into),
+        local_part: local_part.into(),
+    })
+}
+
+impl Decompiler {
+    fn binary(&mut self, left: &SubExpression, operator: BinaryOperator, right: &SubExpression) {
+        self.expr = Some(Expr::binary(expr_of(left), operator, expr_of(right)));
+    }
+}
+
+impl Visitor for Decompiler {
+    fn visit_and(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::And, right);
+    }
+
+    fn visit_attribute(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.node_test = Some(name_test(prefix, local_part));
+    }
+
+    fn visit_comment(&mut self) {
+        self.node_test = Some(NodeTest::Comment);
+    }
+
+    fn visit_conditional(
+        &mut self,
+        condition: &SubExpression,
+        then: &SubExpression,
+        otherwise: &SubExpression,
+    ) {
+        self.expr = Some(Expr::Conditional {
+            condition: Box::new(expr_of(condition)),
+            then: Box::new(expr_of(then)),
+            otherwise: Box::new(expr_of(otherwise)),
+        });
+    }
+
+    fn visit_context_node(&mut self) {
+        self.expr = Some(Expr::ContextNode);
+    }
+
+    fn visit_element(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.node_test = Some(name_test(prefix, local_part));
+    }
+
+    fn visit_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Equal, right);
+    }
+
+    fn visit_except(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Except, right);
+    }
+
+    fn visit_filter(&mut self, node_selector: &SubExpression, predicate: &Predicate) {
+        self.expr = Some(Expr::Filter {
+            expression: Box::new(expr_of(node_selector)),
+            predicate: Box::new(expr_of(&predicate.expression)),
+        });
+    }
+
+    fn visit_for(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        body: &SubExpression,
+    ) {
+        self.expr = Some(Expr::For {
+            variable: variable.into(),
+            sequence: Box::new(expr_of(sequence)),
+            body: Box::new(expr_of(body)),
+        });
+    }
+
+    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        self.expr = Some(Expr::Function {
+            name: name.into(),
+            arguments: arguments.iter().map(expr_of).collect(),
+        });
+    }
+
+    fn visit_intersect(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Intersect, right);
+    }
+
+    fn visit_let(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        value: &SubExpression,
+        body: &SubExpression,
+    ) {
+        self.expr = Some(Expr::Let {
+            variable: variable.into(),
+            value: Box::new(expr_of(value)),
+            body: Box::new(expr_of(body)),
+        });
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue) {
+        let literal = match *value {
+            Value::Boolean(b) => Literal::Boolean(b),
+            Value::Number(n) => Literal::Number(n),
+            Value::String(ref s) => Literal::String(s.clone()),
+            Value::Nodeset(..) => unreachable!("Literals are never nodesets"),
+            Value::Sequence(..) => unreachable!("Literals are never sequences"),
+        };
+        self.expr = Some(Expr::Literal(literal));
+    }
+
+    fn visit_math(&mut self, left: &SubExpression, right: &SubExpression, operator: MathOperator) {
+        let operator = match operator {
+            MathOperator::Addition => BinaryOperator::Addition,
+            MathOperator::Subtraction => BinaryOperator::Subtraction,
+            MathOperator::Multiplication => BinaryOperator::Multiplication,
+            MathOperator::Division => BinaryOperator::Division,
+            MathOperator::Remainder => BinaryOperator::Remainder,
+        };
+        self.binary(left, operator, right);
+    }
+
+    fn visit_namespace(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.node_test = Some(name_test(prefix, local_part));
+    }
+
+    fn visit_node(&mut self) {
+        self.node_test = Some(NodeTest::Node);
+    }
+
+    fn visit_negation(&mut self, expression: &SubExpression) {
+        self.expr = Some(Expr::Negation(Box::new(expr_of(expression))));
+    }
+
+    fn visit_not_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::NotEqual, right);
+    }
+
+    fn visit_or(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Or, right);
+    }
+
+    fn visit_path(&mut self, start_point: &SubExpression, steps: &[expression::Step]) {
+        self.expr = Some(Expr::Path {
+            start: Box::new(expr_of(start_point)),
+            steps: steps.iter().map(step_of).collect(),
+        });
+    }
+
+    fn visit_processing_instruction(&mut self, target: Option<&str>) {
+        self.node_test = Some(NodeTest::ProcessingInstruction(target.map(Into::into)));
+    }
+
+    fn visit_quantified(
+        &mut self,
+        quantifier: Quantifier,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        condition: &SubExpression,
+    ) {
+        self.expr = Some(Expr::Quantified {
+            quantifier,
+            variable: variable.into(),
+            sequence: Box::new(expr_of(sequence)),
+            condition: Box::new(expr_of(condition)),
+        });
+    }
+
+    fn visit_range(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Range, right);
+    }
+
+    fn visit_relational(
+        &mut self,
+        left: &SubExpression,
+        right: &SubExpression,
+        operator: RelationalOperator,
+    ) {
+        let operator = match operator {
+            RelationalOperator::LessThan => BinaryOperator::LessThan,
+            RelationalOperator::LessThanOrEqual => BinaryOperator::LessThanOrEqual,
+            RelationalOperator::GreaterThan => BinaryOperator::GreaterThan,
+            RelationalOperator::GreaterThanOrEqual => BinaryOperator::GreaterThanOrEqual,
+        };
+        self.binary(left, operator, right);
+    }
+
+    fn visit_root_node(&mut self) {
+        self.expr = Some(Expr::RootNode);
+    }
+
+    fn visit_sequence(&mut self, expressions: &[SubExpression]) {
+        self.expr = Some(Expr::Sequence(expressions.iter().map(expr_of).collect()));
+    }
+
+    fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
+        self.step = Some(Step {
+            axis: *axis,
+            node_test: node_test_of(node_test),
+            predicates: predicates
+                .iter()
+                .map(|predicate| expr_of(&predicate.expression))
+                .collect(),
+        });
+    }
+
+    fn visit_text(&mut self) {
+        self.node_test = Some(NodeTest::Text);
+    }
+
+    fn visit_union(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, BinaryOperator::Union, right);
+    }
+
+    fn visit_variable(&mut self, name: &OwnedPrefixedName) {
+        self.expr = Some(Expr::Variable(name.into()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Factory, XPath};
+
+    use super::*;
+
+    fn name(local_part: &str) -> NodeTest {
+        NodeTest::Name(NameTest {
+            prefix: None,
+            local_part: local_part.to_string(),
+        })
+    }
+
+    fn parse(xpath: &str) -> Expr {
+        Factory::new().parse(xpath).expect("Unable to parse XPath")
+    }
+
+    #[test]
+    fn abbreviated_steps_are_expanded() {
+        let mut attribute = Step::new(Axis::Attribute, name("b"));
+        attribute
+            .predicates
+            .push(Expr::Literal(Literal::Number(1.0)));
+
+        assert_eq!(
+            parse("//a/@b[1]"),
+            Expr::Path {
+                start: Box::new(Expr::RootNode),
+                steps: vec![
+                    Step::new(Axis::DescendantOrSelf, NodeTest::Node),
+                    Step::new(Axis::Child, name("a")),
+                    attribute,
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        let number = |n| Expr::Literal(Literal::Number(n));
+
+        assert_eq!(
+            parse("1 - 2 - -3"),
+            Expr::binary(
+                Expr::binary(number(1.0), BinaryOperator::Subtraction, number(2.0)),
+                BinaryOperator::Subtraction,
+                Expr::Negation(Box::new(number(3.0))),
+            )
+        );
+    }
+
+    #[test]
+    fn filters_functions_and_variables_are_parsed() {
+        assert_eq!(
+            parse("f:count($v)[2]"),
+            Expr::Filter {
+                expression: Box::new(Expr::Function {
+                    name: ("f", "count").into(),
+                    arguments: vec![Expr::Variable("v".into())],
+                }),
+                predicate: Box::new(Expr::Literal(Literal::Number(2.0))),
+            }
+        );
+    }
+
+    #[test]
+    fn name_tests_select_the_principal_node_type_when_compiled() {
+        let expr = Expr::binary(
+            Expr::Path {
+                start: Box::new(Expr::ContextNode),
+                steps: vec![Step::new(Axis::Namespace, name("*"))],
+            },
+            BinaryOperator::Union,
+            Expr::Path {
+                start: Box::new(Expr::ContextNode),
+                steps: vec![Step::new(Axis::Attribute, name("a"))],
+            },
+        );
+
+        let compiled = XPath::from(expr.clone());
+        let reparsed = Factory::new().build(&compiled.to_string()).unwrap();
+
+        assert_eq!(format!("{:?}", compiled), format!("{:?}", reparsed));
+        assert_eq!(parse(&compiled.to_string()), expr);
+    }
+}
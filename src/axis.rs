@@ -1,6 +1,7 @@
 use std::fmt;
 
 use crate::context;
+use crate::expression::Error;
 use crate::node_test::NodeTest;
 use crate::nodeset::{self, Node, OrderedNodes};
 
@@ -14,20 +15,32 @@ pub enum PrincipalNodeType {
 /// A directed traversal of Nodes.
 pub trait AxisLike: fmt::Debug {
     /// Applies the given node test to the nodes selected by this axis,
-    /// adding matching nodes to the nodeset.
+    /// adding matching nodes to the nodeset. Fails if the evaluation
+    /// visits too many nodes or is cancelled.
+    ///
+    /// When a `limit` is given, selection stops once that many nodes
+    /// have matched.
     fn select_nodes<'c, 'd>(
         &self,
         context: &context::Evaluation<'c, 'd>,
         node_test: &dyn NodeTest,
-    ) -> OrderedNodes<'d>;
+        limit: Option<usize>,
+    ) -> Result<OrderedNodes<'d>, Error>;
 
     /// Describes what node type is naturally selected by this axis.
     fn principal_node_type(&self) -> PrincipalNodeType {
         PrincipalNodeType::Element
     }
+
+    /// Whether this axis selects nodes in reverse document order.
+    fn is_reverse(&self) -> bool {
+        false
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// The direction a step travels from each context node.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Axis {
     Ancestor,
     AncestorOrSelf,
@@ -44,100 +57,174 @@ pub enum Axis {
     SelfAxis,
 }
 
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::Axis::*;
+        let name = match *self {
+            Ancestor => "ancestor",
+            AncestorOrSelf => "ancestor-or-self",
+            Attribute => "attribute",
+            Namespace => "namespace",
+            Child => "child",
+            Descendant => "descendant",
+            DescendantOrSelf => "descendant-or-self",
+            Parent => "parent",
+            PrecedingSibling => "preceding-sibling",
+            FollowingSibling => "following-sibling",
+            Preceding => "preceding",
+            Following => "following",
+            SelfAxis => "self",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a traversal of an axis ended before visiting every node.
+enum Stop {
+    /// As many nodes as were needed have been selected.
+    Full,
+    Failed(Error),
+}
+
+impl From<Error> for Stop {
+    fn from(other: Error) -> Stop {
+        Stop::Failed(other)
+    }
+}
+
 struct CompleteNodeTest<'c, 'd> {
     context: &'c context::Evaluation<'c, 'd>,
     node_test: &'c dyn NodeTest,
+    limit: Option<usize>,
     result: OrderedNodes<'d>,
 }
 
 impl<'c, 'd> CompleteNodeTest<'c, 'd> {
-    fn new(context: &'c context::Evaluation<'c, 'd>, node_test: &'c dyn NodeTest) -> Self {
+    fn new(
+        context: &'c context::Evaluation<'c, 'd>,
+        node_test: &'c dyn NodeTest,
+        limit: Option<usize>,
+    ) -> Self {
         CompleteNodeTest {
             context,
             node_test,
+            limit,
             result: OrderedNodes::new(),
         }
     }
 
-    fn run(&mut self, node: Node<'d>) {
+    fn run(&mut self, node: Node<'d>) -> Result<(), Stop> {
+        if let Some(limit) = self.limit {
+            if self.result.size() >= limit {
+                return Err(Stop::Full);
+            }
+        }
+
+        self.context.visit_node()?;
         let new_context = self.context.new_context_for(node);
         self.node_test.test(&new_context, &mut self.result);
+        Ok(())
     }
 }
 
-impl AxisLike for Axis {
-    fn select_nodes<'c, 'd>(
-        &self,
-        context: &context::Evaluation<'c, 'd>,
-        node_test: &dyn NodeTest,
-    ) -> OrderedNodes<'d> {
+impl Axis {
+    fn traverse<'d>(
+        self,
+        context_node: Node<'d>,
+        node_test: &mut CompleteNodeTest<'_, 'd>,
+    ) -> Result<(), Stop> {
         use self::Axis::*;
 
-        let mut node_test = CompleteNodeTest::new(context, node_test);
-
-        match *self {
-            Ancestor => each_parent(context.node, |n| node_test.run(n)),
-            AncestorOrSelf => node_and_each_parent(context.node, |n| node_test.run(n)),
+        match self {
+            Ancestor => each_parent(context_node, |n| node_test.run(n))?,
+            AncestorOrSelf => node_and_each_parent(context_node, |n| node_test.run(n))?,
             Attribute => {
-                if let Node::Element(ref e) = context.node {
+                if let Node::Element(ref e) = context_node {
                     for attr in e.attributes() {
-                        node_test.run(Node::Attribute(attr));
+                        node_test.run(Node::Attribute(attr))?;
                     }
                 }
             }
             Namespace => {
-                if let Node::Element(ref e) = context.node {
-                    for ns in e.namespaces_in_scope() {
-                        let ns = Node::Namespace(nodeset::Namespace {
-                            parent: *e,
-                            prefix: ns.prefix(),
-                            uri: ns.uri(),
-                        });
-
-                        node_test.run(ns);
+                if let Node::Element(e) = context_node {
+                    for ns in nodeset::Namespace::in_scope_of(e) {
+                        node_test.run(Node::Namespace(ns))?;
                     }
                 }
             }
             Child => {
-                for child in context.node.children() {
-                    node_test.run(child);
+                for child in context_node.children() {
+                    node_test.run(child)?;
                 }
             }
             Descendant => {
-                for child in context.node.children() {
-                    preorder_left_to_right(child, |n| node_test.run(n));
+                for child in context_node.children() {
+                    preorder_left_to_right(child, |n| node_test.run(n))?;
                 }
             }
-            DescendantOrSelf => preorder_left_to_right(context.node, |n| node_test.run(n)),
+            DescendantOrSelf => preorder_left_to_right(context_node, |n| node_test.run(n))?,
             Parent => {
-                if let Some(parent) = context.node.parent() {
-                    node_test.run(parent);
+                if let Some(parent) = context_node.parent() {
+                    node_test.run(parent)?;
                 }
             }
             PrecedingSibling => {
-                for sibling in context.node.preceding_siblings() {
-                    node_test.run(sibling)
+                for sibling in context_node.preceding_siblings() {
+                    node_test.run(sibling)?;
                 }
             }
             FollowingSibling => {
-                for sibling in context.node.following_siblings() {
-                    node_test.run(sibling)
+                for sibling in context_node.following_siblings() {
+                    node_test.run(sibling)?;
                 }
             }
-            Preceding => node_and_each_parent(context.node, |node| {
+            Preceding => node_and_each_parent(context_node, |node| {
                 for sibling in node.preceding_siblings() {
-                    postorder_right_to_left(sibling, |n| node_test.run(n));
+                    reverse_preorder(sibling, |n| node_test.run(n))?;
                 }
-            }),
-            Following => node_and_each_parent(context.node, |node| {
-                for sibling in node.following_siblings() {
-                    preorder_left_to_right(sibling, |n| node_test.run(n));
+                Ok(())
+            })?,
+            Following => {
+                // The children of an attribute's or namespace's element
+                // follow it without being its descendants.
+                match context_node {
+                    Node::Attribute(_) | Node::Namespace(_) => {
+                        if let Some(parent) = context_node.parent() {
+                            for child in parent.children() {
+                                preorder_left_to_right(child, |n| node_test.run(n))?;
+                            }
+                        }
+                    }
+                    _ => {}
                 }
-            }),
-            SelfAxis => node_test.run(context.node),
+
+                node_and_each_parent(context_node, |node| {
+                    for sibling in node.following_siblings() {
+                        preorder_left_to_right(sibling, |n| node_test.run(n))?;
+                    }
+                    Ok(())
+                })?
+            }
+            SelfAxis => node_test.run(context_node)?,
         }
 
-        node_test.result
+        Ok(())
+    }
+}
+
+impl AxisLike for Axis {
+    fn select_nodes<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        node_test: &dyn NodeTest,
+        limit: Option<usize>,
+    ) -> Result<OrderedNodes<'d>, Error> {
+        let mut node_test = CompleteNodeTest::new(context, node_test, limit);
+
+        match self.traverse(context.node, &mut node_test) {
+            Ok(()) | Err(Stop::Full) => Ok(node_test.result),
+            Err(Stop::Failed(e)) => Err(e),
+        }
     }
 
     fn principal_node_type(&self) -> PrincipalNodeType {
@@ -148,60 +235,73 @@ impl AxisLike for Axis {
             _ => PrincipalNodeType::Element,
         }
     }
+
+    fn is_reverse(&self) -> bool {
+        use self::Axis::*;
+        match *self {
+            Ancestor | AncestorOrSelf | Preceding | PrecedingSibling => true,
+            _ => false,
+        }
+    }
 }
 
-fn preorder_left_to_right<'d, F>(node: Node<'d>, mut f: F)
+fn preorder_left_to_right<'d, F>(node: Node<'d>, mut f: F) -> Result<(), Stop>
 where
-    F: FnMut(Node<'d>),
+    F: FnMut(Node<'d>) -> Result<(), Stop>,
 {
     let mut stack = vec![node];
 
     while let Some(current) = stack.pop() {
-        f(current);
+        f(current)?;
 
         for child in current.children().into_iter().rev() {
             stack.push(child);
         }
     }
+
+    Ok(())
 }
 
-// There's other implementations that only require a single stack; are
-// those applicable? Are they better?
-fn postorder_right_to_left<'d, F>(node: Node<'d>, mut f: F)
+/// Visits the node and its descendants in reverse document order,
+/// expanding each node only when the nodes after it have been visited.
+fn reverse_preorder<'d, F>(node: Node<'d>, mut f: F) -> Result<(), Stop>
 where
-    F: FnMut(Node<'d>),
+    F: FnMut(Node<'d>) -> Result<(), Stop>,
 {
-    let mut stack = vec![node];
-    let mut stack2 = vec![];
-
-    while let Some(current) = stack.pop() {
-        for child in current.children().into_iter().rev() {
-            stack.push(child);
+    let mut stack = vec![(node, false)];
+
+    while let Some((current, expanded)) = stack.pop() {
+        if expanded {
+            f(current)?;
+        } else {
+            stack.push((current, true));
+            for child in current.children() {
+                stack.push((child, false));
+            }
         }
-        stack2.push(current);
     }
 
-    for current in stack2.into_iter().rev() {
-        f(current);
-    }
+    Ok(())
 }
 
-fn node_and_each_parent<'d, F>(node: Node<'d>, mut f: F)
+fn node_and_each_parent<'d, F>(node: Node<'d>, mut f: F) -> Result<(), Stop>
 where
-    F: FnMut(Node<'d>),
+    F: FnMut(Node<'d>) -> Result<(), Stop>,
 {
-    f(node);
-    each_parent(node, f);
+    f(node)?;
+    each_parent(node, f)
 }
 
-fn each_parent<'d, F>(mut node: Node<'d>, mut f: F)
+fn each_parent<'d, F>(mut node: Node<'d>, mut f: F) -> Result<(), Stop>
 where
-    F: FnMut(Node<'d>),
+    F: FnMut(Node<'d>) -> Result<(), Stop>,
 {
     while let Some(parent) = node.parent() {
-        f(parent);
+        f(parent)?;
         node = parent;
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -237,6 +337,13 @@ mod test {
     }
 
     fn execute<'n, N>(axis: Axis, node: N) -> OrderedNodes<'n>
+    where
+        N: Into<Node<'n>>,
+    {
+        execute_limited(axis, node, None)
+    }
+
+    fn execute_limited<'n, N>(axis: Axis, node: N, limit: Option<usize>) -> OrderedNodes<'n>
     where
         N: Into<Node<'n>>,
     {
@@ -244,7 +351,8 @@ mod test {
         let context = context::Evaluation::new(&context, node.into());
         let node_test = &DummyNodeTest;
 
-        axis.select_nodes(&context, node_test)
+        axis.select_nodes(&context, node_test, limit)
+            .expect("Unable to select nodes")
     }
 
     #[test]
@@ -353,6 +461,44 @@ mod test {
         assert_eq!(result, ordered_nodes![child2, child3]);
     }
 
+    #[test]
+    fn namespace_selects_in_prefix_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.register_prefix("b", "uri:b");
+        element.register_prefix("a", "uri:a");
+        doc.root().append_child(element);
+
+        let prefixes: Vec<_> = Vec::from(execute(Namespace, element))
+            .into_iter()
+            .map(|n| n.namespace().expect("not a namespace").prefix())
+            .collect();
+
+        assert_eq!(prefixes, ["a", "b", "xml"]);
+    }
+
+    #[test]
+    fn following_an_attribute_includes_the_children_of_its_element() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let top = doc.create_element("top");
+        let parent = doc.create_element("parent");
+        let attribute = parent.set_attribute_value("attribute", "value");
+        let child = doc.create_element("child");
+        let sibling = doc.create_element("sibling");
+        doc.root().append_child(top);
+        top.append_child(parent);
+        top.append_child(sibling);
+        parent.append_child(child);
+
+        let result = execute(Following, attribute);
+
+        assert_eq!(result, ordered_nodes![child, sibling]);
+    }
+
     // <a0>
     //   <b0>
     //     <c0 />
@@ -434,4 +580,24 @@ mod test {
 
         assert_eq!(result, ordered_nodes![c[4], b[2], c[5], c[6]]);
     }
+
+    #[test]
+    fn selection_stops_at_the_limit() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let PrecedingFollowing { b, c, midpoint } = PrecedingFollowing::new(doc);
+
+        assert_eq!(
+            execute_limited(Following, midpoint, Some(2)),
+            ordered_nodes![c[4], b[2]]
+        );
+        assert_eq!(
+            execute_limited(Preceding, midpoint, Some(1)),
+            ordered_nodes![c[2]]
+        );
+        assert_eq!(
+            execute_limited(Following, midpoint, Some(0)),
+            OrderedNodes::new()
+        );
+    }
 }
@@ -0,0 +1,709 @@
+//! Checks a compiled XPath against a context before evaluation: that
+//! every function, variable and namespace prefix it refers to can be
+//! found, and that function calls match the functions' signatures.
+
+use snafu::Snafu;
+use std::fmt;
+use sxd_document::QName;
+
+use crate::axis::Axis;
+use crate::context::Context;
+use crate::expression::{
+    Expression, MathOperator, Predicate, Quantifier, RelationalOperator, Step, StepTest,
+    SubExpression,
+};
+use crate::function::ArgumentType;
+use crate::visitor::{Visitable, Visitor};
+use crate::{LiteralValue, OwnedPrefixedName};
+
+/// A reference that cannot be resolved, or a function call that does
+/// not match the signature of the function.
+#[derive(Debug, Snafu, Clone, PartialEq)]
+pub enum Error {
+    #[snafu(display("unknown function {}()", name))]
+    UnknownFunction { name: OwnedPrefixedName },
+    #[snafu(display("unknown variable ${}", name))]
+    UnknownVariable { name: OwnedPrefixedName },
+    #[snafu(display("unknown namespace prefix {}", prefix))]
+    UnknownNamespace { prefix: String },
+    #[snafu(display(
+        "{}() expects at least {} but was given {}",
+        name,
+        Arguments(*expected),
+        actual
+    ))]
+    NotEnoughArguments {
+        name: OwnedPrefixedName,
+        expected: usize,
+        actual: usize,
+    },
+    #[snafu(display(
+        "{}() expects at most {} but was given {}",
+        name,
+        Arguments(*expected),
+        actual
+    ))]
+    TooManyArguments {
+        name: OwnedPrefixedName,
+        expected: usize,
+        actual: usize,
+    },
+    #[snafu(display(
+        "argument {} of {}() must be a nodeset but is a {}",
+        position,
+        name,
+        actual
+    ))]
+    ArgumentNotANodeset {
+        name: OwnedPrefixedName,
+        position: usize,
+        actual: ArgumentType,
+    },
+}
+
+/// Displays a number of arguments, such as "1 argument".
+struct Arguments(usize);
+
+impl fmt::Display for Arguments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            1 => write!(f, "1 argument"),
+            n => write!(f, "{} arguments", n),
+        }
+    }
+}
+
+impl Error {
+    fn is_unresolved(&self) -> bool {
+        match *self {
+            Error::UnknownFunction { .. }
+            | Error::UnknownVariable { .. }
+            | Error::UnknownNamespace { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Finds every call in the expression that does not match its
+/// function's signature. Calls to functions that cannot be found or
+/// that have no signature are not checked.
+pub(crate) fn check(expression: &dyn Expression, context: &Context<'_>) -> Vec<Error> {
+    let mut errors = validate(expression, context);
+    errors.retain(|error| !error.is_unresolved());
+    errors
+}
+
+/// Finds every function, variable and namespace prefix that cannot be
+/// resolved in the context, along with every call that does not match
+/// its function's signature. Each problem is reported once, in the
+/// order it first appears.
+pub(crate) fn validate(expression: &dyn Expression, context: &Context<'_>) -> Vec<Error> {
+    let mut checker = Checker {
+        context,
+        kind: None,
+        bound: Vec::new(),
+        errors: Vec::new(),
+    };
+    expression.visit(&mut checker);
+    checker.errors
+}
+
+struct Checker<'a, 'd> {
+    context: &'a Context<'d>,
+    /// The type of the most recently visited expression, if it is
+    /// known before evaluation.
+    kind: Option<ArgumentType>,
+    /// The variables bound by the enclosing expressions.
+    bound: Vec<OwnedPrefixedName>,
+    errors: Vec<Error>,
+}
+
+impl<'a, 'd> Checker<'a, 'd> {
+    fn kind_of<V>(&mut self, visitable: &V) -> Option<ArgumentType>
+    where
+        V: Visitable + ?Sized,
+    {
+        self.kind = None;
+        visitable.visit(self);
+        self.kind.take()
+    }
+
+    fn operands(&mut self, operands: &[&SubExpression], kind: ArgumentType) {
+        for operand in operands {
+            self.kind_of(*operand);
+        }
+        self.kind = Some(kind);
+    }
+
+    /// Checks an expression in which the variable is bound, returning
+    /// its type.
+    fn bind(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        scope: &SubExpression,
+    ) -> Option<ArgumentType> {
+        self.resolve_name(variable);
+        self.bound.push(variable.into());
+        let kind = self.kind_of(scope);
+        self.bound.pop();
+        kind
+    }
+
+    fn report(&mut self, error: Error) {
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    /// Resolves the prefix of the name to a namespace, reporting it if
+    /// it is not bound to one.
+    fn resolve<'n>(&mut self, prefix: Option<&'n str>, local_part: &'n str) -> Option<QName<'n>>
+    where
+        'a: 'n,
+    {
+        let namespace_uri = match prefix {
+            Some(prefix) => match self.context.namespace_for(prefix) {
+                Some(uri) => Some(uri),
+                None => {
+                    self.report(Error::UnknownNamespace {
+                        prefix: prefix.to_owned(),
+                    });
+                    return None;
+                }
+            },
+            None => None,
+        };
+        Some(QName::with_namespace_uri(namespace_uri, local_part))
+    }
+
+    fn resolve_name<'n>(&mut self, name: &'n OwnedPrefixedName) -> Option<QName<'n>>
+    where
+        'a: 'n,
+    {
+        self.resolve(name.prefix.as_ref().map(String::as_str), &name.local_part)
+    }
+
+    fn call(&mut self, name: &OwnedPrefixedName, arguments: &[Option<ArgumentType>]) {
+        let qname = match self.resolve_name(name) {
+            Some(qname) => qname,
+            None => return,
+        };
+
+        let signature = match self.context.function_for_name(qname) {
+            Some(function) => function.signature(),
+            None => {
+                self.report(Error::UnknownFunction { name: name.into() });
+                return;
+            }
+        };
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return,
+        };
+
+        let actual = arguments.len();
+        if actual < signature.min_arity {
+            self.report(Error::NotEnoughArguments {
+                name: name.into(),
+                expected: signature.min_arity,
+                actual,
+            });
+        }
+        if let Some(maximum) = signature.max_arity {
+            if actual > maximum {
+                self.report(Error::TooManyArguments {
+                    name: name.into(),
+                    expected: maximum,
+                    actual,
+                });
+            }
+        }
+
+        for (index, argument) in arguments.iter().enumerate() {
+            let expected = signature.argument_type(index);
+            if let (Some(ArgumentType::Nodeset), Some(actual)) = (expected, *argument) {
+                if actual != ArgumentType::Nodeset {
+                    self.report(Error::ArgumentNotANodeset {
+                        name: name.into(),
+                        position: index + 1,
+                        actual,
+                    });
+                }
+            }
+        }
+
+        self.kind = Some(signature.returns);
+    }
+}
+
+impl<'a, 'd> Visitor for Checker<'a, 'd> {
+    fn visit_and(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Boolean);
+    }
+
+    fn visit_attribute(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.resolve(prefix, local_part);
+    }
+
+    fn visit_conditional(
+        &mut self,
+        condition: &SubExpression,
+        then: &SubExpression,
+        otherwise: &SubExpression,
+    ) {
+        self.kind_of(condition);
+        let then = self.kind_of(then);
+        let otherwise = self.kind_of(otherwise);
+        // Either branch may be chosen
+        self.kind = if then == otherwise { then } else { None };
+    }
+
+    fn visit_context_node(&mut self) {
+        self.kind = Some(ArgumentType::Nodeset);
+    }
+
+    fn visit_element(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.resolve(prefix, local_part);
+    }
+
+    fn visit_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Boolean);
+    }
+
+    fn visit_except(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Nodeset);
+    }
+
+    fn visit_filter(&mut self, node_selector: &SubExpression, predicate: &Predicate) {
+        let selected = self.kind_of(node_selector);
+        self.kind_of(&predicate.expression);
+        // Filtering a sequence of other items gives a sequence
+        self.kind = selected.map(|_| ArgumentType::Nodeset);
+    }
+
+    fn visit_for(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        body: &SubExpression,
+    ) {
+        self.kind_of(sequence);
+        // Any other body gives a sequence unless there are no items
+        self.kind = match self.bind(variable, body) {
+            Some(ArgumentType::Nodeset) => Some(ArgumentType::Nodeset),
+            _ => None,
+        };
+    }
+
+    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        let arguments: Vec<_> = arguments
+            .iter()
+            .map(|argument| self.kind_of(argument))
+            .collect();
+        self.call(name, &arguments);
+    }
+
+    fn visit_intersect(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Nodeset);
+    }
+
+    fn visit_let(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        value: &SubExpression,
+        body: &SubExpression,
+    ) {
+        self.kind_of(value);
+        self.kind = self.bind(variable, body);
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue) {
+        self.kind = Some(value.into());
+    }
+
+    fn visit_math(&mut self, left: &SubExpression, right: &SubExpression, _operator: MathOperator) {
+        self.operands(&[left, right], ArgumentType::Number);
+    }
+
+    fn visit_namespace(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.resolve(prefix, local_part);
+    }
+
+    fn visit_negation(&mut self, expression: &SubExpression) {
+        self.operands(&[expression], ArgumentType::Number);
+    }
+
+    fn visit_not_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Boolean);
+    }
+
+    fn visit_or(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Boolean);
+    }
+
+    fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]) {
+        self.kind_of(start_point);
+        for step in steps {
+            self.kind_of(step);
+        }
+        self.kind = Some(ArgumentType::Nodeset);
+    }
+
+    fn visit_quantified(
+        &mut self,
+        _quantifier: Quantifier,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        condition: &SubExpression,
+    ) {
+        self.kind_of(sequence);
+        self.bind(variable, condition);
+        self.kind = Some(ArgumentType::Boolean);
+    }
+
+    fn visit_range(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.kind_of(left);
+        self.kind_of(right);
+        // An empty range is an empty nodeset too
+        self.kind = None;
+    }
+
+    fn visit_relational(
+        &mut self,
+        left: &SubExpression,
+        right: &SubExpression,
+        _operator: RelationalOperator,
+    ) {
+        self.operands(&[left, right], ArgumentType::Boolean);
+    }
+
+    fn visit_root_node(&mut self) {
+        self.kind = Some(ArgumentType::Nodeset);
+    }
+
+    fn visit_sequence(&mut self, expressions: &[SubExpression]) {
+        let kinds: Vec<_> = expressions
+            .iter()
+            .map(|expression| self.kind_of(expression))
+            .collect();
+        // A sequence of nodes is accepted as a nodeset
+        self.kind = if kinds
+            .iter()
+            .all(|&kind| kind == Some(ArgumentType::Nodeset))
+        {
+            Some(ArgumentType::Nodeset)
+        } else {
+            None
+        };
+    }
+
+    fn visit_step(&mut self, _axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
+        self.kind_of(node_test);
+        for predicate in predicates {
+            self.kind_of(&predicate.expression);
+        }
+    }
+
+    fn visit_union(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.operands(&[left, right], ArgumentType::Nodeset);
+    }
+
+    fn visit_variable(&mut self, name: &OwnedPrefixedName) {
+        if self.bound.contains(name) {
+            return;
+        }
+        if let Some(qname) = self.resolve_name(name) {
+            if !self.context.may_have_variable(qname) {
+                self.report(Error::UnknownVariable { name: name.into() });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sxd_document::QName;
+
+    use crate::context::{Context, Evaluation, VariableResolver};
+    use crate::function::ArgumentType;
+    use crate::{Factory, LanguageLevel, Value, XPath};
+
+    use super::{check, validate, Error};
+
+    fn check_xpath(context: &Context<'_>, xpath: &str) -> Vec<Error> {
+        let xpath = Factory::new().build(xpath).expect("Unable to build XPath");
+        check(&*xpath.0, context)
+    }
+
+    fn build_xpath3(xpath: &str) -> XPath {
+        let mut factory = Factory::new();
+        factory.set_language_level(LanguageLevel::XPath3);
+        factory.build(xpath).expect("Unable to build XPath")
+    }
+
+    #[test]
+    fn calls_matching_their_signatures_pass() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "concat('a', 'b', 'c') = substring('abc', 2)");
+
+        assert_eq!(Vec::<Error>::new(), errors);
+    }
+
+    #[test]
+    fn wrong_number_of_arguments_is_reported() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "substring('a') or true(1)");
+
+        assert_eq!(
+            vec![
+                Error::NotEnoughArguments {
+                    name: "substring".into(),
+                    expected: 2,
+                    actual: 1,
+                },
+                Error::TooManyArguments {
+                    name: "true".into(),
+                    expected: 0,
+                    actual: 1,
+                },
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn arguments_that_cannot_be_nodesets_are_reported() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "count(1) + sum(a | b) + sum(string(a))");
+
+        assert_eq!(
+            vec![
+                Error::ArgumentNotANodeset {
+                    name: "count".into(),
+                    position: 1,
+                    actual: ArgumentType::Number,
+                },
+                Error::ArgumentNotANodeset {
+                    name: "sum".into(),
+                    position: 1,
+                    actual: ArgumentType::String,
+                },
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn errors_describe_the_expected_arguments() {
+        let context = Context::new();
+
+        let errors: Vec<_> = check_xpath(&context, "true(1) or not(1, 2) or count(1)")
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            vec![
+                "true() expects at most 0 arguments but was given 1",
+                "not() expects at most 1 argument but was given 2",
+                "argument 1 of count() must be a nodeset but is a number",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn conditionals_are_nodesets_when_both_branches_are() {
+        let context = Context::new();
+        let check_xpath = |xpath| check(&*build_xpath3(xpath).0, &context);
+
+        assert_eq!(
+            Vec::<Error>::new(),
+            check_xpath("count(if ($a) then b else 1) + count(if ($a) then b else c)")
+        );
+        assert_eq!(
+            vec![Error::ArgumentNotANodeset {
+                name: "count".into(),
+                position: 1,
+                actual: ArgumentType::String,
+            }],
+            check_xpath("count(if ($a) then 'b' else 'c')")
+        );
+    }
+
+    #[test]
+    fn sequences_of_nodes_can_be_nodesets() {
+        let context = Context::new();
+        let check_xpath = |xpath| check(&*build_xpath3(xpath).0, &context);
+
+        assert_eq!(
+            Vec::<Error>::new(),
+            check_xpath("count((a, b)) + count(()) + count(1 to 0)")
+        );
+        assert_eq!(
+            vec![Error::ArgumentNotANodeset {
+                name: "count".into(),
+                position: 1,
+                actual: ArgumentType::Number,
+            }],
+            check_xpath("count(for $a in b return 1) + count(1)")
+        );
+    }
+
+    #[test]
+    fn bindings_have_the_type_of_their_body() {
+        let context = Context::new();
+        let check_xpath = |xpath| check(&*build_xpath3(xpath).0, &context);
+
+        assert_eq!(
+            Vec::<Error>::new(),
+            check_xpath("count(for $a in 1 return $a) + count(let $a := 1 return b)")
+        );
+        assert_eq!(
+            vec![Error::ArgumentNotANodeset {
+                name: "count".into(),
+                position: 1,
+                actual: ArgumentType::Number,
+            }],
+            check_xpath("count(let $a := b return 1)")
+        );
+    }
+
+    #[test]
+    fn calls_nested_in_predicates_are_checked() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "/a[b[last(1)]]");
+
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    fn variables_and_unknown_functions_are_not_checked() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "count($nodes) + unknown(1, 2)");
+
+        assert_eq!(Vec::<Error>::new(), errors);
+    }
+
+    #[test]
+    fn registered_rust_functions_are_checked() {
+        let mut context = Context::new();
+        context.set_function_fn("double", |x: f64| x * 2.0);
+
+        let errors = check_xpath(&context, "count(double(1, 2))");
+
+        assert_eq!(
+            vec![
+                Error::TooManyArguments {
+                    name: "double".into(),
+                    expected: 1,
+                    actual: 2,
+                },
+                Error::ArgumentNotANodeset {
+                    name: "count".into(),
+                    position: 1,
+                    actual: ArgumentType::Number,
+                },
+            ],
+            errors
+        );
+    }
+
+    fn validate_xpath(context: &Context<'_>, xpath: &str) -> Vec<Error> {
+        let xpath = Factory::new().build(xpath).expect("Unable to build XPath");
+        validate(&*xpath.0, context)
+    }
+
+    #[test]
+    fn resolvable_references_are_valid() {
+        let mut context = Context::new();
+        context.set_namespace("p", "uri:p");
+        context.set_variable(("uri:p", "v"), 1.0);
+        context.set_function_fn(("uri:p", "f"), |x: f64| x);
+
+        let errors = validate_xpath(&context, "p:f($p:v) = count(/p:a/@p:b/namespace::p:c)");
+
+        assert_eq!(Vec::<Error>::new(), errors);
+    }
+
+    #[test]
+    fn unresolved_references_are_reported_once_each() {
+        let context = Context::new();
+
+        let errors = validate_xpath(&context, "$v + $v + f() + f() + count(p:a | p:a)");
+
+        assert_eq!(
+            vec![
+                Error::UnknownVariable { name: "v".into() },
+                Error::UnknownFunction { name: "f".into() },
+                Error::UnknownNamespace {
+                    prefix: "p".to_owned()
+                },
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn names_with_unknown_prefixes_only_report_the_prefix() {
+        let context = Context::new();
+
+        let errors = validate_xpath(&context, "p:f($q:v)");
+
+        assert_eq!(
+            vec![
+                Error::UnknownNamespace {
+                    prefix: "q".to_owned()
+                },
+                Error::UnknownNamespace {
+                    prefix: "p".to_owned()
+                },
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn variables_are_known_where_they_are_bound() {
+        let context = Context::new();
+        let xpath = build_xpath3("(some $v in a satisfies $v = 1) and (let $w := $v return $w)");
+
+        let errors = validate(&*xpath.0, &context);
+
+        assert_eq!(vec![Error::UnknownVariable { name: "v".into() }], errors);
+    }
+
+    #[test]
+    fn any_variable_may_come_from_a_variable_resolver() {
+        struct Nothing;
+
+        impl VariableResolver for Nothing {
+            fn resolve_variable<'c, 'd>(
+                &self,
+                _context: &Evaluation<'c, 'd>,
+                _name: QName<'_>,
+            ) -> Option<Value<'d>> {
+                None
+            }
+        }
+
+        let mut context = Context::new();
+        context.set_variable_resolver(Nothing);
+
+        assert_eq!(Vec::<Error>::new(), validate_xpath(&context, "$anything"));
+    }
+
+    #[test]
+    fn checking_ignores_unresolved_references() {
+        let context = Context::new();
+
+        let errors = check_xpath(&context, "$v + f() + count(p:a) + count(1)");
+
+        assert_eq!(1, errors.len());
+    }
+}
@@ -1,21 +1,127 @@
 //! Support for the various types of contexts before and during XPath
 //! evaluation.
 
-use sxd_document::QName;
+use sxd_document::{dom, QName};
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::iter;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, iter};
 
+use crate::expression::Error;
 use crate::function;
-use crate::nodeset::{Node, OrderedNodes};
-use crate::{OwnedQName, Value};
+use crate::nodeset::{DocumentOrder, Node, Nodeset, OrderedNodes};
+use crate::{Item, OwnedQName, Value, XML_NS_URI};
 
 /// A mapping of names to XPath functions.
-type Functions = HashMap<OwnedQName, Box<dyn function::Function + 'static>>;
+type Functions<'d> = HashMap<OwnedQName, Rc<dyn function::DocumentFunction<'d> + 'd>>;
 /// A mapping of names to XPath variables.
 type Variables<'d> = HashMap<OwnedQName, Value<'d>>;
 /// A mapping of namespace prefixes to namespace URIs.
 type Namespaces = HashMap<String, String>;
+/// The names of the attributes that hold an element's unique ID.
+type IdAttributes = Vec<OwnedQName>;
+
+/// Finds functions that were not registered with the context, such as
+/// those of a large library that is expensive to register up front.
+///
+/// ### Examples
+///
+/// ```
+/// use std::rc::Rc;
+/// use sxd_document::{parser, QName};
+/// use sxd_xpath::context::{self, FunctionResolver};
+/// use sxd_xpath::function::{self, Args, Function};
+/// use sxd_xpath::{Context, Factory, Value};
+///
+/// struct Multiply(f64);
+///
+/// impl Function for Multiply {
+///     fn evaluate<'c, 'd>(
+///         &self,
+///         _context: &context::Evaluation<'c, 'd>,
+///         args: Vec<Value<'d>>,
+///     ) -> Result<Value<'d>, function::Error> {
+///         let mut args = Args(args);
+///         args.exactly(1)?;
+///         Ok(Value::Number(self.0 * args.pop_number()?))
+///     }
+/// }
+///
+/// struct Multipliers;
+///
+/// impl FunctionResolver for Multipliers {
+///     fn resolve_function(&self, name: QName<'_>) -> Option<Rc<dyn Function>> {
+///         let factor = name.local_part().trim_start_matches("times").parse().ok()?;
+///         Some(Rc::new(Multiply(factor)))
+///     }
+/// }
+///
+/// let package = parser::parse("<root/>").expect("failed to parse XML");
+/// let document = package.as_document();
+///
+/// let mut context = Context::new();
+/// context.set_function_resolver(Multipliers);
+///
+/// let xpath = Factory::new().build("times3(times2(4))").expect("Could not compile XPath");
+/// let value = xpath.evaluate(&context, document.root()).expect("XPath evaluation failed");
+///
+/// assert_eq!(24.0, value.number());
+/// ```
+pub trait FunctionResolver {
+    /// Returns the function with the given name, or `None` if there is
+    /// no such function. The function may be created for this call or
+    /// shared with other names.
+    fn resolve_function(&self, name: QName<'_>) -> Option<Rc<dyn function::Function>>;
+}
+
+/// Produces the values of variables that were not registered with the
+/// context, so that values are only computed when they are used.
+///
+/// ### Examples
+///
+/// ```
+/// use sxd_document::{parser, QName};
+/// use sxd_xpath::context::{self, VariableResolver};
+/// use sxd_xpath::{Context, Factory, Value};
+///
+/// struct Squares;
+///
+/// impl VariableResolver for Squares {
+///     fn resolve_variable<'c, 'd>(
+///         &self,
+///         _context: &context::Evaluation<'c, 'd>,
+///         name: QName<'_>,
+///     ) -> Option<Value<'d>> {
+///         let n: f64 = name.local_part().trim_start_matches('n').parse().ok()?;
+///         Some(Value::Number(n * n))
+///     }
+/// }
+///
+/// let package = parser::parse("<root/>").expect("failed to parse XML");
+/// let document = package.as_document();
+///
+/// let mut context = Context::new();
+/// context.set_variable_resolver(Squares);
+///
+/// let xpath = Factory::new().build("$n3 + $n4").expect("Could not compile XPath");
+/// let value = xpath.evaluate(&context, document.root()).expect("XPath evaluation failed");
+///
+/// assert_eq!(25.0, value.number());
+/// ```
+pub trait VariableResolver {
+    /// Returns the value of the variable with the given name, or
+    /// `None` if there is no such variable. Nodesets may be built
+    /// from the document of the context node.
+    fn resolve_variable<'c, 'd>(
+        &self,
+        context: &Evaluation<'c, 'd>,
+        name: QName<'_>,
+    ) -> Option<Value<'d>>;
+}
 
 /// Contains the context in which XPath expressions are executed. The
 /// context contains functions, variables, and namespace mappings.
@@ -75,9 +181,17 @@ type Namespaces = HashMap<String, String>;
 /// top-most element.
 ///
 pub struct Context<'d> {
-    functions: Functions,
+    functions: Functions<'d>,
+    core_functions: Functions<'d>,
     variables: Variables<'d>,
+    function_resolver: Option<Box<dyn FunctionResolver + 'static>>,
+    variable_resolver: Option<Box<dyn VariableResolver + 'static>>,
     namespaces: Namespaces,
+    id_attributes: IdAttributes,
+    order: DocumentOrder<'d>,
+    ids: IdIndex<'d>,
+    budget: Budget,
+    parent: Option<Rc<Context<'d>>>,
 }
 
 impl<'d> Context<'d> {
@@ -92,18 +206,177 @@ impl<'d> Context<'d> {
     pub fn without_core_functions() -> Self {
         Context {
             functions: Default::default(),
+            core_functions: Default::default(),
             variables: Default::default(),
+            function_resolver: None,
+            variable_resolver: None,
             namespaces: Default::default(),
+            id_attributes: vec![(XML_NS_URI, "id").into(), "id".into()],
+            order: Default::default(),
+            ids: Default::default(),
+            budget: Default::default(),
+            parent: None,
+        }
+    }
+
+    /// Creates a context layered over a parent context. Functions,
+    /// variables, and namespaces that are not set on the child are
+    /// looked up in the parent, which is shared rather than copied.
+    ///
+    /// The child starts with the parent's ID attributes, limits, and
+    /// cancellation token.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use std::rc::Rc;
+    /// use sxd_document::parser;
+    /// use sxd_xpath::{Context, Factory};
+    ///
+    /// let package = parser::parse("<a xmlns='uri:a'><b/></a>").expect("failed to parse XML");
+    /// let document = package.as_document();
+    ///
+    /// let mut base = Context::new();
+    /// base.set_namespace("a", "uri:a");
+    /// let base = Rc::new(base);
+    ///
+    /// let mut context = Context::child(base.clone());
+    /// context.set_variable("name", "b");
+    ///
+    /// let xpath = "count(/a:a/a:*[local-name() = $name])";
+    /// let xpath = Factory::new().build(xpath).expect("Could not compile XPath");
+    /// let value = xpath.evaluate(&context, document.root()).expect("XPath evaluation failed");
+    ///
+    /// assert_eq!(1.0, value.number());
+    /// ```
+    pub fn child(parent: Rc<Context<'d>>) -> Self {
+        Context {
+            functions: Default::default(),
+            core_functions: Default::default(),
+            variables: Default::default(),
+            function_resolver: None,
+            variable_resolver: None,
+            namespaces: Default::default(),
+            id_attributes: parent.id_attributes.clone(),
+            order: Default::default(),
+            ids: Default::default(),
+            budget: Budget::new(
+                parent.budget.limits.clone(),
+                parent.budget.cancellation.clone(),
+            ),
+            parent: Some(parent),
+        }
+    }
+
+    /// This context followed by each of its ancestors.
+    fn layers(&self) -> Layers<'_, 'd> {
+        Layers(Some(self))
+    }
+
+    /// Looks up the function with the given name in this context or
+    /// its ancestors.
+    pub(crate) fn function_for_name(
+        &self,
+        name: QName<'_>,
+    ) -> Option<Rc<dyn function::DocumentFunction<'d> + 'd>> {
+        // FIXME: remove allocation
+        let owned_name = name.into();
+        self.layers().find_map(|layer| {
+            layer.functions.get(&owned_name).cloned().or_else(|| {
+                let function = layer.function_resolver.as_ref()?.resolve_function(name)?;
+                Some(Rc::new(function) as Rc<dyn function::DocumentFunction<'d>>)
+            })
+        })
+    }
+
+    /// Notes that the functions registered with these names are the
+    /// core functions, so that replacing them can be detected.
+    pub(crate) fn mark_core_functions(&mut self, names: &[&str]) {
+        for &name in names {
+            let name = OwnedQName::from(name);
+            if let Some(function) = self.functions.get(&name).cloned() {
+                self.core_functions.insert(name, function);
+            }
         }
     }
 
+    /// Whether the function with the given name is still the core
+    /// function registered by
+    /// [`register_core_functions`](../function/fn.register_core_functions.html).
+    pub(crate) fn calls_core_function(&self, name: &str) -> bool {
+        let owned_name = OwnedQName::from(name);
+        let core = self
+            .layers()
+            .find_map(|layer| layer.core_functions.get(&owned_name));
+        let thin = |function: &dyn function::DocumentFunction<'d>| {
+            function as *const dyn function::DocumentFunction<'d> as *const u8
+        };
+
+        match (core, self.function_for_name(QName::new(name))) {
+            (Some(core), Some(function)) => thin(&**core) == thin(&*function),
+            _ => false,
+        }
+    }
+
+    /// Whether the variable is set in this context or its ancestors,
+    /// or may be produced by one of their variable resolvers.
+    pub(crate) fn may_have_variable(&self, name: QName<'_>) -> bool {
+        // FIXME: remove allocation
+        let owned_name = name.into();
+        self.layers().any(|layer| {
+            layer.variables.contains_key(&owned_name) || layer.variable_resolver.is_some()
+        })
+    }
+
+    /// Looks up the namespace URI for the given prefix in this context
+    /// or its ancestors.
+    pub(crate) fn namespace_for(&self, prefix: &str) -> Option<&str> {
+        self.layers()
+            .find_map(|layer| layer.namespaces.get(prefix))
+            .map(String::as_str)
+    }
+
     /// Register a function within the context
     pub fn set_function<N, F>(&mut self, name: N, function: F)
     where
         N: Into<OwnedQName>,
         F: function::Function + 'static,
     {
-        self.functions.insert(name.into(), Box::new(function));
+        self.functions.insert(name.into(), Rc::new(function));
+    }
+
+    /// Register a Rust function or closure within the context. The
+    /// number of arguments is checked and each argument is converted
+    /// to the type of its parameter; see
+    /// [`function::Parameter`](../function/trait.Parameter.html).
+    ///
+    /// ```
+    /// use sxd_document::parser;
+    /// use sxd_xpath::{Context, Factory};
+    ///
+    /// let package = parser::parse("<root/>").expect("failed to parse XML");
+    /// let document = package.as_document();
+    ///
+    /// let mut context = Context::new();
+    /// context.set_function_fn("sigmoid", |x: f64| (1.0 + (-x).exp()).recip());
+    /// context.set_function_fn("join", |separator: String, parts: Vec<String>| {
+    ///     parts.join(&separator)
+    /// });
+    ///
+    /// let xpath = Factory::new()
+    ///     .build("join('-', sigmoid(0), 'b', 'c')")
+    ///     .expect("Could not compile XPath");
+    /// let value = xpath.evaluate(&context, document.root()).expect("XPath evaluation failed");
+    ///
+    /// assert_eq!("0.5-b-c", value.string());
+    /// ```
+    pub fn set_function_fn<N, F, P>(&mut self, name: N, function: F)
+    where
+        N: Into<OwnedQName>,
+        F: function::IntoFunction<'d, P>,
+    {
+        self.functions
+            .insert(name.into(), Rc::from(function.into_function()));
     }
 
     /// Register a variable within the context
@@ -115,10 +388,76 @@ impl<'d> Context<'d> {
         self.variables.insert(name.into(), value.into());
     }
 
+    /// Looks up functions that have not been registered with
+    /// `set_function` using the resolver.
+    pub fn set_function_resolver<R>(&mut self, resolver: R)
+    where
+        R: FunctionResolver + 'static,
+    {
+        self.function_resolver = Some(Box::new(resolver));
+    }
+
+    /// Looks up variables that have not been registered with
+    /// `set_variable` using the resolver.
+    pub fn set_variable_resolver<R>(&mut self, resolver: R)
+    where
+        R: VariableResolver + 'static,
+    {
+        self.variable_resolver = Some(Box::new(resolver));
+    }
+
     /// Register a namespace prefix within the context
     pub fn set_namespace(&mut self, prefix: &str, uri: &str) {
         self.namespaces.insert(prefix.into(), uri.into());
     }
+
+    /// Sets the attributes that hold an element's unique ID, as used
+    /// by the `id` function. Without a DTD there is no way to know
+    /// which attributes are IDs, so `xml:id` and `id` are used by
+    /// default.
+    pub fn set_id_attributes<I, N>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<OwnedQName>,
+    {
+        self.id_attributes = names.into_iter().map(Into::into).collect();
+        self.ids.clear();
+    }
+
+    /// Forgets the IDs of every document. The elements of a document
+    /// are indexed by ID the first time the `id` function is used with
+    /// it, and the index is kept for later evaluations, so this must
+    /// be called after adding, removing or changing ID attributes.
+    pub fn clear_id_index(&self) {
+        self.ids.clear();
+    }
+
+    /// Starts evaluating with this context, until the returned value is
+    /// dropped. Only the outermost evaluation resets the work done and
+    /// the document order, so XPaths evaluated by functions while
+    /// another XPath is being evaluated count towards its limits.
+    pub(crate) fn start_evaluation(&self) -> Running<'_> {
+        let budget = &self.budget;
+        if budget.running.get() == 0 {
+            // The document may have changed since the last evaluation
+            self.order.clear();
+            budget.reset();
+        }
+        budget.running.set(budget.running.get() + 1);
+        Running(budget)
+    }
+
+    /// Bounds the work done by each evaluation using this context.
+    /// There are no limits by default.
+    pub fn set_limits(&mut self, limits: EvaluationLimits) {
+        self.budget.limits = limits;
+    }
+
+    /// Stops any evaluation using this context once the token is
+    /// cancelled.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.budget.cancellation = Some(token);
+    }
 }
 
 impl<'d> Default for Context<'d> {
@@ -145,9 +484,14 @@ pub struct Evaluation<'c, 'd> {
     pub position: usize,
     /// The context size
     pub size: usize,
-    functions: &'c Functions,
-    variables: &'c Variables<'d>,
-    namespaces: &'c Namespaces,
+    scope: &'c Context<'d>,
+    id_attributes: &'c IdAttributes,
+    order: &'c DocumentOrder<'d>,
+    ids: &'c IdIndex<'d>,
+    budget: &'c Budget,
+    locals: Option<&'c Frame<'c, 'd>>,
+    item: Option<&'c Item<'d>>,
+    core_positional_functions: bool,
 }
 
 impl<'c, 'd> Evaluation<'c, 'd> {
@@ -155,11 +499,17 @@ impl<'c, 'd> Evaluation<'c, 'd> {
     pub fn new(context: &'c Context<'d>, node: Node<'d>) -> Evaluation<'c, 'd> {
         Evaluation {
             node,
-            functions: &context.functions,
-            variables: &context.variables,
-            namespaces: &context.namespaces,
+            scope: context,
+            id_attributes: &context.id_attributes,
+            order: &context.order,
+            ids: &context.ids,
+            budget: &context.budget,
             position: 1,
             size: 1,
+            locals: None,
+            item: None,
+            core_positional_functions: context.calls_core_function("position")
+                && context.calls_core_function("last"),
         }
     }
 
@@ -170,27 +520,146 @@ impl<'c, 'd> Evaluation<'c, 'd> {
     {
         Evaluation {
             node: node.into(),
+            item: None,
             ..*self
         }
     }
 
+    /// Creates a context for one item of a sequence that is being
+    /// filtered. Nodes become the context node, while other items are
+    /// only available as the context item.
+    pub(crate) fn new_context_for_item<'i>(
+        &self,
+        item: &'i Item<'d>,
+        position: usize,
+        size: usize,
+    ) -> Evaluation<'i, 'd>
+    where
+        'c: 'i,
+    {
+        let (node, item) = match *item {
+            Item::Node(node) => (node, None),
+            ref item => (self.node, Some(item)),
+        };
+        Evaluation {
+            node,
+            position,
+            size,
+            item,
+            ..*self
+        }
+    }
+
+    /// Whether `position()` and `last()` call the core functions, so
+    /// that predicates using them may select nodes by position alone.
+    pub(crate) fn has_core_positional_functions(&self) -> bool {
+        self.core_positional_functions
+    }
+
+    /// The context item: the item of a sequence that a predicate is
+    /// being evaluated for, or else the context node as a nodeset.
+    pub fn context_item(&self) -> Value<'d> {
+        match self.item {
+            Some(item) => item.clone().into(),
+            None => Value::Nodeset(nodeset![self.node]),
+        }
+    }
+
     /// Looks up the function with the given name
-    pub fn function_for_name(&self, name: QName<'_>) -> Option<&'c dyn function::Function> {
-        // FIXME: remove allocation
-        let name = name.into();
-        self.functions.get(&name).map(AsRef::as_ref)
+    pub fn function_for_name(
+        &self,
+        name: QName<'_>,
+    ) -> Option<Rc<dyn function::DocumentFunction<'d> + 'd>> {
+        self.scope.function_for_name(name)
+    }
+
+    /// Binds a variable within the XPath, such as the range variable of
+    /// `some $x in a satisfies b`. The binding is used by evaluations
+    /// made with the context returned by `with_frame`.
+    pub(crate) fn new_frame(&self, name: QName<'_>, value: Value<'d>) -> Frame<'c, 'd> {
+        Frame {
+            name: name.into(),
+            value,
+            parent: self.locals,
+        }
+    }
+
+    /// Creates a context in which the variable bound by the frame hides
+    /// any other variable of the same name.
+    pub(crate) fn with_frame<'f>(&self, frame: &'f Frame<'f, 'd>) -> Evaluation<'f, 'd>
+    where
+        'c: 'f,
+    {
+        Evaluation {
+            locals: Some(frame),
+            ..*self
+        }
     }
 
     /// Looks up the value of the variable
-    pub fn value_of(&self, name: QName<'_>) -> Option<&Value<'d>> {
+    pub fn value_of(&self, name: QName<'_>) -> Option<Value<'d>> {
+        let mut frame = self.locals;
+        while let Some(f) = frame {
+            if f.name.as_qname() == name {
+                return Some(f.value.clone());
+            }
+            frame = f.parent;
+        }
+
         // FIXME: remove allocation
-        let name = name.into();
-        self.variables.get(&name)
+        let owned_name = name.into();
+        self.scope.layers().find_map(|layer| {
+            layer.variables.get(&owned_name).cloned().or_else(|| {
+                layer
+                    .variable_resolver
+                    .as_ref()?
+                    .resolve_variable(self, name)
+            })
+        })
     }
 
     /// Looks up the namespace URI for the given prefix
     pub fn namespace_for(&self, prefix: &str) -> Option<&str> {
-        self.namespaces.get(prefix).map(String::as_str)
+        self.scope.namespace_for(prefix)
+    }
+
+    /// Looks up the element with the given unique ID in the document
+    /// of the context node. The document's IDs are gathered once and
+    /// kept by the context until its ID index is cleared.
+    pub fn element_with_id(&self, id: &str) -> Option<dom::Element<'d>> {
+        self.ids
+            .element_with_id(self.node.document(), self.id_attributes, id)
+    }
+
+    /// Counts a node visited while walking an axis, failing if a
+    /// limit has been exceeded or the evaluation was cancelled.
+    pub(crate) fn visit_node(&self) -> Result<(), Error> {
+        let budget = self.budget;
+        budget.nodes_visited.set(budget.nodes_visited.get() + 1);
+        budget.check(Limit::NodesVisited, budget.nodes_visited.get())?;
+        budget.check_interrupted()
+    }
+
+    /// Fails if a nodeset of this size is not allowed.
+    pub(crate) fn check_nodeset_size(&self, size: usize) -> Result<(), Error> {
+        self.budget.check(Limit::NodesetSize, size)
+    }
+
+    /// Enters a nested evaluation, such as that of a predicate. The
+    /// nesting ends when the returned value is dropped.
+    pub(crate) fn nest(&self) -> Result<Nesting<'c>, Error> {
+        let budget = self.budget;
+        budget.depth.set(budget.depth.get() + 1);
+        let nesting = Nesting(budget);
+        budget.check(Limit::Depth, budget.depth.get())?;
+        budget.check_interrupted()?;
+        Ok(nesting)
+    }
+
+    /// Puts the nodes in document order. The order of the document's
+    /// nodes is computed at most once per evaluation.
+    pub(crate) fn sort(&self, nodes: &mut Nodeset<'d>) {
+        nodes.sort_with(self.order);
     }
 
     /// Yields a new `Evaluation` context for each node in the nodeset.
@@ -204,6 +673,249 @@ impl<'c, 'd> Evaluation<'c, 'd> {
     }
 }
 
+/// A variable bound within the XPath itself. Each frame refers to the
+/// frame that was in scope where it was bound.
+pub(crate) struct Frame<'c, 'd> {
+    name: OwnedQName,
+    value: Value<'d>,
+    parent: Option<&'c Frame<'c, 'd>>,
+}
+
+/// Bounds the work done while evaluating an XPath, for use when
+/// evaluating untrusted expressions. Each limit is unbounded when
+/// `None`.
+///
+/// ### Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use sxd_document::parser;
+/// use sxd_xpath::context::{EvaluationLimits, Limit};
+/// use sxd_xpath::{Context, Factory};
+///
+/// let package = parser::parse("<a><b/><b/><b/></a>").expect("failed to parse XML");
+/// let document = package.as_document();
+///
+/// let mut context = Context::new();
+/// context.set_limits(EvaluationLimits {
+///     max_nodes_visited: Some(3),
+///     max_duration: Some(Duration::from_secs(1)),
+///     ..Default::default()
+/// });
+///
+/// let xpath = Factory::new().build("//b").expect("Could not compile XPath");
+/// let error = xpath.evaluate(&context, document.root()).unwrap_err();
+///
+/// assert_eq!(Some(Limit::NodesVisited), error.limit_exceeded());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvaluationLimits {
+    /// The number of nodes that may be visited while walking axes
+    pub max_nodes_visited: Option<usize>,
+    /// The number of nodes a single step or union may select, or of
+    /// items a single sequence may hold
+    pub max_nodeset_size: Option<usize>,
+    /// How deeply predicates and steps may be nested
+    pub max_depth: Option<usize>,
+    /// How long an evaluation may run
+    pub max_duration: Option<Duration>,
+}
+
+/// One of the bounds of an [`EvaluationLimits`](struct.EvaluationLimits.html).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Limit {
+    NodesVisited,
+    NodesetSize,
+    Depth,
+    Duration,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Limit::NodesVisited => "nodes visited",
+            Limit::NodesetSize => "nodeset size",
+            Limit::Depth => "nesting depth",
+            Limit::Duration => "duration",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Requests that evaluations stop early. Clones share the same state,
+/// so a clone may be cancelled from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stops every evaluation using this token. Evaluations started
+    /// afterwards fail immediately.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Tracks the work done by the current evaluation.
+struct Budget {
+    limits: EvaluationLimits,
+    cancellation: Option<CancellationToken>,
+    started: Cell<Instant>,
+    nodes_visited: Cell<usize>,
+    depth: Cell<usize>,
+    running: Cell<usize>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::new(Default::default(), None)
+    }
+}
+
+impl Budget {
+    fn new(limits: EvaluationLimits, cancellation: Option<CancellationToken>) -> Self {
+        Budget {
+            limits,
+            cancellation,
+            started: Cell::new(Instant::now()),
+            nodes_visited: Cell::new(0),
+            depth: Cell::new(0),
+            running: Cell::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.started.set(Instant::now());
+        self.nodes_visited.set(0);
+        self.depth.set(0);
+    }
+
+    fn check(&self, limit: Limit, value: usize) -> Result<(), Error> {
+        let maximum = match limit {
+            Limit::NodesVisited => self.limits.max_nodes_visited,
+            Limit::NodesetSize => self.limits.max_nodeset_size,
+            Limit::Depth => self.limits.max_depth,
+            Limit::Duration => None,
+        };
+
+        match maximum {
+            Some(maximum) if value > maximum => Err(Error::LimitExceeded { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_interrupted(&self) -> Result<(), Error> {
+        if let Some(ref token) = self.cancellation {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+        }
+
+        if let Some(maximum) = self.limits.max_duration {
+            if self.started.get().elapsed() > maximum {
+                return Err(Error::LimitExceeded {
+                    limit: Limit::Duration,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A nested evaluation, which ends when dropped.
+pub(crate) struct Nesting<'c>(&'c Budget);
+
+impl<'c> Drop for Nesting<'c> {
+    fn drop(&mut self) {
+        self.0.depth.set(self.0.depth.get() - 1);
+    }
+}
+
+/// An evaluation in progress, which ends when dropped.
+pub(crate) struct Running<'c>(&'c Budget);
+
+impl<'c> Drop for Running<'c> {
+    fn drop(&mut self) {
+        self.0.running.set(self.0.running.get() - 1);
+    }
+}
+
+/// Iterates over a context and its ancestors.
+struct Layers<'c, 'd>(Option<&'c Context<'d>>);
+
+impl<'c, 'd> Iterator for Layers<'c, 'd> {
+    type Item = &'c Context<'d>;
+
+    fn next(&mut self) -> Option<&'c Context<'d>> {
+        let layer = self.0?;
+        self.0 = layer.parent.as_ref().map(|parent| &**parent);
+        Some(layer)
+    }
+}
+
+/// Remembers the elements of each document by their unique ID.
+#[derive(Default)]
+struct IdIndex<'d>(RefCell<HashMap<dom::Root<'d>, Ids<'d>>>);
+
+struct Ids<'d> {
+    elements: HashMap<&'d str, dom::Element<'d>>,
+}
+
+impl<'d> IdIndex<'d> {
+    fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    fn element_with_id(
+        &self,
+        doc: dom::Document<'d>,
+        id_attributes: &[OwnedQName],
+        id: &str,
+    ) -> Option<dom::Element<'d>> {
+        self.0
+            .borrow_mut()
+            .entry(doc.root())
+            .or_insert_with(|| Ids::new(doc, id_attributes))
+            .elements
+            .get(id)
+            .cloned()
+    }
+}
+
+impl<'d> Ids<'d> {
+    fn new(doc: dom::Document<'d>, id_attributes: &[OwnedQName]) -> Self {
+        let mut elements = HashMap::new();
+        let mut stack: Vec<Node<'d>> = doc.root().children().into_iter().map(Into::into).collect();
+        stack.reverse();
+
+        while let Some(node) = stack.pop() {
+            if let Node::Element(e) = node {
+                let id = id_attributes
+                    .iter()
+                    .filter_map(|name| e.attribute_value(name.as_qname()))
+                    .next();
+
+                // Should an ID be repeated, the first element wins
+                if let Some(id) = id {
+                    elements.entry(id).or_insert(e);
+                }
+            }
+
+            stack.extend(node.children().into_iter().rev());
+        }
+
+        Ids { elements }
+    }
+}
+
 /// An iterator for the contexts of each node in a nodeset
 pub struct EvaluationNodesetIter<'c, 'd> {
     parent: Evaluation<'c, 'd>,
@@ -219,6 +931,7 @@ impl<'c, 'd> Iterator for EvaluationNodesetIter<'c, 'd> {
             node,
             position: idx + 1,
             size: self.size,
+            item: None,
             ..self.parent
         })
     }
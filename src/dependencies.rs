@@ -0,0 +1,293 @@
+//! Finds what a compiled XPath needs from the context it is evaluated
+//! in: the variables, functions and namespace prefixes it refers to,
+//! and whether it uses the context node, position or size.
+
+use crate::expression::{Expression, Predicate, Quantifier, SubExpression};
+use crate::visitor::{self, Visitor};
+use crate::{ContextDependence, OwnedPrefixedName};
+
+/// Everything an expression refers to, in the order each first
+/// appears.
+#[derive(Debug, Default)]
+pub(crate) struct Dependencies {
+    pub variables: Vec<OwnedPrefixedName>,
+    pub functions: Vec<OwnedPrefixedName>,
+    pub namespace_prefixes: Vec<String>,
+    pub context: ContextDependence,
+}
+
+pub(crate) fn dependencies(expression: &dyn Expression) -> Dependencies {
+    let mut collector = Collector::default();
+    expression.visit(&mut collector);
+    collector.dependencies
+}
+
+/// Core functions that only use their arguments.
+const CONTEXT_FREE_FUNCTIONS: &[&str] = &[
+    "count",
+    "concat",
+    "starts-with",
+    "contains",
+    "substring-before",
+    "substring-after",
+    "substring",
+    "translate",
+    "boolean",
+    "not",
+    "true",
+    "false",
+    "sum",
+    "floor",
+    "ceiling",
+    "round",
+];
+
+/// Core functions that use the context node when they are called
+/// without arguments.
+const CONTEXT_NODE_DEFAULT_FUNCTIONS: &[&str] = &[
+    "local-name",
+    "namespace-uri",
+    "name",
+    "string",
+    "string-length",
+    "normalize-space",
+    "number",
+];
+
+fn add_once(names: &mut Vec<OwnedPrefixedName>, name: &OwnedPrefixedName) {
+    if !names.contains(name) {
+        names.push(name.into());
+    }
+}
+
+#[derive(Default)]
+struct Collector {
+    dependencies: Dependencies,
+    /// How many predicates enclose the visited expression.
+    predicate_depth: usize,
+    /// The variables bound by the enclosing expressions.
+    bound: Vec<OwnedPrefixedName>,
+}
+
+impl Collector {
+    fn outermost(&self) -> bool {
+        self.predicate_depth == 0
+    }
+
+    fn uses_context_node(&mut self) {
+        if self.outermost() {
+            self.dependencies.context.node = true;
+        }
+    }
+
+    fn prefix(&mut self, prefix: Option<&str>) {
+        if let Some(prefix) = prefix {
+            let prefixes = &mut self.dependencies.namespace_prefixes;
+            if !prefixes.iter().any(|p| p == prefix) {
+                prefixes.push(prefix.to_owned());
+            }
+        }
+    }
+
+    fn variable(&mut self, name: &OwnedPrefixedName) {
+        self.prefix(name.prefix());
+        if self.bound.contains(name) {
+            return;
+        }
+        add_once(&mut self.dependencies.variables, name);
+    }
+
+    /// Collects the references of an expression in which the variable
+    /// is bound.
+    fn bind(&mut self, variable: &OwnedPrefixedName, scope: &SubExpression) {
+        self.prefix(variable.prefix());
+        self.bound.push(variable.into());
+        scope.visit(self);
+        self.bound.pop();
+    }
+
+    fn function(&mut self, name: &OwnedPrefixedName) {
+        self.prefix(name.prefix());
+        add_once(&mut self.dependencies.functions, name);
+    }
+
+    fn function_context(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        if !self.outermost() {
+            return;
+        }
+
+        let context = &mut self.dependencies.context;
+        let core_name = match name.prefix() {
+            None => name.local_part(),
+            Some(_) => "",
+        };
+        match core_name {
+            "position" => context.position = true,
+            "last" => context.size = true,
+            "lang" | "id" => context.node = true,
+            n if CONTEXT_NODE_DEFAULT_FUNCTIONS.contains(&n) => {
+                context.node = context.node || arguments.is_empty()
+            }
+            n if CONTEXT_FREE_FUNCTIONS.contains(&n) => {}
+            // Other functions are given the whole context
+            _ => {
+                context.node = true;
+                context.position = true;
+                context.size = true;
+            }
+        }
+    }
+}
+
+impl Visitor for Collector {
+    fn visit_attribute(&mut self, prefix: Option<&str>, _local_part: &str) {
+        self.prefix(prefix);
+    }
+
+    fn visit_context_node(&mut self) {
+        self.uses_context_node();
+    }
+
+    fn visit_element(&mut self, prefix: Option<&str>, _local_part: &str) {
+        self.prefix(prefix);
+    }
+
+    fn visit_for(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        body: &SubExpression,
+    ) {
+        sequence.visit(self);
+        self.bind(variable, body);
+    }
+
+    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        self.function(name);
+        self.function_context(name, arguments);
+        visitor::walk_function(self, name, arguments);
+    }
+
+    fn visit_let(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        value: &SubExpression,
+        body: &SubExpression,
+    ) {
+        value.visit(self);
+        self.bind(variable, body);
+    }
+
+    fn visit_namespace(&mut self, prefix: Option<&str>, _local_part: &str) {
+        self.prefix(prefix);
+    }
+
+    fn visit_predicate(&mut self, predicate: &Predicate) {
+        // Predicates have their own context, so only expressions
+        // outside of any predicate use the context of the XPath.
+        self.predicate_depth += 1;
+        visitor::walk_predicate(self, predicate);
+        self.predicate_depth -= 1;
+    }
+
+    fn visit_quantified(
+        &mut self,
+        _quantifier: Quantifier,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        condition: &SubExpression,
+    ) {
+        sequence.visit(self);
+        self.bind(variable, condition);
+    }
+
+    fn visit_root_node(&mut self) {
+        // The root of the document that contains the context node
+        self.uses_context_node();
+    }
+
+    fn visit_variable(&mut self, name: &OwnedPrefixedName) {
+        self.variable(name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ContextDependence, Factory, LanguageLevel, OwnedPrefixedName};
+
+    use super::{dependencies, Dependencies};
+
+    fn dependencies_of(xpath: &str) -> Dependencies {
+        let xpath = Factory::new().build(xpath).expect("Unable to build XPath");
+        dependencies(&*xpath.0)
+    }
+
+    fn context_of(xpath: &str) -> ContextDependence {
+        dependencies_of(xpath).context
+    }
+
+    #[test]
+    fn references_are_collected_once_in_order() {
+        let d = dependencies_of("$b + f:g($a, $b) + count(p:x/@q:y[$a]) + f:g(1)");
+
+        let names = |names: &[OwnedPrefixedName]| -> Vec<String> {
+            names.iter().map(ToString::to_string).collect()
+        };
+        assert_eq!(vec!["b", "a"], names(&d.variables));
+        assert_eq!(vec!["f:g", "count"], names(&d.functions));
+        assert_eq!(vec!["f", "p", "q"], d.namespace_prefixes);
+    }
+
+    #[test]
+    fn bound_variables_are_not_dependencies() {
+        let mut factory = Factory::new();
+        factory.set_language_level(LanguageLevel::XPath3);
+        let xpath = factory
+            .build("every $p:x in $a satisfies $p:x > (let $y := $b return $y + $c)")
+            .expect("Unable to build XPath");
+        let d = dependencies(&*xpath.0);
+
+        let names: Vec<_> = d.variables.iter().map(ToString::to_string).collect();
+        assert_eq!(vec!["a", "b", "c"], names);
+        assert_eq!(vec!["p"], d.namespace_prefixes);
+    }
+
+    #[test]
+    fn relative_and_absolute_paths_use_the_context_node() {
+        let node = ContextDependence {
+            node: true,
+            ..Default::default()
+        };
+
+        assert_eq!(node, context_of("a"));
+        assert_eq!(node, context_of("/a"));
+        assert_eq!(node, context_of("string()"));
+        assert_eq!(ContextDependence::default(), context_of("string($a)"));
+    }
+
+    #[test]
+    fn position_and_size_are_used_outside_of_predicates() {
+        let position_and_size = ContextDependence {
+            position: true,
+            size: true,
+            ..Default::default()
+        };
+
+        assert_eq!(position_and_size, context_of("position() = last()"));
+        assert_eq!(
+            ContextDependence::default(),
+            context_of("count($a[position() = last()][.])")
+        );
+    }
+
+    #[test]
+    fn other_functions_may_use_the_whole_context() {
+        let everything = ContextDependence {
+            node: true,
+            position: true,
+            size: true,
+        };
+
+        assert_eq!(everything, context_of("custom(1)"));
+    }
+}
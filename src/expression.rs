@@ -1,8 +1,12 @@
+// Expression constructors return boxed trait objects for the parser.
+#![allow(clippy::new_ret_no_self)]
+
 use snafu::{OptionExt, ResultExt, Snafu};
 use std::collections::HashSet;
 use std::fmt;
 use sxd_document::QName;
 
+use crate::ast;
 use crate::axis::{Axis, AxisLike};
 use crate::context;
 use crate::function;
@@ -10,7 +14,7 @@ use crate::node_test::NodeTest;
 use crate::nodeset::{Nodeset, OrderedNodes};
 use crate::visitor::{Visitable, Visitor};
 use crate::Value::{Boolean, Number};
-use crate::{LiteralValue, OwnedPrefixedName, Value};
+use crate::{Item, LiteralValue, OwnedPrefixedName, Value};
 
 #[derive(Debug, Snafu, Clone, PartialEq, Hash)]
 #[cfg_attr(test, snafu(visibility(pub(crate))))]
@@ -25,13 +29,18 @@ pub enum Error {
     UnknownNamespace { prefix: String },
     #[snafu(display("error while evaluating function: {}", source))]
     FunctionEvaluation { source: function::Error },
+    #[snafu(display("evaluation exceeded the limit on {}", limit))]
+    LimitExceeded { limit: context::Limit },
+    /// range bounds must be whole numbers
+    InvalidRangeBound,
+    #[snafu(display("range has more than {} numbers", MAX_RANGE_LENGTH))]
+    RangeTooLarge,
+    /// evaluation was cancelled
+    Cancelled,
 }
 
 fn value_into_nodeset(v: Value<'_>) -> Result<Nodeset<'_>, Error> {
-    match v {
-        Value::Nodeset(ns) => Ok(ns),
-        _ => Err(Error::NotANodeset),
-    }
+    v.into_nodeset().map_err(|_| Error::NotANodeset)
 }
 
 // In these cases, we use document order. From the spec:
@@ -40,15 +49,41 @@ fn value_into_nodeset(v: Value<'_>) -> Result<Nodeset<'_>, Error> {
 // > order, because the axis that applies to the `[1]` predicate is
 // > the child axis
 //
-fn value_into_ordered_nodes(v: Value<'_>) -> Result<OrderedNodes<'_>, Error> {
+fn value_into_ordered_nodes<'d>(
+    context: &context::Evaluation<'_, 'd>,
+    v: Value<'d>,
+) -> Result<OrderedNodes<'d>, Error> {
+    let mut ns = value_into_nodeset(v)?;
+    context.sort(&mut ns);
+    Ok(ns.into_document_order().into())
+}
+
+// Nodesets created outside of the expression, such as those returned
+// by functions or stored in variables, are sorted as they enter it so
+// that every nodeset produced during evaluation is in document order.
+fn value_in_document_order<'d>(context: &context::Evaluation<'_, 'd>, v: Value<'d>) -> Value<'d> {
     match v {
-        Value::Nodeset(ns) => Ok(ns.document_order().into()),
-        _ => Err(Error::NotANodeset),
+        Value::Nodeset(mut ns) => {
+            context.sort(&mut ns);
+            Value::Nodeset(ns)
+        }
+        v => v,
     }
 }
 
 pub trait Expression: fmt::Debug + Visitable {
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error>;
+
+    /// Evaluates the expression when only the first `limit` nodes, in
+    /// document order, of the resulting nodeset will be used. The
+    /// result may contain more nodes than that.
+    fn evaluate_first<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        _limit: usize,
+    ) -> Result<Value<'d>, Error> {
+        self.evaluate(context)
+    }
 }
 
 impl<T: ?Sized> Expression for Box<T>
@@ -58,6 +93,14 @@ where
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
         (**self).evaluate(context)
     }
+
+    fn evaluate_first<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        limit: usize,
+    ) -> Result<Value<'d>, Error> {
+        (**self).evaluate_first(context, limit)
+    }
 }
 
 pub type SubExpression = Box<dyn Expression + 'static>;
@@ -66,7 +109,7 @@ macro_rules! binary_constructor(
     ($t:ident) => (
         impl $t {
             pub fn new(left: SubExpression, right: SubExpression) -> SubExpression {
-                Box::new($t{left: left, right: right})
+                Box::new($t { left, right })
             }
         }
     );
@@ -94,6 +137,44 @@ impl Expression for And {
     }
 }
 
+/// Evaluates only one of two expressions, chosen by a condition.
+#[derive(Debug)]
+pub struct Conditional {
+    pub condition: SubExpression,
+    pub then: SubExpression,
+    pub otherwise: SubExpression,
+}
+
+impl Conditional {
+    fn branch(&self, context: &context::Evaluation<'_, '_>) -> Result<&SubExpression, Error> {
+        if self.condition.evaluate(context)?.boolean() {
+            Ok(&self.then)
+        } else {
+            Ok(&self.otherwise)
+        }
+    }
+}
+
+impl Visitable for Conditional {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_conditional(&self.condition, &self.then, &self.otherwise);
+    }
+}
+
+impl Expression for Conditional {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        self.branch(context)?.evaluate(context)
+    }
+
+    fn evaluate_first<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        limit: usize,
+    ) -> Result<Value<'d>, Error> {
+        self.branch(context)?.evaluate_first(context, limit)
+    }
+}
+
 #[allow(missing_copy_implementations)]
 #[derive(Debug)]
 pub struct ContextNode;
@@ -106,7 +187,7 @@ impl Visitable for ContextNode {
 
 impl Expression for ContextNode {
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
-        Ok(Value::Nodeset(nodeset![context.node]))
+        Ok(context.context_item())
     }
 }
 
@@ -125,44 +206,66 @@ impl Equal {
     ) -> Result<bool, Error> {
         let left_val = self.left.evaluate(context)?;
         let right_val = self.right.evaluate(context)?;
+        Ok(any_pair(left_val, right_val, equal))
+    }
+}
 
-        fn str_vals(nodes: &Nodeset<'_>) -> HashSet<String> {
-            nodes.iter().map(|n| n.string_value()).collect()
-        }
+/// Compares two values that are not sequences.
+fn equal(left_val: &Value<'_>, right_val: &Value<'_>) -> bool {
+    fn str_vals(nodes: &Nodeset<'_>) -> HashSet<String> {
+        nodes.iter().map(|n| n.string_value()).collect()
+    }
 
-        fn num_vals(nodes: &Nodeset<'_>) -> Vec<f64> {
-            // f64 isn't hashable...
-            nodes
-                .iter()
-                .map(|n| Value::String(n.string_value()).number())
-                .collect()
-        }
+    fn num_vals(nodes: &Nodeset<'_>) -> Vec<f64> {
+        // f64 isn't hashable...
+        nodes
+            .iter()
+            .map(|n| Value::String(n.string_value()).number())
+            .collect()
+    }
 
-        let v = match (&left_val, &right_val) {
-            (&Value::Nodeset(ref left_nodes), &Value::Nodeset(ref right_nodes)) => {
-                let left_strings = str_vals(left_nodes);
-                let right_strings = str_vals(right_nodes);
-                !left_strings.is_disjoint(&right_strings)
-            }
-            (&Value::Nodeset(ref nodes), &Number(val))
-            | (&Number(val), &Value::Nodeset(ref nodes)) => {
-                let numbers = num_vals(nodes);
-                numbers.iter().any(|n| *n == val)
-            }
-            (&Value::Nodeset(ref nodes), &Value::String(ref val))
-            | (&Value::String(ref val), &Value::Nodeset(ref nodes)) => {
-                let strings = str_vals(nodes);
-                strings.contains(val)
-            }
-            (&Boolean(_), _) | (_, &Boolean(_)) => left_val.boolean() == right_val.boolean(),
-            (&Number(_), _) | (_, &Number(_)) => left_val.number() == right_val.number(),
-            _ => left_val.string() == right_val.string(),
-        };
+    match (left_val, right_val) {
+        (Value::Nodeset(left_nodes), Value::Nodeset(right_nodes)) => {
+            let left_strings = str_vals(left_nodes);
+            let right_strings = str_vals(right_nodes);
+            !left_strings.is_disjoint(&right_strings)
+        }
+        (&Value::Nodeset(ref nodes), &Number(val)) | (&Number(val), &Value::Nodeset(ref nodes)) => {
+            let numbers = num_vals(nodes);
+            numbers.contains(&val)
+        }
+        (&Value::Nodeset(ref nodes), &Value::String(ref val))
+        | (&Value::String(ref val), &Value::Nodeset(ref nodes)) => {
+            let strings = str_vals(nodes);
+            strings.contains(val)
+        }
+        (&Boolean(_), _) | (_, &Boolean(_)) => left_val.boolean() == right_val.boolean(),
+        (&Number(_), _) | (_, &Number(_)) => left_val.number() == right_val.number(),
+        _ => left_val.string() == right_val.string(),
+    }
+}
 
-        Ok(v)
+/// The values that a comparison is made between: each item of a
+/// sequence on its own, or any other value as a whole.
+fn comparands(value: Value<'_>) -> Vec<Value<'_>> {
+    match value {
+        Value::Sequence(items) => items.into_iter().map(Value::from).collect(),
+        value => vec![value],
     }
 }
 
+/// Whether the comparison holds for any pair of comparands, so that a
+/// sequence is compared item by item, as a nodeset is node by node.
+fn any_pair<'d, F>(left: Value<'d>, right: Value<'d>, compare: F) -> bool
+where
+    F: Fn(&Value<'d>, &Value<'d>) -> bool,
+{
+    let right = comparands(right);
+    comparands(left)
+        .iter()
+        .any(|left| right.iter().any(|right| compare(left, right)))
+}
+
 impl Visitable for Equal {
     fn visit(&self, visitor: &mut dyn Visitor) {
         visitor.visit_equal(&self.left, &self.right);
@@ -226,6 +329,72 @@ impl Expression for Function {
                     .collect::<Result<_, _>>()?;
                 fun.evaluate(context, args).context(FunctionEvaluation)
             })
+            .map(|value| value_in_document_order(context, value))
+    }
+}
+
+/// Binds a variable to each item of a sequence in turn, joining the
+/// results of the body for each. The result is a nodeset when every
+/// result is made of nodes, and a sequence otherwise.
+#[derive(Debug)]
+pub struct For {
+    pub variable: OwnedPrefixedName,
+    pub sequence: SubExpression,
+    pub body: SubExpression,
+}
+
+impl Visitable for For {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_for(&self.variable, &self.sequence, &self.body);
+    }
+}
+
+impl Expression for For {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let name = resolve_prefixed_name(context, &self.variable)?;
+        let items = items(context, self.sequence.evaluate(context)?);
+
+        let _nesting = context.nest()?;
+
+        let mut result = Vec::new();
+        for item in items {
+            let frame = context.new_frame(name, item);
+            let value = self.body.evaluate(&context.with_frame(&frame))?;
+            extend_items(context, &mut result, value);
+            context.check_nodeset_size(result.len())?;
+        }
+
+        match Value::Sequence(result).into_nodeset() {
+            Ok(mut nodes) => {
+                context.sort(&mut nodes);
+                Ok(Value::Nodeset(nodes))
+            }
+            Err(sequence) => Ok(sequence),
+        }
+    }
+}
+
+/// Binds a variable to a value while evaluating the body.
+#[derive(Debug)]
+pub struct Let {
+    pub variable: OwnedPrefixedName,
+    pub value: SubExpression,
+    pub body: SubExpression,
+}
+
+impl Visitable for Let {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_let(&self.variable, &self.value, &self.body);
+    }
+}
+
+impl Expression for Let {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let name = resolve_prefixed_name(context, &self.variable)?;
+        let value = self.value.evaluate(context)?;
+
+        let frame = context.new_frame(name, value);
+        self.body.evaluate(&context.with_frame(&frame))
     }
 }
 
@@ -252,73 +421,48 @@ impl Expression for Literal {
     }
 }
 
-pub struct Math {
-    left: SubExpression,
-    right: SubExpression,
-    operation: fn(f64, f64) -> f64,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MathOperator {
+    Addition,
+    Subtraction,
+    Multiplication,
+    Division,
+    Remainder,
 }
 
-fn add(a: f64, b: f64) -> f64 {
-    a + b
-}
-fn subtract(a: f64, b: f64) -> f64 {
-    a - b
-}
-fn multiply(a: f64, b: f64) -> f64 {
-    a * b
-}
-fn divide(a: f64, b: f64) -> f64 {
-    a / b
+impl MathOperator {
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        use self::MathOperator::*;
+        match self {
+            Addition => a + b,
+            Subtraction => a - b,
+            Multiplication => a * b,
+            Division => a / b,
+            Remainder => a % b,
+        }
+    }
 }
-fn modulus(a: f64, b: f64) -> f64 {
-    a % b
+
+#[derive(Debug)]
+pub struct Math {
+    left: SubExpression,
+    right: SubExpression,
+    operator: MathOperator,
 }
 
 impl Math {
-    pub fn addition(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Math {
-            left,
-            right,
-            operation: add,
-        })
-    }
-
-    pub fn subtraction(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Math {
-            left,
-            right,
-            operation: subtract,
-        })
-    }
-
-    pub fn multiplication(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Math {
-            left,
-            right,
-            operation: multiply,
-        })
-    }
-
-    pub fn division(left: SubExpression, right: SubExpression) -> SubExpression {
+    pub fn new(left: SubExpression, right: SubExpression, operator: MathOperator) -> SubExpression {
         Box::new(Math {
             left,
             right,
-            operation: divide,
-        })
-    }
-
-    pub fn remainder(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Math {
-            left,
-            right,
-            operation: modulus,
+            operator,
         })
     }
 }
 
 impl Visitable for Math {
     fn visit(&self, visitor: &mut dyn Visitor) {
-        visitor.visit_math(&self.left, &self.right, &self.operation);
+        visitor.visit_math(&self.left, &self.right, self.operator);
     }
 }
 
@@ -326,18 +470,7 @@ impl Expression for Math {
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
         let left = self.left.evaluate(context)?;
         let right = self.right.evaluate(context)?;
-        let op = self.operation;
-        Ok(Number(op(left.number(), right.number())))
-    }
-}
-
-impl fmt::Debug for Math {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Math {{ left: {:?}, right: {:?} }}",
-            self.left, self.right
-        )
+        Ok(Number(self.operator.apply(left.number(), right.number())))
     }
 }
 
@@ -386,11 +519,25 @@ impl Expression for Or {
 pub struct Path {
     start_point: SubExpression,
     steps: Vec<Step>,
+    joined: Vec<(usize, Step)>,
 }
 
 impl Path {
-    pub fn new(start_point: SubExpression, steps: Vec<Step>) -> SubExpression {
-        Box::new(Path { start_point, steps })
+    /// Each of the `joined` steps selects the same nodes as a pair of
+    /// the `steps`, given by the index of the first, such as
+    /// `descendant::a` for `descendant-or-self::node()/child::a`. They
+    /// are evaluated in place of the pair, as they select nodes
+    /// without first selecting every node along the way.
+    pub fn new(
+        start_point: SubExpression,
+        steps: Vec<Step>,
+        joined: Vec<(usize, Step)>,
+    ) -> SubExpression {
+        Box::new(Path {
+            start_point,
+            steps,
+            joined,
+        })
     }
 }
 
@@ -400,19 +547,54 @@ impl Visitable for Path {
     }
 }
 
-impl Expression for Path {
-    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+impl Path {
+    fn evaluate_limited<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        limit: Option<usize>,
+    ) -> Result<Value<'d>, Error> {
         let result = self.start_point.evaluate(context)?;
         let mut result = value_into_nodeset(result)?;
 
-        for step in &self.steps {
-            result = step.evaluate(context, result)?;
+        let mut joined = self.joined.iter().peekable();
+        let mut index = 0;
+        while index < self.steps.len() {
+            let (step, next) = match joined.peek() {
+                Some(&&(at, ref step)) if at == index => {
+                    joined.next();
+                    (step, index + 2)
+                }
+                _ => (&self.steps[index], index + 1),
+            };
+
+            // Only the last step knows which of its nodes will be used
+            let step_limit = if next == self.steps.len() {
+                limit
+            } else {
+                None
+            };
+            result = step.evaluate(context, result, step_limit)?;
+            index = next;
         }
 
         Ok(Value::Nodeset(result))
     }
 }
 
+impl Expression for Path {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        self.evaluate_limited(context, None)
+    }
+
+    fn evaluate_first<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        limit: usize,
+    ) -> Result<Value<'d>, Error> {
+        self.evaluate_limited(context, Some(limit))
+    }
+}
+
 #[derive(Debug)]
 pub struct Filter {
     node_selector: SubExpression,
@@ -420,10 +602,7 @@ pub struct Filter {
 }
 
 impl Filter {
-    pub fn new(node_selector: SubExpression, predicate: SubExpression) -> SubExpression {
-        let predicate = Predicate {
-            expression: predicate,
-        };
+    pub fn new(node_selector: SubExpression, predicate: Predicate) -> SubExpression {
         Box::new(Filter {
             node_selector,
             predicate,
@@ -439,70 +618,201 @@ impl Visitable for Filter {
 
 impl Expression for Filter {
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
-        self.node_selector
-            .evaluate(context)
-            .and_then(value_into_ordered_nodes)
+        let value = match self.predicate.limit(context) {
+            Some(limit) => self.node_selector.evaluate_first(context, limit)?,
+            None => self.node_selector.evaluate(context)?,
+        };
+
+        // Sequences that only hold nodes are filtered as nodesets
+        let value = match value {
+            Value::Sequence(items) => match Value::Sequence(items).into_nodeset() {
+                Ok(nodes) => Value::Nodeset(nodes),
+                Err(Value::Sequence(items)) => {
+                    let items = self.predicate.select_items(context, items)?;
+                    return Ok(Value::Sequence(items));
+                }
+                Err(value) => value,
+            },
+            value => value,
+        };
+
+        value_into_ordered_nodes(context, value)
             .and_then(|nodes| self.predicate.select(context, nodes))
-            .map(|nodes| Value::Nodeset(nodes.into()))
+            .map(|nodes| Value::Nodeset(Nodeset::from_document_order(nodes.into())))
+    }
+}
+
+/// Whether some or every item must satisfy the condition of a
+/// quantified expression.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Quantifier {
+    Some,
+    Every,
+}
+
+/// Appends the items of a value to a sequence. Nodesets are treated as
+/// a sequence of nodes in document order, and other values as a
+/// sequence of one item.
+fn extend_items<'d>(
+    context: &context::Evaluation<'_, 'd>,
+    items: &mut Vec<Item<'d>>,
+    value: Value<'d>,
+) {
+    match value {
+        Value::Boolean(b) => items.push(Item::Boolean(b)),
+        Value::Number(n) => items.push(Item::Number(n)),
+        Value::String(s) => items.push(Item::String(s)),
+        Value::Nodeset(mut nodes) => {
+            context.sort(&mut nodes);
+            items.extend(nodes.into_document_order().into_iter().map(Item::Node));
+        }
+        Value::Sequence(more) => items.extend(more),
     }
 }
 
-pub struct Relational {
-    pub left: SubExpression,
-    pub right: SubExpression,
-    pub operation: fn(f64, f64) -> bool,
+/// The items that a variable is bound to in turn by `for`, `some` and
+/// `every`. Each node is bound as a nodeset containing only that node.
+fn items<'d>(context: &context::Evaluation<'_, 'd>, value: Value<'d>) -> Vec<Value<'d>> {
+    let mut items = Vec::new();
+    extend_items(context, &mut items, value);
+    items.into_iter().map(Value::from).collect()
 }
 
-fn less_than(left: f64, right: f64) -> bool {
-    left < right
+/// Binds a variable to each item of a sequence in turn, testing the
+/// condition with each.
+#[derive(Debug)]
+pub struct Quantified {
+    pub quantifier: Quantifier,
+    pub variable: OwnedPrefixedName,
+    pub sequence: SubExpression,
+    pub condition: SubExpression,
 }
-fn less_than_or_equal(left: f64, right: f64) -> bool {
-    left <= right
+
+impl Visitable for Quantified {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_quantified(
+            self.quantifier,
+            &self.variable,
+            &self.sequence,
+            &self.condition,
+        );
+    }
 }
-fn greater_than(left: f64, right: f64) -> bool {
-    left > right
+
+impl Expression for Quantified {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let name = resolve_prefixed_name(context, &self.variable)?;
+        let items = items(context, self.sequence.evaluate(context)?);
+
+        let _nesting = context.nest()?;
+
+        // Stops at the first item that decides the result
+        let every = self.quantifier == Quantifier::Every;
+        for item in items {
+            let frame = context.new_frame(name, item);
+            let satisfied = self
+                .condition
+                .evaluate(&context.with_frame(&frame))?
+                .boolean();
+            if satisfied != every {
+                return Ok(Boolean(satisfied));
+            }
+        }
+        Ok(Boolean(every))
+    }
 }
-fn greater_than_or_equal(left: f64, right: f64) -> bool {
-    left >= right
+
+/// The whole numbers from one number to another, such as `1 to 3`.
+/// There are no numbers when the first is greater than the second,
+/// and it is an error for either to not be a whole number or for there
+/// to be more than `MAX_RANGE_LENGTH` numbers.
+#[derive(Debug)]
+pub struct Range {
+    pub left: SubExpression,
+    pub right: SubExpression,
 }
 
-impl Relational {
-    pub fn less_than(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Relational {
-            left,
-            right,
-            operation: less_than,
-        })
+binary_constructor!(Range);
+
+/// Bounds every range, even when the context does not limit the size
+/// of nodesets, as the numbers are all held in memory.
+const MAX_RANGE_LENGTH: usize = 1 << 24;
+
+impl Visitable for Range {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_range(&self.left, &self.right);
     }
+}
 
-    pub fn less_than_or_equal(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Relational {
-            left,
-            right,
-            operation: less_than_or_equal,
-        })
+impl Expression for Range {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let is_whole = |n: f64| n.is_finite() && n.fract() == 0.0;
+        let start = self.left.evaluate(context)?.number();
+        let end = self.right.evaluate(context)?.number();
+
+        if !is_whole(start) || !is_whole(end) {
+            return Err(Error::InvalidRangeBound);
+        }
+        if start > end {
+            return Ok(Value::Sequence(Vec::new()));
+        }
+
+        let count = end - start + 1.0;
+        if count > MAX_RANGE_LENGTH as f64 {
+            return Err(Error::RangeTooLarge);
+        }
+        let count = count as usize;
+        context.check_nodeset_size(count)?;
+
+        let numbers = (0..count).map(|i| Item::Number(start + i as f64));
+        Ok(Value::Sequence(numbers.collect()))
     }
+}
 
-    pub fn greater_than(left: SubExpression, right: SubExpression) -> SubExpression {
-        Box::new(Relational {
-            left,
-            right,
-            operation: greater_than,
-        })
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RelationalOperator {
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl RelationalOperator {
+    pub fn apply(self, left: f64, right: f64) -> bool {
+        use self::RelationalOperator::*;
+        match self {
+            LessThan => left < right,
+            LessThanOrEqual => left <= right,
+            GreaterThan => left > right,
+            GreaterThanOrEqual => left >= right,
+        }
     }
+}
 
-    pub fn greater_than_or_equal(left: SubExpression, right: SubExpression) -> SubExpression {
+#[derive(Debug)]
+pub struct Relational {
+    pub left: SubExpression,
+    pub right: SubExpression,
+    pub operator: RelationalOperator,
+}
+
+impl Relational {
+    pub fn new(
+        left: SubExpression,
+        right: SubExpression,
+        operator: RelationalOperator,
+    ) -> SubExpression {
         Box::new(Relational {
             left,
             right,
-            operation: greater_than_or_equal,
+            operator,
         })
     }
 }
 
 impl Visitable for Relational {
     fn visit(&self, visitor: &mut dyn Visitor) {
-        visitor.visit_relational(&self.left, &self.right, &self.operation);
+        visitor.visit_relational(&self.left, &self.right, self.operator);
     }
 }
 
@@ -510,18 +820,10 @@ impl Expression for Relational {
     fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
         let left_val = self.left.evaluate(context)?;
         let right_val = self.right.evaluate(context)?;
-        let op = self.operation;
-        Ok(Boolean(op(left_val.number(), right_val.number())))
-    }
-}
-
-impl fmt::Debug for Relational {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Relational {{ left: {:?}, right: {:?} }}",
-            self.left, self.right
-        )
+        let operator = self.operator;
+        Ok(Boolean(any_pair(left_val, right_val, |left, right| {
+            operator.apply(left.number(), right.number())
+        })))
     }
 }
 
@@ -541,17 +843,197 @@ impl Expression for RootNode {
     }
 }
 
+/// The items of each expression in turn, such as `(1, a, $b)`.
+#[derive(Debug)]
+pub struct Sequence {
+    pub expressions: Vec<SubExpression>,
+}
+
+impl Visitable for Sequence {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_sequence(&self.expressions);
+    }
+}
+
+impl Expression for Sequence {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let mut items = Vec::new();
+        for expression in &self.expressions {
+            let value = expression.evaluate(context)?;
+            extend_items(context, &mut items, value);
+            context.check_nodeset_size(items.len())?;
+        }
+        Ok(Value::Sequence(items))
+    }
+}
+
+/// A predicate that selects nodes by their position alone, so the
+/// nodes it selects are known without evaluating it for each node.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Positional {
+    /// `[2]` or `[position() = 2]`
+    At(usize),
+    /// `[position() < 3]` or `[position() <= 2]`
+    UpTo(usize),
+    /// `[last()]` or `[position() = last()]`
+    Last,
+}
+
+impl Positional {
+    fn of(expression: &ast::Expr) -> Option<Positional> {
+        use crate::ast::BinaryOperator::*;
+
+        match *expression {
+            ast::Expr::Binary {
+                ref left,
+                operator,
+                ref right,
+            } => {
+                let (left, right) = (Term::of(left), Term::of(right));
+                // Put `position()` on the left
+                let (operator, other) = match (left, right) {
+                    (Term::Position, other) => (operator, other),
+                    (other, Term::Position) => match operator {
+                        LessThan => (GreaterThan, other),
+                        LessThanOrEqual => (GreaterThanOrEqual, other),
+                        GreaterThan => (LessThan, other),
+                        GreaterThanOrEqual => (LessThanOrEqual, other),
+                        operator => (operator, other),
+                    },
+                    _ => return None,
+                };
+
+                match (operator, other) {
+                    (Equal, Term::Last) => Some(Positional::Last),
+                    (Equal, Term::Number(n)) => Positional::at(n),
+                    (LessThan, Term::Number(n)) => Positional::up_to(n.ceil() - 1.0),
+                    (LessThanOrEqual, Term::Number(n)) => Positional::up_to(n.floor()),
+                    _ => None,
+                }
+            }
+            ref expr => match Term::of(expr) {
+                Term::Number(n) => Positional::at(n),
+                Term::Last => Some(Positional::Last),
+                _ => None,
+            },
+        }
+    }
+
+    fn at(position: f64) -> Option<Positional> {
+        // Other numbers are compared with the position they round
+        // down to, which is left to the full evaluation.
+        if position >= 1.0 && position.fract() == 0.0 {
+            Some(Positional::At(position as usize))
+        } else {
+            None
+        }
+    }
+
+    fn up_to(count: f64) -> Option<Positional> {
+        if count.is_finite() {
+            Some(Positional::UpTo(count.max(0.0) as usize))
+        } else {
+            None
+        }
+    }
+
+    /// How many of the nodes, in proximity order, must be known to
+    /// select from them.
+    fn limit(self) -> Option<usize> {
+        match self {
+            Positional::At(n) | Positional::UpTo(n) => Some(n),
+            Positional::Last => None,
+        }
+    }
+
+    fn select(self, nodes: OrderedNodes<'_>) -> OrderedNodes<'_> {
+        let mut nodes = Vec::from(nodes);
+        match self {
+            Positional::At(n) => nodes.into_iter().nth(n - 1).into_iter().collect(),
+            Positional::UpTo(n) => {
+                nodes.truncate(n);
+                nodes.into()
+            }
+            Positional::Last => nodes.pop().into_iter().collect(),
+        }
+    }
+}
+
+/// The parts of a positional predicate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Term {
+    Number(f64),
+    Position,
+    Last,
+    Other,
+}
+
+impl Term {
+    fn of(expr: &ast::Expr) -> Term {
+        match *expr {
+            ast::Expr::Literal(ast::Literal::Number(n)) => Term::Number(n),
+            ast::Expr::Function {
+                ref name,
+                ref arguments,
+            } if name.prefix().is_none() && arguments.is_empty() => match name.local_part() {
+                "position" => Term::Position,
+                "last" => Term::Last,
+                _ => Term::Other,
+            },
+            _ => Term::Other,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Predicate {
     pub expression: SubExpression,
+    positional: Option<Positional>,
+    calls_functions: bool,
 }
 
 impl Predicate {
+    /// Compiles the predicate, noting whether it only selects nodes
+    /// by their position.
+    pub fn new(expression: ast::Expr) -> Predicate {
+        let calls_functions = match expression {
+            ast::Expr::Literal(..) => false,
+            _ => true,
+        };
+        Predicate {
+            positional: Positional::of(&expression),
+            calls_functions,
+            expression: expression.compile(),
+        }
+    }
+
+    /// How the predicate selects nodes by their position, if it does.
+    /// Predicates such as `[last()]` only do so when the context has
+    /// not replaced the core functions they call.
+    fn positional(&self, context: &context::Evaluation<'_, '_>) -> Option<Positional> {
+        if self.calls_functions && !context.has_core_positional_functions() {
+            return None;
+        }
+        self.positional
+    }
+
+    /// How many of the nodes, in proximity order, the predicate needs
+    /// to select from, if not all of them.
+    fn limit(&self, context: &context::Evaluation<'_, '_>) -> Option<usize> {
+        self.positional(context).and_then(Positional::limit)
+    }
+
     fn select<'c, 'd>(
         &self,
         context: &context::Evaluation<'c, 'd>,
         nodes: OrderedNodes<'d>,
     ) -> Result<OrderedNodes<'d>, Error> {
+        if let Some(positional) = self.positional(context) {
+            return Ok(positional.select(nodes));
+        }
+
+        let _nesting = context.nest()?;
+
         context
             .new_contexts_for(nodes)
             .filter_map(|ctx| match self.matches(&ctx) {
@@ -562,6 +1044,24 @@ impl Predicate {
             .collect()
     }
 
+    /// Selects the items of a sequence, in the order they are given.
+    fn select_items<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        items: Vec<Item<'d>>,
+    ) -> Result<Vec<Item<'d>>, Error> {
+        let _nesting = context.nest()?;
+
+        let size = items.len();
+        let mut selected = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            if self.matches(&context.new_context_for_item(item, index + 1, size))? {
+                selected.push(item.clone());
+            }
+        }
+        Ok(selected)
+    }
+
     fn matches(&self, context: &context::Evaluation<'_, '_>) -> Result<bool, Error> {
         let value = self.expression.evaluate(context)?;
 
@@ -594,26 +1094,22 @@ impl<A> ParameterizedStep<A>
 where
     A: AxisLike,
 {
-    pub fn new(
-        axis: A,
-        node_test: StepTest,
-        predicates: Vec<SubExpression>,
-    ) -> ParameterizedStep<A> {
-        let preds = predicates
-            .into_iter()
-            .map(|p| Predicate { expression: p })
-            .collect();
+    pub fn new(axis: A, node_test: StepTest, predicates: Vec<Predicate>) -> ParameterizedStep<A> {
         ParameterizedStep {
             axis,
             node_test,
-            predicates: preds,
+            predicates,
         }
     }
 
-    fn evaluate<'c, 'd>(
+    /// Selects the nodes of this step from each of the starting nodes.
+    /// When a `limit` is given, only the first `limit` nodes in
+    /// document order will be used, so fewer nodes may be selected.
+    pub fn evaluate<'c, 'd>(
         &self,
         context: &context::Evaluation<'c, 'd>,
         starting_nodes: Nodeset<'d>,
+        limit: Option<usize>,
     ) -> Result<Nodeset<'d>, Error> {
         // For every starting node, we collect new nodes based on the
         // axis and node-test. We evaluate the predicates on each node.
@@ -621,20 +1117,46 @@ where
         // This seems like a likely place where we could differ from
         // the spec, so thorough testing is key.
 
-        let mut unique = Nodeset::new();
+        let _nesting = context.nest()?;
+        let mut selected = Vec::new();
+
+        // The first nodes in document order from each starting node
+        // include the first nodes of the whole result, unless the axis
+        // is in reverse or a predicate needs to see every node.
+        let axis_limit = match self.predicates.first() {
+            Some(predicate) => predicate.limit(context),
+            None if self.axis.is_reverse() => None,
+            None => limit,
+        };
 
         for node in starting_nodes.iter() {
             let child_context = context.new_context_for(node);
-            let mut nodes = self.axis.select_nodes(&child_context, &self.node_test);
+            let mut nodes = self
+                .axis
+                .select_nodes(&child_context, &self.node_test, axis_limit)?;
 
             for predicate in &self.predicates {
                 nodes = predicate.select(context, nodes)?;
             }
 
-            unique.extend(Vec::from(nodes));
+            let mut nodes = Vec::from(nodes);
+            if self.axis.is_reverse() {
+                nodes.reverse();
+            }
+            selected.extend(nodes);
+            context.check_nodeset_size(selected.len())?;
         }
 
-        Ok(unique)
+        // The nodes selected from one starting node are already in
+        // document order, but those from several may overlap or
+        // interleave.
+        if starting_nodes.size() == 1 {
+            Ok(Nodeset::from_document_order(selected))
+        } else {
+            let mut unique: Nodeset<'_> = selected.into_iter().collect();
+            context.sort(&mut unique);
+            Ok(unique)
+        }
     }
 }
 
@@ -660,10 +1182,69 @@ impl Expression for Union {
         let right_nodes = as_nodes(&self.right)?;
 
         left_nodes.extend(right_nodes);
+        context.check_nodeset_size(left_nodes.size())?;
+        context.sort(&mut left_nodes);
         Ok(Value::Nodeset(left_nodes))
     }
 }
 
+/// The nodes selected by both expressions, such as `a intersect b`.
+#[derive(Debug)]
+pub struct Intersect {
+    pub left: SubExpression,
+    pub right: SubExpression,
+}
+
+binary_constructor!(Intersect);
+
+impl Visitable for Intersect {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_intersect(&self.left, &self.right);
+    }
+}
+
+impl Expression for Intersect {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let as_nodes = |e: &SubExpression| e.evaluate(context).and_then(value_into_nodeset);
+
+        let left_nodes = as_nodes(&self.left)?;
+        let right_nodes = as_nodes(&self.right)?;
+
+        let mut nodes = left_nodes.intersection(&right_nodes);
+        context.sort(&mut nodes);
+        Ok(Value::Nodeset(nodes))
+    }
+}
+
+/// The nodes selected by the left expression but not the right, such
+/// as `a except b`.
+#[derive(Debug)]
+pub struct Except {
+    pub left: SubExpression,
+    pub right: SubExpression,
+}
+
+binary_constructor!(Except);
+
+impl Visitable for Except {
+    fn visit(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_except(&self.left, &self.right);
+    }
+}
+
+impl Expression for Except {
+    fn evaluate<'c, 'd>(&self, context: &context::Evaluation<'c, 'd>) -> Result<Value<'d>, Error> {
+        let as_nodes = |e: &SubExpression| e.evaluate(context).and_then(value_into_nodeset);
+
+        let left_nodes = as_nodes(&self.left)?;
+        let right_nodes = as_nodes(&self.right)?;
+
+        let mut nodes = left_nodes.difference(&right_nodes);
+        context.sort(&mut nodes);
+        Ok(Value::Nodeset(nodes))
+    }
+}
+
 fn resolve_prefixed_name<'a>(
     context: &'a context::Evaluation<'_, '_>,
     name: &'a OwnedPrefixedName,
@@ -697,8 +1278,8 @@ impl Expression for Variable {
 
         context
             .value_of(name)
-            .cloned()
             .context(UnknownVariable { name: &self.name })
+            .map(|value| value_in_document_order(context, value))
     }
 }
 
@@ -715,7 +1296,7 @@ mod test {
     use crate::context::{self, Context};
     use crate::function;
     use crate::node_test::NodeTest;
-    use crate::nodeset::OrderedNodes;
+    use crate::nodeset::{self, OrderedNodes};
     use crate::Value::{self, Boolean, Number, String};
 
     use super::*;
@@ -746,7 +1327,7 @@ mod test {
             }
         }
 
-        fn context(&'d self) -> context::Evaluation<'d, 'd> {
+        fn context(&self) -> context::Evaluation<'_, 'd> {
             let node = self.doc.create_element("test");
             context::Evaluation::new(&self.context, node.into())
         }
@@ -1024,7 +1605,7 @@ mod test {
             value: Value::Number(5.0),
         });
 
-        let expr = Math::multiplication(left, right);
+        let expr = Math::new(left, right, MathOperator::Multiplication);
 
         let context = setup.context();
         let res = expr.evaluate(&context);
@@ -1052,9 +1633,7 @@ mod test {
         let selected_nodes = Box::new(Variable {
             name: "nodes".into(),
         });
-        let predicate = Box::new(Literal {
-            value: Value::Number(1.0),
-        });
+        let predicate = Predicate::new(ast::Expr::Literal(ast::Literal::Number(1.0)));
 
         let expr = Filter::new(selected_nodes, predicate);
 
@@ -1078,9 +1657,7 @@ mod test {
         let selected_nodes = Box::new(Variable {
             name: "nodes".into(),
         });
-        let predicate = Box::new(Literal {
-            value: Value::Boolean(false),
-        });
+        let predicate = Predicate::new(ast::Expr::Literal(ast::Literal::Boolean(false)));
 
         let expr = Filter::new(selected_nodes, predicate);
 
@@ -1102,7 +1679,7 @@ mod test {
             value: Value::Number(5.0),
         });
 
-        let expr = Relational::less_than(left, right);
+        let expr = Relational::new(left, right, RelationalOperator::LessThan);
 
         let context = setup.context();
         let res = expr.evaluate(&context);
@@ -1144,9 +1721,10 @@ mod test {
             &self,
             _context: &context::Evaluation<'c, 'd>,
             _node_test: &dyn NodeTest,
-        ) -> OrderedNodes<'d> {
+            _limit: Option<usize>,
+        ) -> Result<OrderedNodes<'d>, Error> {
             *self.calls.borrow_mut() += 1;
-            OrderedNodes::new()
+            Ok(OrderedNodes::new())
         }
     }
 
@@ -1173,7 +1751,8 @@ mod test {
         let expr = ParameterizedStep::new(axis.clone(), Box::new(node_test), vec![]);
 
         let context = setup.context();
-        expr.evaluate(&context, nodeset![context.node]).unwrap();
+        expr.evaluate(&context, nodeset![context.node], None)
+            .unwrap();
 
         assert_eq!(1, axis.calls());
     }
@@ -1205,6 +1784,37 @@ mod test {
         assert_eq!(res, Ok(Value::Nodeset(nodeset![left_node, right_node])));
     }
 
+    #[test]
+    fn expression_union_is_in_document_order() {
+        let package = Package::new();
+        let mut setup = Setup::new(&package);
+
+        let first = setup.doc.create_element("first");
+        let second = setup.doc.create_element("second");
+        setup.doc.root().append_child(first);
+        first.append_child(second);
+
+        setup.context.set_variable("left", nodeset![second]);
+        let left = Box::new(Variable {
+            name: "left".into(),
+        });
+        setup.context.set_variable("right", nodeset![first]);
+        let right = Box::new(Variable {
+            name: "right".into(),
+        });
+
+        let expr = Union { left, right };
+
+        let context = setup.context();
+        let res = expr.evaluate(&context);
+
+        let nodes: Vec<nodeset::Node<'_>> = match res {
+            Ok(Value::Nodeset(nodes)) => nodes.iter().collect(),
+            other => panic!("expected a nodeset, got {:?}", other),
+        };
+        assert_eq!(nodes, vec![first.into(), second.into()]);
+    }
+
     #[test]
     fn expression_variable_looks_up_the_variable() {
         let package = Package::new();
@@ -1218,4 +1828,79 @@ mod test {
 
         assert_eq!(res, Ok(Boolean(true)));
     }
+
+    fn positional(predicate: &str) -> Option<Positional> {
+        let expr = crate::Factory::new()
+            .parse(predicate)
+            .expect("Unable to parse XPath");
+        Positional::of(&expr)
+    }
+
+    #[test]
+    fn numbers_and_position_tests_are_positional() {
+        assert_eq!(Some(Positional::At(2)), positional("2"));
+        assert_eq!(Some(Positional::At(2)), positional("2 = position()"));
+        assert_eq!(Some(Positional::Last), positional("last()"));
+        assert_eq!(Some(Positional::Last), positional("position() = last()"));
+        assert_eq!(Some(Positional::UpTo(2)), positional("position() < 3"));
+        assert_eq!(Some(Positional::UpTo(2)), positional("position() <= 2.5"));
+        assert_eq!(Some(Positional::UpTo(2)), positional("2.5 > position()"));
+        assert_eq!(Some(Positional::UpTo(0)), positional("position() < 0.5"));
+    }
+
+    #[test]
+    fn other_predicates_are_not_positional() {
+        assert_eq!(None, positional("1.5"));
+        assert_eq!(None, positional("position() > 2"));
+        assert_eq!(None, positional("position() = $n"));
+        assert_eq!(None, positional("last() - 1"));
+        assert_eq!(None, positional("p:position() = 1"));
+    }
+
+    #[test]
+    fn positional_predicates_select_without_evaluating() {
+        let package = Package::new();
+        let setup = Setup::new(&package);
+        let context = setup.context();
+        let nodes: Vec<_> = (0..4)
+            .map(|_| nodeset::Node::from(setup.doc.create_element("a")))
+            .collect();
+        let select = |positional: Positional| -> Vec<nodeset::Node<'_>> {
+            positional.select(nodes.clone().into()).into()
+        };
+        let predicate = |positional| Predicate {
+            expression: Box::new(FailExpression),
+            positional: Some(positional),
+            calls_functions: false,
+        };
+
+        assert_eq!(vec![nodes[1]], select(Positional::At(2)));
+        assert_eq!(Vec::<nodeset::Node<'_>>::new(), select(Positional::At(5)));
+        assert_eq!(nodes[..3].to_vec(), select(Positional::UpTo(3)));
+        assert_eq!(vec![nodes[3]], select(Positional::Last));
+        assert_eq!(
+            Ok(ordered_nodes![nodes[3]]),
+            predicate(Positional::Last).select(&context, nodes.clone().into())
+        );
+    }
+
+    #[test]
+    fn positional_predicates_are_evaluated_without_the_core_functions() {
+        let package = Package::new();
+        let setup = Setup::new(&package);
+        let context = setup.context();
+        let node = nodeset::Node::from(setup.doc.create_element("a"));
+
+        // As if `last()` had been replaced by a function returning false
+        let predicate = Predicate {
+            expression: ast::Expr::Literal(ast::Literal::Boolean(false)).compile(),
+            positional: Some(Positional::Last),
+            calls_functions: true,
+        };
+
+        assert_eq!(
+            Ok(ordered_nodes![]),
+            predicate.select(&context, ordered_nodes![node])
+        );
+    }
 }
@@ -0,0 +1,429 @@
+//! Support for rewriting XPaths.
+//!
+//! A [`Fold`][] takes each part of an XPath, as an [`Expr`][], and
+//! returns the part to replace it with. Every method has a default
+//! implementation that folds the children of the part with the
+//! matching `fold_*` function and rebuilds it, so a fold only
+//! implements the methods for the parts it changes.
+//!
+//! # Examples
+//!
+//! Replacing a variable with its value:
+//!
+//! ```
+//! use sxd_xpath::ast::{Expr, Literal};
+//! use sxd_xpath::fold::Fold;
+//! use sxd_xpath::{Factory, OwnedPrefixedName};
+//!
+//! struct Inline;
+//!
+//! impl Fold for Inline {
+//!     fn fold_variable(&mut self, name: OwnedPrefixedName) -> Expr {
+//!         match name.local_part() {
+//!             "limit" => Expr::Literal(Literal::Number(10.0)),
+//!             _ => Expr::Variable(name),
+//!         }
+//!     }
+//! }
+//!
+//! let xpath = Factory::new()
+//!     .build("count(item) > $limit")
+//!     .expect("Could not compile XPath");
+//! let xpath = xpath.fold(&mut Inline);
+//!
+//! assert_eq!(xpath.to_string(), "count(child::item) > 10");
+//! ```
+//!
+//! [`Fold`]: trait.Fold.html
+//! [`Expr`]: ../ast/enum.Expr.html
+
+use crate::ast::{BinaryOperator, Expr, Literal, NameTest, NodeTest, Quantifier, Step};
+use crate::OwnedPrefixedName;
+
+pub trait Fold {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr(self, expr)
+    }
+
+    fn fold_binary(&mut self, left: Expr, operator: BinaryOperator, right: Expr) -> Expr {
+        fold_binary(self, left, operator, right)
+    }
+
+    fn fold_conditional(&mut self, condition: Expr, then: Expr, otherwise: Expr) -> Expr {
+        fold_conditional(self, condition, then, otherwise)
+    }
+
+    fn fold_context_node(&mut self) -> Expr {
+        Expr::ContextNode
+    }
+
+    fn fold_filter(&mut self, expression: Expr, predicate: Expr) -> Expr {
+        fold_filter(self, expression, predicate)
+    }
+
+    fn fold_for(&mut self, variable: OwnedPrefixedName, sequence: Expr, body: Expr) -> Expr {
+        fold_for(self, variable, sequence, body)
+    }
+
+    fn fold_function(&mut self, name: OwnedPrefixedName, arguments: Vec<Expr>) -> Expr {
+        fold_function(self, name, arguments)
+    }
+
+    fn fold_let(&mut self, variable: OwnedPrefixedName, value: Expr, body: Expr) -> Expr {
+        fold_let(self, variable, value, body)
+    }
+
+    fn fold_literal(&mut self, literal: Literal) -> Expr {
+        Expr::Literal(literal)
+    }
+
+    fn fold_name_test(&mut self, name_test: NameTest) -> NameTest {
+        name_test
+    }
+
+    fn fold_negation(&mut self, expression: Expr) -> Expr {
+        fold_negation(self, expression)
+    }
+
+    fn fold_node_test(&mut self, node_test: NodeTest) -> NodeTest {
+        fold_node_test(self, node_test)
+    }
+
+    fn fold_path(&mut self, start: Expr, steps: Vec<Step>) -> Expr {
+        fold_path(self, start, steps)
+    }
+
+    /// Folds a predicate of a step or a filter.
+    fn fold_predicate(&mut self, predicate: Expr) -> Expr {
+        fold_predicate(self, predicate)
+    }
+
+    fn fold_quantified(
+        &mut self,
+        quantifier: Quantifier,
+        variable: OwnedPrefixedName,
+        sequence: Expr,
+        condition: Expr,
+    ) -> Expr {
+        fold_quantified(self, quantifier, variable, sequence, condition)
+    }
+
+    fn fold_root_node(&mut self) -> Expr {
+        Expr::RootNode
+    }
+
+    fn fold_sequence(&mut self, expressions: Vec<Expr>) -> Expr {
+        fold_sequence(self, expressions)
+    }
+
+    fn fold_step(&mut self, step: Step) -> Step {
+        fold_step(self, step)
+    }
+
+    fn fold_variable(&mut self, name: OwnedPrefixedName) -> Expr {
+        Expr::Variable(name)
+    }
+}
+
+/// Passes the expression to the method of the fold for its kind.
+pub fn fold_expr<F>(folder: &mut F, expr: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    match expr {
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        } => folder.fold_binary(*left, operator, *right),
+        Expr::Negation(expression) => folder.fold_negation(*expression),
+        Expr::Filter {
+            expression,
+            predicate,
+        } => folder.fold_filter(*expression, *predicate),
+        Expr::Path { start, steps } => folder.fold_path(*start, steps),
+        Expr::Literal(literal) => folder.fold_literal(literal),
+        Expr::Variable(name) => folder.fold_variable(name),
+        Expr::Function { name, arguments } => folder.fold_function(name, arguments),
+        Expr::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => folder.fold_conditional(*condition, *then, *otherwise),
+        Expr::Quantified {
+            quantifier,
+            variable,
+            sequence,
+            condition,
+        } => folder.fold_quantified(quantifier, variable, *sequence, *condition),
+        Expr::For {
+            variable,
+            sequence,
+            body,
+        } => folder.fold_for(variable, *sequence, *body),
+        Expr::Let {
+            variable,
+            value,
+            body,
+        } => folder.fold_let(variable, *value, *body),
+        Expr::Sequence(expressions) => folder.fold_sequence(expressions),
+        Expr::RootNode => folder.fold_root_node(),
+        Expr::ContextNode => folder.fold_context_node(),
+    }
+}
+
+pub fn fold_binary<F>(folder: &mut F, left: Expr, operator: BinaryOperator, right: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    let left = folder.fold_expr(left);
+    let right = folder.fold_expr(right);
+    Expr::binary(left, operator, right)
+}
+
+pub fn fold_conditional<F>(folder: &mut F, condition: Expr, then: Expr, otherwise: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Conditional {
+        condition: Box::new(folder.fold_expr(condition)),
+        then: Box::new(folder.fold_expr(then)),
+        otherwise: Box::new(folder.fold_expr(otherwise)),
+    }
+}
+
+pub fn fold_filter<F>(folder: &mut F, expression: Expr, predicate: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Filter {
+        expression: Box::new(folder.fold_expr(expression)),
+        predicate: Box::new(folder.fold_predicate(predicate)),
+    }
+}
+
+pub fn fold_for<F>(folder: &mut F, variable: OwnedPrefixedName, sequence: Expr, body: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::For {
+        variable,
+        sequence: Box::new(folder.fold_expr(sequence)),
+        body: Box::new(folder.fold_expr(body)),
+    }
+}
+
+pub fn fold_function<F>(folder: &mut F, name: OwnedPrefixedName, arguments: Vec<Expr>) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Function {
+        name,
+        arguments: arguments
+            .into_iter()
+            .map(|argument| folder.fold_expr(argument))
+            .collect(),
+    }
+}
+
+pub fn fold_let<F>(folder: &mut F, variable: OwnedPrefixedName, value: Expr, body: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Let {
+        variable,
+        value: Box::new(folder.fold_expr(value)),
+        body: Box::new(folder.fold_expr(body)),
+    }
+}
+
+pub fn fold_negation<F>(folder: &mut F, expression: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Negation(Box::new(folder.fold_expr(expression)))
+}
+
+pub fn fold_node_test<F>(folder: &mut F, node_test: NodeTest) -> NodeTest
+where
+    F: Fold + ?Sized,
+{
+    match node_test {
+        NodeTest::Name(name_test) => NodeTest::Name(folder.fold_name_test(name_test)),
+        node_test => node_test,
+    }
+}
+
+pub fn fold_path<F>(folder: &mut F, start: Expr, steps: Vec<Step>) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Path {
+        start: Box::new(folder.fold_expr(start)),
+        steps: steps
+            .into_iter()
+            .map(|step| folder.fold_step(step))
+            .collect(),
+    }
+}
+
+pub fn fold_predicate<F>(folder: &mut F, predicate: Expr) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    folder.fold_expr(predicate)
+}
+
+pub fn fold_quantified<F>(
+    folder: &mut F,
+    quantifier: Quantifier,
+    variable: OwnedPrefixedName,
+    sequence: Expr,
+    condition: Expr,
+) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Quantified {
+        quantifier,
+        variable,
+        sequence: Box::new(folder.fold_expr(sequence)),
+        condition: Box::new(folder.fold_expr(condition)),
+    }
+}
+
+pub fn fold_sequence<F>(folder: &mut F, expressions: Vec<Expr>) -> Expr
+where
+    F: Fold + ?Sized,
+{
+    Expr::Sequence(
+        expressions
+            .into_iter()
+            .map(|expression| folder.fold_expr(expression))
+            .collect(),
+    )
+}
+
+pub fn fold_step<F>(folder: &mut F, step: Step) -> Step
+where
+    F: Fold + ?Sized,
+{
+    Step {
+        axis: step.axis,
+        node_test: folder.fold_node_test(step.node_test),
+        predicates: step
+            .predicates
+            .into_iter()
+            .map(|predicate| folder.fold_predicate(predicate))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::{Axis, BinaryOperator, Expr, Literal, NameTest, NodeTest, Step};
+    use crate::{Factory, OwnedPrefixedName, XPath};
+
+    use super::Fold;
+
+    fn name(local_part: &str) -> NodeTest {
+        NodeTest::Name(NameTest {
+            prefix: None,
+            local_part: local_part.to_string(),
+        })
+    }
+
+    fn build(xpath: &str) -> XPath {
+        Factory::new().build(xpath).expect("Unable to build XPath")
+    }
+
+    struct Identity;
+
+    impl Fold for Identity {}
+
+    #[test]
+    fn the_default_methods_rebuild_the_same_xpath() {
+        let source = "-$a[1] or f(/b/@c | d, 'e') and (g/text())[h != 2] div -3";
+        let xpath = build(source);
+        let expected = format!("{:?}", xpath);
+
+        assert_eq!(expected, format!("{:?}", xpath.fold(&mut Identity)));
+    }
+
+    struct Prefixes;
+
+    impl Fold for Prefixes {
+        fn fold_name_test(&mut self, mut name_test: NameTest) -> NameTest {
+            if name_test.prefix.as_ref().map(String::as_str) == Some("old") {
+                name_test.prefix = Some("new".into());
+            }
+            name_test
+        }
+
+        fn fold_function(&mut self, name: OwnedPrefixedName, arguments: Vec<Expr>) -> Expr {
+            let name = match name.prefix() {
+                Some("old") => ("new", name.local_part()).into(),
+                _ => name,
+            };
+            super::fold_function(self, name, arguments)
+        }
+    }
+
+    #[test]
+    fn names_can_be_rewritten() {
+        let xpath = build("old:f(old:a/@old:b, other:c)").fold(&mut Prefixes);
+
+        assert_eq!(
+            xpath.to_string(),
+            "new:f(child::new:a/attribute::new:b, child::other:c)"
+        );
+    }
+
+    /// Only allows `row` elements owned by the `$user`.
+    struct RowSecurity;
+
+    impl Fold for RowSecurity {
+        fn fold_step(&mut self, step: Step) -> Step {
+            let mut step = super::fold_step(self, step);
+            if step.node_test == name("row") {
+                let owner = Expr::Path {
+                    start: Box::new(Expr::ContextNode),
+                    steps: vec![Step::new(Axis::Attribute, name("owner"))],
+                };
+                let owned =
+                    Expr::binary(owner, BinaryOperator::Equal, Expr::Variable("user".into()));
+                step.predicates.insert(0, owned);
+            }
+            step
+        }
+    }
+
+    #[test]
+    fn predicates_can_be_added() {
+        let xpath = build("count(//row[@id > 2])").fold(&mut RowSecurity);
+
+        assert_eq!(
+            xpath.to_string(),
+            "count(/descendant-or-self::node()/child::row\
+             [attribute::owner = $user][attribute::id > 2])"
+        );
+    }
+
+    struct Double;
+
+    impl Fold for Double {
+        fn fold_literal(&mut self, literal: Literal) -> Expr {
+            match literal {
+                Literal::Number(n) => Expr::Literal(Literal::Number(n * 2.0)),
+                literal => Expr::Literal(literal),
+            }
+        }
+    }
+
+    #[test]
+    fn nested_expressions_are_folded() {
+        let xpath = build("a[1][f(2, -3)] + $b[4]").fold(&mut Double);
+
+        assert_eq!(xpath.to_string(), "child::a[2][f(4, -6)] + $b[8]");
+    }
+}
@@ -4,13 +4,16 @@ use snafu::Snafu;
 use std::borrow::ToOwned;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::iter;
+use std::marker::PhantomData;
 use std::ops::Index;
+use std::rc::Rc;
+use std::vec;
+use std::{fmt, iter};
 use sxd_document::XmlChar;
 
 use crate::context;
-use crate::nodeset::Nodeset;
-use crate::{str_to_num, Value};
+use crate::nodeset::{Node, Nodeset};
+use crate::{Item, Value, XML_NS_URI};
 
 /// Types that can be used as XPath functions.
 pub trait Function {
@@ -21,15 +24,115 @@ pub trait Function {
         context: &context::Evaluation<'c, 'd>,
         args: Vec<Value<'d>>,
     ) -> Result<Value<'d>, Error>;
+
+    /// The arguments this function accepts and the value it returns,
+    /// used to check calls before they are evaluated. Functions
+    /// without a signature are not checked.
+    fn signature(&self) -> Option<Signature> {
+        None
+    }
+
+    /// Whether this function always returns the same value for the
+    /// same arguments without using the evaluation context. Calls to
+    /// pure core functions with constant arguments are evaluated once,
+    /// when the XPath is built.
+    fn is_pure(&self) -> bool {
+        false
+    }
+}
+
+impl<F> Function for Rc<F>
+where
+    F: Function + ?Sized,
+{
+    fn evaluate<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        args: Vec<Value<'d>>,
+    ) -> Result<Value<'d>, Error> {
+        (**self).evaluate(context, args)
+    }
+
+    fn signature(&self) -> Option<Signature> {
+        (**self).signature()
+    }
+
+    fn is_pure(&self) -> bool {
+        (**self).is_pure()
+    }
+}
+
+/// An XPath function that can only be evaluated with documents that
+/// live for `'d`, such as a Rust function registered with
+/// [`Context::set_function_fn`](../context/struct.Context.html#method.set_function_fn)
+/// that accepts a `Nodeset<'d>`. Every `Function` is also one of
+/// these.
+pub trait DocumentFunction<'d> {
+    /// Evaluate this function in a specific context with a specific
+    /// set of arguments.
+    fn evaluate<'c>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        args: Vec<Value<'d>>,
+    ) -> Result<Value<'d>, Error>;
+
+    /// See [`Function::signature`](trait.Function.html#method.signature).
+    fn signature(&self) -> Option<Signature> {
+        None
+    }
+
+    /// See [`Function::is_pure`](trait.Function.html#method.is_pure).
+    fn is_pure(&self) -> bool {
+        false
+    }
+}
+
+impl<'d, F> DocumentFunction<'d> for F
+where
+    F: Function + ?Sized,
+{
+    fn evaluate<'c>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        args: Vec<Value<'d>>,
+    ) -> Result<Value<'d>, Error> {
+        Function::evaluate(self, context, args)
+    }
+
+    fn signature(&self) -> Option<Signature> {
+        Function::signature(self)
+    }
+
+    fn is_pure(&self) -> bool {
+        Function::is_pure(self)
+    }
 }
 
 /// Represents the kind of an XPath value without carrying a value.
-#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ArgumentType {
     Boolean,
     Number,
     String,
     Nodeset,
+    /// The type of a
+    /// [`Value::Sequence`](../enum.Value.html#variant.Sequence). This
+    /// variant was added in 0.5.0, so code that matched every
+    /// `ArgumentType` must now handle it as well.
+    Sequence,
+}
+
+impl fmt::Display for ArgumentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            ArgumentType::Boolean => "boolean",
+            ArgumentType::Number => "number",
+            ArgumentType::String => "string",
+            ArgumentType::Nodeset => "nodeset",
+            ArgumentType::Sequence => "sequence",
+        };
+        f.write_str(name)
+    }
 }
 
 impl<'a> From<&'a Value<'a>> for ArgumentType {
@@ -39,6 +142,78 @@ impl<'a> From<&'a Value<'a>> for ArgumentType {
             Value::Number(..) => ArgumentType::Number,
             Value::String(..) => ArgumentType::String,
             Value::Nodeset(..) => ArgumentType::Nodeset,
+            Value::Sequence(..) => ArgumentType::Sequence,
+        }
+    }
+}
+
+/// The arguments a function accepts and the value it returns.
+///
+/// Arguments other than nodesets are converted to the expected type,
+/// so a function that accepts any value lists the type it converts
+/// the value to.
+///
+/// # Examples
+///
+/// The signature of `substring(string, number, number?)`:
+///
+/// ```
+/// use sxd_xpath::function::{ArgumentType, Signature};
+///
+/// let signature = Signature::new(
+///     vec![ArgumentType::String, ArgumentType::Number, ArgumentType::Number],
+///     ArgumentType::String,
+/// )
+/// .optional(1);
+///
+/// assert_eq!(2, signature.min_arity);
+/// assert_eq!(Some(3), signature.max_arity);
+/// assert_eq!(Some(ArgumentType::Number), signature.argument_type(2));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+    /// The fewest arguments the function accepts.
+    pub min_arity: usize,
+    /// The most arguments the function accepts, or `None` if the
+    /// last argument may be repeated.
+    pub max_arity: Option<usize>,
+    /// The type of each argument.
+    pub arguments: Vec<ArgumentType>,
+    /// The type of the returned value.
+    pub returns: ArgumentType,
+}
+
+impl Signature {
+    /// A function that requires each of the arguments.
+    pub fn new(arguments: Vec<ArgumentType>, returns: ArgumentType) -> Signature {
+        let arity = arguments.len();
+        Signature {
+            min_arity: arity,
+            max_arity: Some(arity),
+            arguments,
+            returns,
+        }
+    }
+
+    /// Makes the last `count` arguments optional.
+    pub fn optional(mut self, count: usize) -> Signature {
+        self.min_arity = self.min_arity.saturating_sub(count);
+        self
+    }
+
+    /// Allows the last argument to be repeated any number of times.
+    pub fn variadic(mut self) -> Signature {
+        self.max_arity = None;
+        self
+    }
+
+    /// The type of the argument at the given position, if the
+    /// function accepts that many arguments.
+    pub fn argument_type(&self, index: usize) -> Option<ArgumentType> {
+        match self.arguments.get(index) {
+            Some(&argument) => Some(argument),
+            None if self.max_arity.is_none() => self.arguments.last().cloned(),
+            None => None,
         }
     }
 }
@@ -142,54 +317,54 @@ impl<'d> Args<'d> {
         Ok(v.into_string())
     }
 
-    /// Removes the **last** argument and ensures it is a nodeset. If
-    /// the argument is not a nodeset, a type mismatch error is
-    /// returned.
+    /// Removes the **last** argument and ensures it is a nodeset. A
+    /// sequence that only holds nodes is accepted as a nodeset. If the
+    /// argument is not a nodeset, a type mismatch error is returned.
     pub fn pop_nodeset(&mut self) -> Result<Nodeset<'d>, Error> {
         let v = self.0.pop().ok_or(Error::ArgumentMissing)?;
-        match v {
-            Value::Nodeset(v) => Ok(v),
-            a => Err(Error::not_a_nodeset(&a)),
-        }
+        v.into_nodeset().map_err(|a| Error::not_a_nodeset(&a))
     }
 
     /// Removes the **last** argument. If no argument is present, the
-    /// context node is returned as a nodeset.
+    /// context item is returned.
     fn pop_value_or_context_node<'c>(
         &mut self,
         context: &context::Evaluation<'c, 'd>,
     ) -> Value<'d> {
-        self.0
-            .pop()
-            .unwrap_or_else(|| Value::Nodeset(nodeset![context.node]))
+        self.0.pop().unwrap_or_else(|| context.context_item())
     }
 
     /// Removes the **last** argument if it is a string. If no
-    /// argument is present, the context node is converted to a string
+    /// argument is present, the context item is converted to a string
     /// and returned. If there is an argument but it is not a string,
     /// it is converted to one.
     fn pop_string_value_or_context_node(
         &mut self,
-        context: &context::Evaluation<'_, '_>,
+        context: &context::Evaluation<'_, 'd>,
     ) -> String {
-        self.0
-            .pop()
-            .map(Value::into_string)
-            .unwrap_or_else(|| context.node.string_value())
+        self.pop_value_or_context_node(context).into_string()
     }
 
     /// Removes the **last** argument if it is a nodeset. If no
-    /// argument is present, the context node is added to a nodeset
-    /// and returned. If there is an argument but it is not a nodeset,
-    /// a type mismatch error is returned.
+    /// argument is present, the context item is used instead. If the
+    /// value is not a nodeset, a type mismatch error is returned.
     fn pop_nodeset_or_context_node<'c>(
         &mut self,
         context: &context::Evaluation<'c, 'd>,
     ) -> Result<Nodeset<'d>, Error> {
-        match self.0.pop() {
-            Some(Value::Nodeset(ns)) => Ok(ns),
-            Some(arg) => Err(Error::not_a_nodeset(&arg)),
-            None => Ok(nodeset![context.node]),
+        self.pop_value_or_context_node(context)
+            .into_nodeset()
+            .map_err(|a| Error::not_a_nodeset(&a))
+    }
+
+    /// Removes the **last** argument and gives its items. A nodeset
+    /// holds its nodes, in no particular order, and any other value
+    /// that is not a sequence is a type mismatch error.
+    fn pop_items(&mut self) -> Result<Vec<Item<'d>>, Error> {
+        match self.0.pop().ok_or(Error::ArgumentMissing)? {
+            Value::Nodeset(nodes) => Ok(nodes.iter().map(Item::Node).collect()),
+            Value::Sequence(items) => Ok(items),
+            other => Err(Error::not_a_nodeset(&other)),
         }
     }
 }
@@ -202,6 +377,311 @@ impl<'d> Index<usize> for Args<'d> {
     }
 }
 
+/// Rust types that an XPath argument can be converted into. Numbers,
+/// strings and booleans are converted following the `number()`,
+/// `string()` and `boolean()` functions, nodesets are not converted,
+/// and a `Value` accepts any argument as it is.
+pub trait FromValue<'d>: Sized {
+    /// The type of argument this is converted from, or `None` if any
+    /// argument is accepted as it is.
+    const TYPE: Option<ArgumentType>;
+
+    fn from_value(value: Value<'d>) -> Result<Self, Error>;
+}
+
+impl<'d> FromValue<'d> for f64 {
+    const TYPE: Option<ArgumentType> = Some(ArgumentType::Number);
+
+    fn from_value(value: Value<'d>) -> Result<f64, Error> {
+        Ok(value.into_number())
+    }
+}
+
+impl<'d> FromValue<'d> for String {
+    const TYPE: Option<ArgumentType> = Some(ArgumentType::String);
+
+    fn from_value(value: Value<'d>) -> Result<String, Error> {
+        Ok(value.into_string())
+    }
+}
+
+impl<'d> FromValue<'d> for bool {
+    const TYPE: Option<ArgumentType> = Some(ArgumentType::Boolean);
+
+    fn from_value(value: Value<'d>) -> Result<bool, Error> {
+        Ok(value.into_boolean())
+    }
+}
+
+impl<'d> FromValue<'d> for Nodeset<'d> {
+    const TYPE: Option<ArgumentType> = Some(ArgumentType::Nodeset);
+
+    fn from_value(value: Value<'d>) -> Result<Nodeset<'d>, Error> {
+        value.into_nodeset().map_err(|a| Error::not_a_nodeset(&a))
+    }
+}
+
+impl<'d> FromValue<'d> for Value<'d> {
+    const TYPE: Option<ArgumentType> = None;
+
+    fn from_value(value: Value<'d>) -> Result<Value<'d>, Error> {
+        Ok(value)
+    }
+}
+
+/// A parameter of a Rust function registered with
+/// [`Context::set_function_fn`](../context/struct.Context.html#method.set_function_fn).
+///
+/// A `FromValue` type is a required parameter, `Option<T>` is an
+/// optional parameter and `Vec<T>` collects all the remaining
+/// arguments. Optional parameters may only be followed by other
+/// optional parameters or a variadic one, and a variadic parameter
+/// must be the last; functions with other orders are rejected when
+/// they are registered.
+///
+/// ```compile_fail
+/// use sxd_xpath::Context;
+///
+/// let mut context = Context::new();
+/// context.set_function_fn("pick", |a: Option<f64>, b: f64| a.unwrap_or(b));
+/// ```
+pub trait Parameter<'d>: Sized {
+    /// Whether this parameter is `Required`, `Optional` or `Variadic`.
+    type Kind;
+
+    /// The fewest arguments this parameter consumes.
+    const MIN: usize;
+    /// The most arguments this parameter consumes, if limited.
+    const MAX: Option<usize>;
+    /// The type of each argument this parameter consumes, or `None`
+    /// if any argument is accepted as it is.
+    const TYPE: Option<ArgumentType>;
+
+    /// Converts the arguments consumed by this parameter.
+    fn take(args: &mut vec::IntoIter<Value<'d>>) -> Result<Self, Error>;
+}
+
+/// The kind of a parameter that consumes exactly one argument.
+pub enum Required {}
+/// The kind of a parameter that consumes at most one argument.
+pub enum Optional {}
+/// The kind of a parameter that consumes all the remaining arguments.
+pub enum Variadic {}
+
+/// Parameter kinds that may come after a parameter of kind `K`.
+pub trait Follows<K> {}
+
+impl Follows<Required> for Required {}
+impl Follows<Required> for Optional {}
+impl Follows<Required> for Variadic {}
+impl Follows<Optional> for Optional {}
+impl Follows<Optional> for Variadic {}
+
+impl<'d, T: FromValue<'d>> Parameter<'d> for T {
+    type Kind = Required;
+
+    const MIN: usize = 1;
+    const MAX: Option<usize> = Some(1);
+    const TYPE: Option<ArgumentType> = T::TYPE;
+
+    fn take(args: &mut vec::IntoIter<Value<'d>>) -> Result<T, Error> {
+        args.next()
+            .ok_or(Error::ArgumentMissing)
+            .and_then(T::from_value)
+    }
+}
+
+impl<'d, T: FromValue<'d>> Parameter<'d> for Option<T> {
+    type Kind = Optional;
+
+    const MIN: usize = 0;
+    const MAX: Option<usize> = Some(1);
+    const TYPE: Option<ArgumentType> = T::TYPE;
+
+    fn take(args: &mut vec::IntoIter<Value<'d>>) -> Result<Option<T>, Error> {
+        match args.next() {
+            Some(arg) => T::from_value(arg).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'d, T: FromValue<'d>> Parameter<'d> for Vec<T> {
+    type Kind = Variadic;
+
+    const MIN: usize = 0;
+    const MAX: Option<usize> = None;
+    const TYPE: Option<ArgumentType> = T::TYPE;
+
+    fn take(args: &mut vec::IntoIter<Value<'d>>) -> Result<Vec<T>, Error> {
+        args.map(T::from_value).collect()
+    }
+}
+
+/// Rust types that a function registered with
+/// [`Context::set_function_fn`](../context/struct.Context.html#method.set_function_fn)
+/// may return. Returning a `Result` allows the function to fail.
+pub trait IntoResult<'d> {
+    /// The type of the returned value, or `None` if it is only known
+    /// once the function has been evaluated.
+    const TYPE: Option<ArgumentType>;
+
+    fn into_result(self) -> Result<Value<'d>, Error>;
+}
+
+macro_rules! value_result {
+    ($($t:ty => $kind:expr),*) => {
+        $(
+            impl<'d> IntoResult<'d> for $t {
+                const TYPE: Option<ArgumentType> = $kind;
+
+                fn into_result(self) -> Result<Value<'d>, Error> {
+                    Ok(Value::from(self))
+                }
+            }
+        )*
+    };
+}
+
+value_result!(
+    f64 => Some(ArgumentType::Number),
+    String => Some(ArgumentType::String),
+    bool => Some(ArgumentType::Boolean),
+    &'static str => Some(ArgumentType::String),
+    Nodeset<'d> => Some(ArgumentType::Nodeset),
+    Value<'d> => None
+);
+
+impl<'d, T: IntoResult<'d>> IntoResult<'d> for Result<T, Error> {
+    const TYPE: Option<ArgumentType> = T::TYPE;
+
+    fn into_result(self) -> Result<Value<'d>, Error> {
+        self.and_then(IntoResult::into_result)
+    }
+}
+
+/// Rust functions and closures whose parameters are all `Parameter`s
+/// and that return an `IntoResult`. `P` is the tuple of parameter
+/// types.
+///
+/// Parameters and return values may borrow from documents that live
+/// for `'d`, such as `Nodeset<'d>`. A closure cannot return a value
+/// borrowed from its parameters, so such functions have to be
+/// declared with `fn`. Functions that take or return a `Value` have
+/// no signature, so their calls are only checked when they are
+/// evaluated. Functions that need the evaluation context should
+/// implement [`Function`](trait.Function.html) instead.
+///
+/// ```
+/// use sxd_document::parser;
+/// use sxd_xpath::nodeset::Nodeset;
+/// use sxd_xpath::{Context, Factory};
+///
+/// fn leaves<'d>(nodes: Nodeset<'d>) -> Nodeset<'d> {
+///     nodes.into_iter().filter(|node| node.children().is_empty()).collect()
+/// }
+///
+/// let package = parser::parse("<a><b/><c>1</c></a>").expect("failed to parse XML");
+/// let document = package.as_document();
+///
+/// let mut context = Context::new();
+/// context.set_function_fn("leaves", leaves);
+/// context.set_function_fn("size", |nodes: Nodeset| nodes.size() as f64);
+///
+/// let xpath = Factory::new()
+///     .build("size(leaves(//*))")
+///     .expect("Could not compile XPath");
+/// let value = xpath.evaluate(&context, document.root()).expect("XPath evaluation failed");
+///
+/// assert_eq!(1.0, value.number());
+/// ```
+pub trait IntoFunction<'d, P> {
+    /// Wraps this in a function that checks the number of arguments
+    /// and converts them to the parameter types.
+    fn into_function(self) -> Box<dyn DocumentFunction<'d> + 'd>;
+}
+
+struct Typed<F, P> {
+    function: F,
+    parameters: PhantomData<fn(P)>,
+}
+
+// Each pair of neighbouring parameters is listed after the `;` so
+// that the order of their kinds can be checked.
+macro_rules! typed_function {
+    ($($p:ident $arg:ident),* ; $($prev:ident $next:ident),*) => {
+        impl<'d, F, R, $($p),*> DocumentFunction<'d> for Typed<F, ($($p,)*)>
+        where
+            F: Fn($($p),*) -> R,
+            R: IntoResult<'d>,
+            $($p: Parameter<'d>,)*
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn evaluate<'c>(
+                &self,
+                _context: &context::Evaluation<'c, 'd>,
+                args: Vec<Value<'d>>,
+            ) -> Result<Value<'d>, Error> {
+                let (min_arity, max_arity) = Self::arity();
+                let args = Args(args);
+                args.at_least(min_arity)?;
+                if let Some(maximum) = max_arity {
+                    args.at_most(maximum)?;
+                }
+
+                let mut args = args.0.into_iter();
+                $(let $arg = $p::take(&mut args)?;)*
+                (self.function)($($arg),*).into_result()
+            }
+
+            fn signature(&self) -> Option<Signature> {
+                let (min_arity, max_arity) = Self::arity();
+                Some(Signature {
+                    min_arity,
+                    max_arity,
+                    arguments: vec![$($p::TYPE?),*],
+                    returns: R::TYPE?,
+                })
+            }
+        }
+
+        impl<F, $($p),*> Typed<F, ($($p,)*)> {
+            #[allow(clippy::extra_unused_lifetimes)]
+            fn arity<'d>() -> (usize, Option<usize>)
+            where
+                $($p: Parameter<'d>,)*
+            {
+                let min = 0 $(+ $p::MIN)*;
+                let max = Some(0) $(.and_then(|n| $p::MAX.map(|m| n + m)))*;
+                (min, max)
+            }
+        }
+
+        impl<'d, F, R, $($p),*> IntoFunction<'d, ($($p,)*)> for F
+        where
+            F: Fn($($p),*) -> R + 'd,
+            R: IntoResult<'d>,
+            $($p: Parameter<'d> + 'd,)*
+            $(<$next as Parameter<'d>>::Kind: Follows<<$prev as Parameter<'d>>::Kind>,)*
+        {
+            fn into_function(self) -> Box<dyn DocumentFunction<'d> + 'd> {
+                Box::new(Typed {
+                    function: self,
+                    parameters: PhantomData,
+                })
+            }
+        }
+    };
+}
+
+typed_function!(;);
+typed_function!(A a;);
+typed_function!(A a, B b; A B);
+typed_function!(A a, B b, C c; A B, B C);
+typed_function!(A a, B b, C c, D d; A B, B C, C D);
+typed_function!(A a, B b, C c, D d, E e; A B, B C, C D, D E);
+typed_function!(A a, B b, C c, D d, E e, G g; A B, B C, C D, D E, E G);
+
 struct Last;
 
 impl Function for Last {
@@ -214,6 +694,10 @@ impl Function for Last {
         args.exactly(0)?;
         Ok(Value::Number(context.size as f64))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![], ArgumentType::Number))
+    }
 }
 
 struct Position;
@@ -228,6 +712,10 @@ impl Function for Position {
         args.exactly(0)?;
         Ok(Value::Number(context.position as f64))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![], ArgumentType::Number))
+    }
 }
 
 struct Count;
@@ -240,8 +728,55 @@ impl Function for Count {
     ) -> Result<Value<'d>, Error> {
         let mut args = Args(args);
         args.exactly(1)?;
-        let arg = args.pop_nodeset()?;
-        Ok(Value::Number(arg.size() as f64))
+        let arg = args.pop_items()?;
+        Ok(Value::Number(arg.len() as f64))
+    }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::Nodeset],
+            ArgumentType::Number,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
+}
+
+struct Id;
+
+impl Function for Id {
+    fn evaluate<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        args: Vec<Value<'d>>,
+    ) -> Result<Value<'d>, Error> {
+        let mut args = Args(args);
+        args.exactly(1)?;
+        let ids = match args.0.pop().ok_or(Error::ArgumentMissing)? {
+            Value::Nodeset(ns) => ns.iter().map(|n| n.string_value()).collect(),
+            Value::Sequence(items) => items
+                .into_iter()
+                .map(|item| Value::from(item).into_string())
+                .collect(),
+            arg => vec![arg.into_string()],
+        };
+        let elements = ids
+            .iter()
+            .flat_map(|ids| ids.split(XmlChar::is_space_char))
+            .filter(|id| !id.is_empty())
+            .filter_map(|id| context.element_with_id(id))
+            .map(Node::Element)
+            .collect();
+        Ok(Value::Nodeset(elements))
+    }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::String],
+            ArgumentType::Nodeset,
+        ))
     }
 }
 
@@ -263,6 +798,10 @@ impl Function for LocalName {
             .unwrap_or("");
         Ok(Value::String(name.to_owned()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::Nodeset], ArgumentType::String).optional(1))
+    }
 }
 
 struct NamespaceUri;
@@ -283,6 +822,10 @@ impl Function for NamespaceUri {
             .unwrap_or("");
         Ok(Value::String(name.to_owned()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::Nodeset], ArgumentType::String).optional(1))
+    }
 }
 
 struct Name;
@@ -302,6 +845,10 @@ impl Function for Name {
             .unwrap_or_else(String::new);
         Ok(Value::String(name))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::Nodeset], ArgumentType::String).optional(1))
+    }
 }
 
 struct StringFn;
@@ -317,6 +864,10 @@ impl Function for StringFn {
         let arg = args.pop_value_or_context_node(context);
         Ok(Value::String(arg.string()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::String], ArgumentType::String).optional(1))
+    }
 }
 
 struct Concat;
@@ -332,6 +883,14 @@ impl Function for Concat {
         let args = args.into_strings();
         Ok(Value::String(args.concat()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::String; 2], ArgumentType::String).variadic())
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 struct TwoStringPredicate(fn(&str, &str) -> bool);
@@ -348,18 +907,29 @@ impl Function for TwoStringPredicate {
         let v = self.0(&args[0], &args[1]);
         Ok(Value::Boolean(v))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::String; 2],
+            ArgumentType::Boolean,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 fn starts_with() -> TwoStringPredicate {
     fn imp(a: &str, b: &str) -> bool {
         str::starts_with(a, b)
-    };
+    }
     TwoStringPredicate(imp)
 }
 fn contains() -> TwoStringPredicate {
     fn imp(a: &str, b: &str) -> bool {
         str::contains(a, b)
-    };
+    }
     TwoStringPredicate(imp)
 }
 
@@ -377,6 +947,17 @@ impl Function for SubstringCommon {
         let s = self.0(&args[0], &args[1]);
         Ok(Value::String(s.to_owned()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::String; 2],
+            ArgumentType::String,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 fn substring_before() -> SubstringCommon {
@@ -436,6 +1017,24 @@ impl Function for Substring {
 
         Ok(Value::String(selected_chars))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(
+            Signature::new(
+                vec![
+                    ArgumentType::String,
+                    ArgumentType::Number,
+                    ArgumentType::Number,
+                ],
+                ArgumentType::String,
+            )
+            .optional(1),
+        )
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 struct StringLength;
@@ -451,6 +1050,10 @@ impl Function for StringLength {
         let arg = args.pop_string_value_or_context_node(context);
         Ok(Value::Number(arg.chars().count() as f64))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::String], ArgumentType::Number).optional(1))
+    }
 }
 
 struct NormalizeSpace;
@@ -472,6 +1075,10 @@ impl Function for NormalizeSpace {
         let s = s.join(" ");
         Ok(Value::String(s))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::String], ArgumentType::String).optional(1))
+    }
 }
 
 struct Translate;
@@ -501,11 +1108,68 @@ impl Function for Translate {
 
         let s = s
             .chars()
-            .filter_map(|c| replacements.get(&c).cloned().unwrap_or_else(|| Some(c)))
+            .filter_map(|c| replacements.get(&c).cloned().unwrap_or(Some(c)))
             .collect();
 
         Ok(Value::String(s))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::String; 3],
+            ArgumentType::String,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
+}
+
+struct Lang;
+
+impl Function for Lang {
+    fn evaluate<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        args: Vec<Value<'d>>,
+    ) -> Result<Value<'d>, Error> {
+        let mut args = Args(args);
+        args.exactly(1)?;
+        let wanted = args.pop_string()?;
+
+        let mut node = Some(context.node);
+        while let Some(n) = node {
+            let lang = n
+                .element()
+                .and_then(|e| e.attribute_value((XML_NS_URI, "lang")));
+            if let Some(lang) = lang {
+                return Ok(Value::Boolean(lang_matches(lang, &wanted)));
+            }
+            node = n.parent();
+        }
+
+        Ok(Value::Boolean(false))
+    }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::String],
+            ArgumentType::Boolean,
+        ))
+    }
+}
+
+/// Whether the language is the wanted one, or a sublanguage of it,
+/// ignoring case.
+fn lang_matches(lang: &str, wanted: &str) -> bool {
+    match lang.get(..wanted.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(wanted) => {
+            let rest = &lang[wanted.len()..];
+            rest.is_empty() || rest.starts_with('-')
+        }
+        _ => false,
+    }
 }
 
 struct BooleanFn;
@@ -520,6 +1184,17 @@ impl Function for BooleanFn {
         args.exactly(1)?;
         Ok(Value::Boolean(args[0].boolean()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::Boolean],
+            ArgumentType::Boolean,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 struct Not;
@@ -535,6 +1210,17 @@ impl Function for Not {
         let arg = args.pop_boolean()?;
         Ok(Value::Boolean(!arg))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::Boolean],
+            ArgumentType::Boolean,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 struct BooleanLiteral(bool);
@@ -549,6 +1235,14 @@ impl Function for BooleanLiteral {
         args.exactly(0)?;
         Ok(Value::Boolean(self.0))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![], ArgumentType::Boolean))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 fn true_fn() -> BooleanLiteral {
@@ -571,6 +1265,10 @@ impl Function for NumberFn {
         let arg = args.pop_value_or_context_node(context);
         Ok(Value::Number(arg.number()))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(vec![ArgumentType::Number], ArgumentType::Number).optional(1))
+    }
 }
 
 struct Sum;
@@ -583,13 +1281,24 @@ impl Function for Sum {
     ) -> Result<Value<'d>, Error> {
         let mut args = Args(args);
         args.exactly(1)?;
-        let arg = args.pop_nodeset()?;
+        let arg = args.pop_items()?;
         let r = arg
-            .iter()
-            .map(|n| str_to_num(&n.string_value()))
+            .into_iter()
+            .map(|item| Value::from(item).number())
             .fold(0.0, |acc, i| acc + i);
         Ok(Value::Number(r))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::Nodeset],
+            ArgumentType::Number,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 struct NumberConvert(fn(f64) -> f64);
@@ -605,6 +1314,17 @@ impl Function for NumberConvert {
         let arg = args.pop_number()?;
         Ok(Value::Number(self.0(arg)))
     }
+
+    fn signature(&self) -> Option<Signature> {
+        Some(Signature::new(
+            vec![ArgumentType::Number],
+            ArgumentType::Number,
+        ))
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
 }
 
 fn floor() -> NumberConvert {
@@ -641,6 +1361,7 @@ pub fn register_core_functions(context: &mut context::Context<'_>) {
     context.set_function("last", Last);
     context.set_function("position", Position);
     context.set_function("count", Count);
+    context.set_function("id", Id);
     context.set_function("local-name", LocalName);
     context.set_function("namespace-uri", NamespaceUri);
     context.set_function("name", Name);
@@ -658,28 +1379,32 @@ pub fn register_core_functions(context: &mut context::Context<'_>) {
     context.set_function("not", Not);
     context.set_function("true", true_fn());
     context.set_function("false", false_fn());
+    context.set_function("lang", Lang);
     context.set_function("number", NumberFn);
     context.set_function("sum", Sum);
     context.set_function("floor", floor());
     context.set_function("ceiling", ceiling());
     context.set_function("round", round());
+    context.mark_core_functions(&["last", "position"]);
 }
 
 #[cfg(test)]
 mod test {
     use std::borrow::ToOwned;
+    use std::marker::PhantomData;
     use std::{f64, fmt};
 
     use sxd_document::Package;
 
     use crate::context;
-    use crate::nodeset::Node;
+    use crate::nodeset::{Node, Nodeset};
     use crate::{LiteralValue, Value};
 
     use super::{
-        ceiling, contains, floor, round, starts_with, substring_after, substring_before, BooleanFn,
-        Concat, Count, Error, Function, Last, LocalName, Name, NamespaceUri, NormalizeSpace,
-        NumberFn, Position, StringFn, StringLength, Substring, Sum, Translate,
+        ceiling, contains, floor, round, starts_with, substring_after, substring_before,
+        ArgumentType, BooleanFn, Concat, Count, DocumentFunction, Error, Id, Lang, Last, LocalName,
+        Name, NamespaceUri, NormalizeSpace, NumberFn, Position, Signature, StringFn, StringLength,
+        Substring, Sum, Translate,
     };
 
     /// Converts each argument into a `Value` and packs them into a
@@ -709,16 +1434,24 @@ mod test {
         fn evaluate<N, F>(&self, node: N, f: F, args: Vec<Value<'d>>) -> Result<Value<'d>, Error>
         where
             N: Into<Node<'d>>,
-            F: Function,
+            F: DocumentFunction<'d>,
         {
             let context = context::Evaluation::new(&self.context, node.into());
             f.evaluate(&context, args)
         }
     }
 
+    /// A Rust function or closure registered as a `Function`.
+    fn typed<F, P>(function: F) -> super::Typed<F, P> {
+        super::Typed {
+            function,
+            parameters: PhantomData,
+        }
+    }
+
     fn evaluate_literal<F, F2, T>(f: F, args: Vec<LiteralValue>, rf: F2) -> T
     where
-        F: Function,
+        F: for<'d> DocumentFunction<'d>,
         F2: FnOnce(Result<Value<'_>, Error>) -> T,
     {
         let package = Package::new();
@@ -753,6 +1486,183 @@ mod test {
         assert_eq!(Ok(Value::Number(1.0)), r);
     }
 
+    #[test]
+    fn typed_function_converts_its_arguments() {
+        let f = typed(|s: String, n: f64, b: bool| format!("{}{}{}", s, n, b));
+        evaluate_literal(f, args![1.0, "2", 0.0], |r| {
+            assert_eq!(Ok(Value::String("12false".to_owned())), r);
+        });
+    }
+
+    #[test]
+    fn typed_function_checks_the_number_of_arguments() {
+        let f = typed(|x: f64| x * 2.0);
+        evaluate_literal(f, args![], |r| {
+            assert_eq!(
+                Err(Error::NotEnoughArguments {
+                    expected: 1,
+                    actual: 0
+                }),
+                r
+            );
+        });
+
+        let f = typed(|x: f64| x * 2.0);
+        evaluate_literal(f, args![1.0, 2.0], |r| {
+            assert_eq!(
+                Err(Error::TooManyArguments {
+                    expected: 1,
+                    actual: 2
+                }),
+                r
+            );
+        });
+    }
+
+    #[test]
+    fn typed_function_accepts_optional_and_variadic_parameters() {
+        let f = typed(|x: f64, y: Option<f64>| x + y.unwrap_or(10.0));
+        evaluate_literal(f, args![1.0], |r| assert_eq!(Ok(Value::Number(11.0)), r));
+
+        let f = typed(|x: f64, y: Option<f64>| x + y.unwrap_or(10.0));
+        evaluate_literal(f, args![1.0, 2.0], |r| {
+            assert_eq!(Ok(Value::Number(3.0)), r)
+        });
+
+        let f = typed(|xs: Vec<f64>| xs.len() as f64);
+        evaluate_literal(f, args![1.0, 2.0, 3.0], |r| {
+            assert_eq!(Ok(Value::Number(3.0)), r)
+        });
+    }
+
+    #[test]
+    fn typed_function_signature_lists_its_parameter_types() {
+        fn first<'d>(nodes: Nodeset<'d>, _: Vec<String>) -> Nodeset<'d> {
+            nodes
+        }
+        fn identity<'d>(value: Value<'d>) -> Value<'d> {
+            value
+        }
+
+        assert_eq!(
+            Some(
+                Signature::new(
+                    vec![ArgumentType::Nodeset, ArgumentType::String],
+                    ArgumentType::Nodeset
+                )
+                .optional(1)
+                .variadic()
+            ),
+            DocumentFunction::signature(&typed(first))
+        );
+        assert_eq!(None, DocumentFunction::signature(&typed(identity)));
+    }
+
+    #[test]
+    fn typed_function_may_fail() {
+        let f = typed(|x: f64| {
+            if x < 0.0 {
+                Err(Error::Other {
+                    what: "negative".to_owned(),
+                })
+            } else {
+                Ok(x.sqrt())
+            }
+        });
+        evaluate_literal(f, args![-1.0], |r| {
+            assert_eq!(
+                Err(Error::Other {
+                    what: "negative".to_owned()
+                }),
+                r
+            );
+        });
+    }
+
+    #[test]
+    fn id_finds_elements_for_each_whitespace_separated_id() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let setup = Setup::new();
+
+        let top = doc.create_element("top");
+        let a = doc.create_element("a");
+        a.set_attribute_value("id", "one");
+        let b = doc.create_element("b");
+        b.set_attribute_value(("http://www.w3.org/XML/1998/namespace", "id"), "two");
+        doc.root().append_child(top);
+        top.append_child(a);
+        top.append_child(b);
+
+        let r = setup.evaluate(doc.root(), Id, args![" two\tmissing\none "]);
+
+        assert_eq!(Ok(Value::Nodeset(nodeset![a, b])), r);
+    }
+
+    #[test]
+    fn id_uses_the_string_value_of_each_node_in_a_nodeset() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let setup = Setup::new();
+
+        let top = doc.create_element("top");
+        let a = doc.create_element("a");
+        a.set_attribute_value("id", "one");
+        let reference = doc.create_text("one");
+        doc.root().append_child(top);
+        top.append_child(a);
+        top.append_child(reference);
+
+        let r = setup.evaluate(doc.root(), Id, args![nodeset![reference]]);
+
+        assert_eq!(Ok(Value::Nodeset(nodeset![a])), r);
+    }
+
+    #[test]
+    fn id_uses_the_configured_id_attributes() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let mut setup = Setup::new();
+        setup.context.set_id_attributes(vec!["key"]);
+
+        let top = doc.create_element("top");
+        let a = doc.create_element("a");
+        a.set_attribute_value("id", "one");
+        let b = doc.create_element("b");
+        b.set_attribute_value("key", "one");
+        doc.root().append_child(top);
+        top.append_child(a);
+        top.append_child(b);
+
+        let r = setup.evaluate(doc.root(), Id, args!["one"]);
+
+        assert_eq!(Ok(Value::Nodeset(nodeset![b])), r);
+    }
+
+    #[test]
+    fn id_index_is_kept_until_it_is_cleared() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let setup = Setup::new();
+
+        let top = doc.create_element("top");
+        let a = doc.create_element("a");
+        a.set_attribute_value("id", "one");
+        doc.root().append_child(top);
+        top.append_child(a);
+
+        let r = setup.evaluate(doc.root(), Id, args!["one"]);
+        assert_eq!(Ok(Value::Nodeset(nodeset![a])), r);
+
+        a.set_attribute_value("id", "two");
+        let r = setup.evaluate(doc.root(), Id, args!["two"]);
+        assert_eq!(Ok(Value::Nodeset(nodeset![])), r);
+
+        setup.context.clear_id_index();
+        let r = setup.evaluate(doc.root(), Id, args!["two"]);
+        assert_eq!(Ok(Value::Nodeset(nodeset![a])), r);
+    }
+
     #[test]
     fn local_name_gets_name_of_element() {
         let package = Package::new();
@@ -969,6 +1879,43 @@ mod test {
         });
     }
 
+    fn lang_test(lang: &str, wanted: &str) -> bool {
+        let package = Package::new();
+        let doc = package.as_document();
+        let setup = Setup::new();
+
+        let parent = doc.create_element("parent");
+        parent.set_attribute_value(("http://www.w3.org/XML/1998/namespace", "lang"), lang);
+        let child = doc.create_element("child");
+        doc.root().append_child(parent);
+        parent.append_child(child);
+
+        setup
+            .evaluate(child, Lang, args![wanted])
+            .expect("Unable to evaluate lang")
+            .boolean()
+    }
+
+    #[test]
+    fn lang_matches_the_nearest_language_ignoring_case() {
+        assert!(lang_test("en", "EN"));
+        assert!(!lang_test("en", "de"));
+    }
+
+    #[test]
+    fn lang_matches_sublanguages() {
+        assert!(lang_test("en-US", "en"));
+        assert!(!lang_test("en", "en-US"));
+        assert!(!lang_test("eng", "en"));
+    }
+
+    #[test]
+    fn lang_is_false_without_a_language() {
+        evaluate_literal(Lang, args!["en"], |r| {
+            assert_eq!(Ok(Value::Boolean(false)), r);
+        });
+    }
+
     #[test]
     fn number_converts_to_number() {
         evaluate_literal(NumberFn, args![" -1.2 "], |r| {
@@ -1031,7 +1978,7 @@ mod test {
     fn assert_number(expected: f64, actual: Result<Value<'_>, Error>) {
         match actual {
             Ok(Value::Number(n)) => assert_eq!(PedanticNumber(n), PedanticNumber(expected)),
-            _ => assert!(false, "{:?} did not evaluate correctly", actual),
+            _ => panic!("{:?} did not evaluate correctly", actual),
         }
     }
 
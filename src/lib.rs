@@ -83,9 +83,9 @@
 //! defined prefixes, some XPath behavior may be confusing:
 //!
 //! 1. The `name` method will not include a prefix, even if the
-//! element or attribute has a namespace.
+//!    element or attribute has a namespace.
 //! 2. The `namespace` axis will not include namespaces without
-//! prefixes.
+//!    prefixes.
 //!
 //! #### Document order
 //!
@@ -93,45 +93,68 @@
 //! nodes to the document, some XPath behavior may be confusing:
 //!
 //! 1. These nodes have no [*document order*]. If you create a
-//! variable containing these nodes and apply a predicate to them,
-//! these nodes will appear after any nodes that are present in the
-//! document, but the relative order of the nodes is undefined.
+//!    variable containing these nodes and apply a predicate to them,
+//!    these nodes will appear after any nodes that are present in the
+//!    document, but the relative order of the nodes is undefined.
 //!
 //! [*document order*]: https://www.w3.org/TR/xpath/#dt-document-order
 
 use snafu::{ResultExt, Snafu};
 use std::borrow::ToOwned;
+use std::fmt;
 use std::string;
 use sxd_document::dom::Document;
 use sxd_document::{PrefixedName, QName};
 
+use crate::fold::Fold;
 use crate::parser::Parser;
 use crate::tokenizer::{TokenDeabbreviator, Tokenizer};
 
 pub use crate::context::Context;
+pub use crate::pattern::Pattern;
 pub use crate::visitor::{Visitable, Visitor};
 
 #[macro_use]
 pub mod macros;
+pub mod ast;
 mod axis;
+mod check;
 pub mod context;
+mod dependencies;
 mod expression;
+pub mod fold;
 pub mod function;
 mod node_test;
 pub mod nodeset;
+mod optimize;
 mod parser;
+pub mod pattern;
+mod printer;
 mod token;
 mod tokenizer;
 pub mod visitor;
 
 // These belong in the the document
 
+/// The namespace bound to the `xml` prefix.
+const XML_NS_URI: &str = "http://www.w3.org/XML/1998/namespace";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OwnedPrefixedName {
     prefix: Option<String>,
     local_part: String,
 }
 
+impl OwnedPrefixedName {
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_ref().map(String::as_str)
+    }
+
+    pub fn local_part(&self) -> &str {
+        &self.local_part
+    }
+}
+
 impl<'a> From<&'a str> for OwnedPrefixedName {
     fn from(local_part: &'a str) -> Self {
         OwnedPrefixedName {
@@ -168,12 +191,30 @@ impl<'a> From<&'a OwnedPrefixedName> for OwnedPrefixedName {
     }
 }
 
+impl fmt::Display for OwnedPrefixedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix {
+            Some(ref prefix) => write!(f, "{}:{}", prefix, self.local_part),
+            None => f.write_str(&self.local_part),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OwnedQName {
     namespace_uri: Option<String>,
     local_part: String,
 }
 
+impl OwnedQName {
+    pub(crate) fn as_qname(&self) -> QName<'_> {
+        QName::with_namespace_uri(
+            self.namespace_uri.as_ref().map(String::as_str),
+            &self.local_part,
+        )
+    }
+}
+
 impl<'a> From<&'a str> for OwnedQName {
     fn from(local_part: &'a str) -> Self {
         OwnedQName {
@@ -201,6 +242,26 @@ impl<'a> From<QName<'a>> for OwnedQName {
     }
 }
 
+/// A region of an XPath expression, as byte offsets into the
+/// original string.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
 type LiteralValue = Value<'static>;
 
 /// The primary types of values that an XPath expression accepts
@@ -215,6 +276,36 @@ pub enum Value<'d> {
     String(string::String),
     /// A collection of unique nodes
     Nodeset(nodeset::Nodeset<'d>),
+    /// An ordered list of items, which may repeat. Only produced by
+    /// XPath 2.0 expressions.
+    ///
+    /// This variant was added in 0.5.0: code that matched every
+    /// variant of `Value` must now handle it as well, for example by
+    /// converting it with [`string`](#method.string) or
+    /// [`number`](#method.number).
+    Sequence(Vec<Item<'d>>),
+}
+
+/// One member of a [`Value::Sequence`](enum.Value.html#variant.Sequence).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item<'d> {
+    Boolean(bool),
+    Number(f64),
+    String(string::String),
+    Node(nodeset::Node<'d>),
+}
+
+impl<'d> From<Item<'d>> for Value<'d> {
+    /// Converts the item into the value it is treated as when it is
+    /// used on its own. A node becomes a nodeset containing it.
+    fn from(other: Item<'d>) -> Value<'d> {
+        match other {
+            Item::Boolean(b) => Value::Boolean(b),
+            Item::Number(n) => Value::Number(n),
+            Item::String(s) => Value::String(s),
+            Item::Node(n) => Value::Nodeset(nodeset![n]),
+        }
+    }
 }
 
 fn str_to_num(s: &str) -> f64 {
@@ -222,6 +313,9 @@ fn str_to_num(s: &str) -> f64 {
 }
 
 impl<'d> Value<'d> {
+    /// Converts the value as the `boolean()` function does. A sequence
+    /// is false when it is empty, true when its first item is a node,
+    /// and otherwise converted like its first item.
     pub fn boolean(&self) -> bool {
         use crate::Value::*;
         match *self {
@@ -229,6 +323,11 @@ impl<'d> Value<'d> {
             Number(n) => n != 0.0 && !n.is_nan(),
             String(ref s) => !s.is_empty(),
             Nodeset(ref nodeset) => nodeset.size() > 0,
+            Sequence(ref items) => match items.first() {
+                Some(&Item::Node(_)) => true,
+                Some(item) => Value::from(item.clone()).boolean(),
+                None => false,
+            },
         }
     }
 
@@ -236,6 +335,8 @@ impl<'d> Value<'d> {
         self.boolean()
     }
 
+    /// Converts the value as the `number()` function does. A sequence
+    /// is converted like its first item, and is NaN when it is empty.
     pub fn number(&self) -> f64 {
         use crate::Value::*;
         match *self {
@@ -249,6 +350,10 @@ impl<'d> Value<'d> {
             Number(val) => val,
             String(ref s) => str_to_num(s),
             Nodeset(..) => str_to_num(&self.string()),
+            Sequence(ref items) => match items.first() {
+                Some(item) => Value::from(item.clone()).number(),
+                None => ::std::f64::NAN,
+            },
         }
     }
 
@@ -256,6 +361,8 @@ impl<'d> Value<'d> {
         self.number()
     }
 
+    /// Converts the value as the `string()` function does. A sequence
+    /// is converted like its first item, and is empty when it is empty.
     pub fn string(&self) -> string::String {
         use crate::Value::*;
         match *self {
@@ -267,6 +374,9 @@ impl<'d> Value<'d> {
                     } else {
                         "Infinity".to_owned()
                     }
+                } else if n == 0.0 {
+                    // Negative zero is also represented as `0`
+                    "0".to_owned()
                 } else {
                     n.to_string()
                 }
@@ -276,6 +386,10 @@ impl<'d> Value<'d> {
                 Some(n) => n.string_value(),
                 None => "".to_owned(),
             },
+            Sequence(ref items) => match items.first() {
+                Some(item) => Value::from(item.clone()).string(),
+                None => "".to_owned(),
+            },
         }
     }
 
@@ -286,6 +400,31 @@ impl<'d> Value<'d> {
             other => other.string(),
         }
     }
+
+    /// The nodes of a nodeset, or of a sequence that only holds nodes.
+    /// Any other value is given back.
+    pub(crate) fn into_nodeset(self) -> Result<nodeset::Nodeset<'d>, Value<'d>> {
+        match self {
+            Value::Nodeset(nodes) => Ok(nodes),
+            Value::Sequence(items) => {
+                let is_node = |item: &Item<'_>| match *item {
+                    Item::Node(_) => true,
+                    _ => false,
+                };
+                if !items.iter().all(is_node) {
+                    return Err(Value::Sequence(items));
+                }
+                Ok(items
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        Item::Node(node) => Some(node),
+                        _ => None,
+                    })
+                    .collect())
+            }
+            other => Err(other),
+        }
+    }
 }
 
 macro_rules! from_impl {
@@ -307,6 +446,7 @@ impl<'a, 'd> From<&'a str> for Value<'d> {
     }
 }
 from_impl!(nodeset::Nodeset<'d>, Value::Nodeset);
+from_impl!(Vec<Item<'d>>, Value::Sequence);
 
 macro_rules! partial_eq_impl {
     ($raw:ty, $variant:pat => $b:expr) => {
@@ -339,8 +479,12 @@ partial_eq_impl!(nodeset::Nodeset<'d>, Value::Nodeset(ref v) => v);
 /// A compiled XPath. Construct via [`Factory`][].
 ///
 /// [`Factory`]: struct.Factory.html
-#[derive(Debug)]
-pub struct XPath(Box<dyn expression::Expression + 'static>);
+pub struct XPath(
+    Box<dyn expression::Expression + 'static>,
+    /// The XPath as it was written, if it has been simplified since.
+    /// Problems are looked for in branches that were removed, too.
+    Option<Box<dyn expression::Expression + 'static>>,
+);
 
 impl XPath {
     /// Evaluate this expression in the given context.
@@ -371,9 +515,215 @@ impl XPath {
     where
         N: Into<nodeset::Node<'d>>,
     {
+        let _running = context.start_evaluation();
         let context = context::Evaluation::new(context, node.into());
         self.0.evaluate(&context).map_err(ExecutionError)
     }
+
+    /// Checks every function call against the [`Signature`][] of the
+    /// function it calls in the given context, without evaluating
+    /// anything. Fails with the first call that has the wrong number
+    /// of arguments or passes a value that cannot be a nodeset where
+    /// one is required.
+    ///
+    /// Calls to functions that are not in the context or that have no
+    /// signature are not checked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sxd_xpath::{Context, Factory};
+    ///
+    /// let context = Context::new();
+    /// let factory = Factory::new();
+    ///
+    /// let xpath = factory.build("count(//a)").expect("Could not compile XPath");
+    /// assert!(xpath.check(&context).is_ok());
+    ///
+    /// let xpath = factory.build("count(1)").expect("Could not compile XPath");
+    /// let error = xpath.check(&context).unwrap_err();
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "argument 1 of count() must be a nodeset but is a number"
+    /// );
+    /// ```
+    ///
+    /// [`Signature`]: function/struct.Signature.html
+    pub fn check(&self, context: &Context<'_>) -> Result<(), CheckError> {
+        match check::check(self.as_written(), context).into_iter().next() {
+            Some(error) => Err(CheckError(error)),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports every function, variable and namespace prefix used by
+    /// this XPath that cannot be found in the given context, along
+    /// with the problems found by [`check`](#method.check). Each
+    /// problem is reported once. Nothing is evaluated, so branches that
+    /// evaluation would skip are validated too.
+    ///
+    /// Variables are assumed to exist when the context has a variable
+    /// resolver, and functions are looked up with its function
+    /// resolver.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sxd_xpath::{Context, Factory};
+    ///
+    /// let mut context = Context::new();
+    /// context.set_variable("limit", 10.0);
+    ///
+    /// let xpath = Factory::new()
+    ///     .build("count(//ns:item) < $limt or contans('a', 'b')")
+    ///     .expect("Could not compile XPath");
+    /// let diagnostics: Vec<_> = xpath
+    ///     .validate(&context)
+    ///     .iter()
+    ///     .map(ToString::to_string)
+    ///     .collect();
+    ///
+    /// assert_eq!(
+    ///     diagnostics,
+    ///     [
+    ///         "unknown namespace prefix ns",
+    ///         "unknown variable $limt",
+    ///         "unknown function contans()",
+    ///     ]
+    /// );
+    /// ```
+    pub fn validate(&self, context: &Context<'_>) -> Vec<Diagnostic> {
+        check::validate(self.as_written(), context)
+            .into_iter()
+            .map(Diagnostic)
+            .collect()
+    }
+
+    /// The variables this XPath refers to, in the order each first
+    /// appears.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sxd_xpath::Factory;
+    ///
+    /// let xpath = Factory::new()
+    ///     .build("//item[@price < $max] | $extra:items")
+    ///     .expect("Could not compile XPath");
+    /// let variables: Vec<_> = xpath.variables().iter().map(ToString::to_string).collect();
+    ///
+    /// assert_eq!(variables, ["max", "extra:items"]);
+    /// ```
+    pub fn variables(&self) -> Vec<OwnedPrefixedName> {
+        dependencies::dependencies(&*self.0).variables
+    }
+
+    /// The functions this XPath calls, in the order each first
+    /// appears.
+    pub fn functions(&self) -> Vec<OwnedPrefixedName> {
+        dependencies::dependencies(&*self.0).functions
+    }
+
+    /// The namespace prefixes used by the names in this XPath, in the
+    /// order each first appears.
+    pub fn namespace_prefixes(&self) -> Vec<String> {
+        dependencies::dependencies(&*self.0).namespace_prefixes
+    }
+
+    /// Which parts of the evaluation context this XPath uses, other
+    /// than variables, functions and namespaces. An XPath that uses
+    /// none of them gives the same value for every context node.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sxd_xpath::{ContextDependence, Factory};
+    ///
+    /// let factory = Factory::new();
+    /// let dependence = |xpath| {
+    ///     let xpath = factory.build(xpath).expect("Could not compile XPath");
+    ///     xpath.context_dependence()
+    /// };
+    ///
+    /// assert_eq!(dependence("count($items[position() > 2])"), ContextDependence::default());
+    /// assert!(dependence("count(item)").node);
+    /// assert!(dependence("position() = last()").size);
+    /// ```
+    pub fn context_dependence(&self) -> ContextDependence {
+        dependencies::dependencies(&*self.0).context
+    }
+
+    /// Rewrites this XPath with the given [`Fold`][], producing a new
+    /// XPath.
+    ///
+    /// [`Fold`]: fold/trait.Fold.html
+    pub fn fold<F>(self, folder: &mut F) -> XPath
+    where
+        F: Fold + ?Sized,
+    {
+        XPath::from(folder.fold_expr(ast::Expr::from(&self)))
+    }
+
+    /// The XPath as it was written, before it was simplified.
+    fn as_written(&self) -> &dyn expression::Expression {
+        match self.1 {
+            Some(ref written) => &**written,
+            None => &*self.0,
+        }
+    }
+}
+
+/// The parts of the evaluation context that an XPath uses.
+///
+/// Functions outside of the core library are assumed to use all of
+/// the context, as they are given it when they are called.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ContextDependence {
+    /// Uses the context node, or the document that contains it
+    pub node: bool,
+    /// Uses the context position, as with `position()`
+    pub position: bool,
+    /// Uses the context size, as with `last()`
+    pub size: bool,
+}
+
+impl fmt::Debug for XPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("XPath").field(&self.0).finish()
+    }
+}
+
+impl From<ast::Expr> for XPath {
+    fn from(expr: ast::Expr) -> XPath {
+        XPath(expr.compile(), None)
+    }
+}
+
+impl<'a> From<&'a XPath> for ast::Expr {
+    fn from(xpath: &'a XPath) -> ast::Expr {
+        ast::decompile(&*xpath.0)
+    }
+}
+
+/// Formats the XPath in a canonical form that compiles back into the
+/// same expression. Abbreviations are expanded and parentheses are
+/// only kept where they are required.
+///
+/// # Examples
+///
+/// ```
+/// use sxd_xpath::Factory;
+///
+/// let xpath = Factory::new().build("../a/@b").expect("Could not compile XPath");
+/// assert_eq!(
+///     xpath.to_string(),
+///     "parent::node()/child::a/attribute::b"
+/// );
+/// ```
+impl fmt::Display for XPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&printer::print(&*self.0))
+    }
 }
 
 impl Visitable for XPath {
@@ -386,24 +736,103 @@ impl Visitable for XPath {
 /// to a structure that can be evaluated.
 pub struct Factory {
     parser: Parser,
+    optimize: bool,
 }
 
 impl Factory {
     pub fn new() -> Factory {
         Factory {
             parser: Parser::new(),
+            optimize: false,
         }
     }
 
     /// Compiles the given string into an XPath structure.
     pub fn build(&self, xpath: &str) -> Result<XPath, ParserError> {
-        let tokenizer = Tokenizer::new(xpath);
+        let expr = self.parse(xpath)?;
+        if !self.optimize {
+            return Ok(XPath::from(expr));
+        }
+
+        let written = expr.clone().compile();
+        Ok(XPath(optimize::optimize(expr).compile(), Some(written)))
+    }
+
+    /// Simplifies the parts of each XPath that do not depend on the
+    /// context when it is built, such as `concat('a', 'b')` or
+    /// `true() and $x`. XPaths are not simplified by default.
+    ///
+    /// Unprefixed calls to functions of the [core library][corelib]
+    /// are assumed to call those functions, so this must not be used
+    /// with contexts that replace them.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use sxd_xpath::Factory;
+    ///
+    /// let mut factory = Factory::new();
+    /// factory.set_optimize(true);
+    ///
+    /// let xpath = factory.build("concat('a', 'b')").expect("Could not compile XPath");
+    /// assert_eq!(xpath.to_string(), "'ab'");
+    /// ```
+    ///
+    /// [corelib]: https://www.w3.org/TR/xpath/#corelib
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Bounds the size and complexity of the XPaths this factory
+    /// accepts.
+    pub fn set_limits(&mut self, limits: ParserLimits) {
+        self.parser.set_limits(limits);
+    }
+
+    /// Chooses the version of XPath this factory accepts. XPath 1.0 is
+    /// accepted by default.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use sxd_xpath::{Factory, LanguageLevel};
+    ///
+    /// let mut factory = Factory::new();
+    /// let xpath = "if (@title) then @title else @name";
+    /// assert!(factory.build(xpath).is_err());
+    ///
+    /// factory.set_language_level(LanguageLevel::XPath2);
+    /// assert!(factory.build(xpath).is_ok());
+    /// ```
+    pub fn set_language_level(&mut self, language_level: LanguageLevel) {
+        self.parser.set_language_level(language_level);
+    }
+
+    /// Parses the given string into an [`Expr`][] that can be
+    /// inspected or modified before it is compiled.
+    ///
+    /// [`Expr`]: ast/enum.Expr.html
+    pub fn parse(&self, xpath: &str) -> Result<ast::Expr, ParserError> {
+        self.parser.check_length(xpath)?;
+
+        let tokenizer = Tokenizer::with_language_level(xpath, self.parser.language_level());
         let deabbreviator = TokenDeabbreviator::new(tokenizer);
 
-        self.parser
-            .parse(deabbreviator)
-            .map(XPath)
-            .map_err(Into::into)
+        self.parser.parse(deabbreviator).map_err(Into::into)
+    }
+
+    /// Compiles the given string into an XSLT [`Pattern`][]. Fails if
+    /// the string is a valid XPath but not a valid pattern.
+    ///
+    /// [`Pattern`]: pattern/struct.Pattern.html
+    pub fn build_pattern(&self, pattern: &str) -> Result<Pattern, ParserError> {
+        let expr = self.parse(pattern)?;
+
+        Pattern::new(expr).ok_or_else(|| {
+            ParserError(parser::Error::InvalidPattern {
+                span: Span::new(0, pattern.len()),
+            })
+        })
     }
 }
 
@@ -413,14 +842,201 @@ impl Default for Factory {
     }
 }
 
+/// The versions of XPath that a [`Factory`](struct.Factory.html) can
+/// accept. Each level accepts everything the levels before it do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LanguageLevel {
+    /// XPath 1.0
+    XPath1,
+    /// XPath 1.0 along with these XPath 2.0 expressions:
+    ///
+    /// - `if (condition) then a else b`
+    /// - `some $x in a satisfies b` and `every $x in a satisfies b`
+    /// - `for $x in a return b`
+    /// - `1 to 3`, `(a, b)` and `()`, which produce a
+    ///   [`Value::Sequence`](enum.Value.html#variant.Sequence)
+    ///
+    ///   Predicates select from the items of a sequence, and `count()`
+    ///   and `sum()` accept one. Within a predicate, functions such as
+    ///   `string()` and `number()` use the item when called without an
+    ///   argument, but `.` still stands for `self::node()`, so it
+    ///   only selects nodes.
+    ///
+    /// - `a intersect b` and `a except b`
+    XPath2,
+    /// XPath 2.0 along with these XPath 3.0 expressions:
+    ///
+    /// - `let $x := a return b`
+    XPath3,
+}
+
+impl Default for LanguageLevel {
+    fn default() -> Self {
+        LanguageLevel::XPath1
+    }
+}
+
+/// Bounds the XPaths that a [`Factory`](struct.Factory.html) accepts,
+/// for use when parsing untrusted expressions. Each limit is unbounded
+/// when `None`, which is the default.
+///
+/// The parser recurses for each nested subexpression, and compiled
+/// XPaths are as deep as the chains of operators they contain, so
+/// limiting both the depth and the chain length keeps hostile XPaths
+/// from overflowing the stack.
+///
+/// ### Examples
+///
+/// ```
+/// use sxd_xpath::{Factory, ParserLimit, ParserLimits};
+///
+/// let mut factory = Factory::new();
+/// factory.set_limits(ParserLimits {
+///     max_steps: Some(2),
+///     ..Default::default()
+/// });
+///
+/// let error = factory.build("/a/b/c").unwrap_err();
+///
+/// assert_eq!(Some(ParserLimit::Steps), error.limit_exceeded());
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParserLimits {
+    /// The length of the XPath, in bytes
+    pub max_length: Option<usize>,
+    /// How deeply subexpressions may be nested, such as within
+    /// parentheses, negations, predicates or function arguments
+    pub max_depth: Option<usize>,
+    /// The number of location steps and predicates
+    pub max_steps: Option<usize>,
+    /// How many expressions may be chained together, such as the
+    /// operands of `a + b + c`, the predicates of `$a[1][2]` or the
+    /// variables of `for $a in b, $c in d return e`
+    pub max_chain_length: Option<usize>,
+}
+
+/// One of the bounds of a [`ParserLimits`](struct.ParserLimits.html).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ParserLimit {
+    Length,
+    Depth,
+    Steps,
+    ChainLength,
+}
+
+impl fmt::Display for ParserLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            ParserLimit::Length => "length",
+            ParserLimit::Depth => "nesting depth",
+            ParserLimit::Steps => "number of steps",
+            ParserLimit::ChainLength => "chain length",
+        };
+        f.write_str(name)
+    }
+}
+
 /// Errors that may occur when parsing an XPath
 #[derive(Debug, Snafu, Clone, PartialEq)]
 pub struct ParserError(parser::Error);
 
+impl ParserError {
+    /// The limit that the XPath exceeded, if any.
+    pub fn limit_exceeded(&self) -> Option<ParserLimit> {
+        match self.0 {
+            parser::Error::LimitExceeded { limit, .. } => Some(limit),
+            _ => None,
+        }
+    }
+
+    /// The region of the XPath where the error was detected.
+    pub fn span(&self) -> Span {
+        self.0.span()
+    }
+
+    /// Describes the error along with the line of the XPath that
+    /// contains it, underlining the region where the error was
+    /// detected. The XPath must be the same string that was parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sxd_xpath::Factory;
+    ///
+    /// let xpath = "/root/child[@id = ]";
+    /// let error = Factory::new().build(xpath).unwrap_err();
+    ///
+    /// println!("{}", error.render(xpath));
+    /// // right hand side of expression is missing
+    /// // 1 | /root/child[@id = ]
+    /// //   |                   ^
+    /// ```
+    pub fn render(&self, xpath: &str) -> String {
+        // Offsets that would split a character are moved back to its start
+        let char_boundary = |mut offset: usize| {
+            offset = offset.min(xpath.len());
+            while !xpath.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            offset
+        };
+
+        let Span { start, end } = self.span();
+        let start = char_boundary(start);
+
+        let line_start = xpath[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = xpath[start..].find('\n').map_or(xpath.len(), |i| start + i);
+        let end = char_boundary(end.max(start).min(line_end));
+
+        let line = xpath[line_start..line_end].trim_end_matches('\r');
+        let line_number = (xpath[..line_start].matches('\n').count() + 1).to_string();
+
+        // Keep tabs so the underline stays aligned with the text above it
+        let padding: String = xpath[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = xpath[start..end].chars().count().max(1);
+
+        format!(
+            "{}\n{} | {}\n{} | {}{}",
+            self,
+            line_number,
+            line,
+            " ".repeat(line_number.len()),
+            padding,
+            "^".repeat(width)
+        )
+    }
+}
+
+/// A function call that does not match the signature of the function
+#[derive(Debug, Snafu, Clone, PartialEq)]
+pub struct CheckError(check::Error);
+
+/// A problem found when validating an XPath against a context
+#[derive(Debug, Snafu, Clone, PartialEq)]
+pub struct Diagnostic(check::Error);
+
 /// Errors that may occur when executing an XPath
 #[derive(Debug, Snafu, Clone, PartialEq)]
 pub struct ExecutionError(expression::Error);
 
+impl ExecutionError {
+    /// The limit that stopped the evaluation, if any.
+    pub fn limit_exceeded(&self) -> Option<context::Limit> {
+        match self.0 {
+            expression::Error::LimitExceeded { limit } => Some(limit),
+            _ => None,
+        }
+    }
+
+    /// Whether the evaluation was stopped by its cancellation token.
+    pub fn is_cancelled(&self) -> bool {
+        self.0 == expression::Error::Cancelled
+    }
+}
+
 /// The failure modes of executing an XPath.
 #[derive(Debug, Snafu, Clone, PartialEq)]
 pub enum Error {
@@ -591,6 +1207,48 @@ mod test {
         assert_eq!("comment 1", v.string());
     }
 
+    #[test]
+    fn sequence_is_converted_like_its_first_item() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let comment = doc.create_comment("7");
+        doc.root().append_child(comment);
+
+        let v = Value::Sequence(vec![Item::String("0".to_owned()), Item::Number(2.0)]);
+        assert_eq!("0", v.string());
+        assert_eq!(0.0, v.number());
+        assert!(v.boolean());
+
+        let v = Value::Sequence(vec![Item::Node(comment.into()), Item::Boolean(false)]);
+        assert_eq!("7", v.string());
+        assert_eq!(7.0, v.number());
+        assert!(v.boolean());
+    }
+
+    #[test]
+    fn empty_sequence_is_false_nan_and_empty() {
+        let v = Value::Sequence(Vec::new());
+        assert!(!v.boolean());
+        assert!(v.number().is_nan());
+        assert_eq!("", v.string());
+    }
+
+    #[test]
+    fn sequence_of_nodes_is_a_nodeset() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let c1 = doc.create_comment("comment 1");
+        doc.root().append_child(c1);
+
+        let nodes = vec![Item::Node(c1.into()), Item::Node(c1.into())];
+        assert_eq!(Ok(nodeset![c1]), Value::Sequence(nodes).into_nodeset());
+
+        let mixed = Value::Sequence(vec![Item::Node(c1.into()), Item::Number(1.0)]);
+        assert_eq!(Err(mixed.clone()), mixed.into_nodeset());
+    }
+
     fn with_document<F>(xml: &str, f: F)
     where
         F: FnOnce(dom::Document<'_>),
@@ -613,14 +1271,138 @@ mod test {
         with_document("<root><child>content</child></root>", |doc| {
             let result = evaluate_xpath(&doc, "/root/child/");
 
-            let expected_error = crate::parser::TrailingSlash
-                .fail()
-                .map_err(ParserError::from)
-                .context(Parsing);
+            let expected_error = crate::parser::TrailingSlash {
+                span: Span::new(12, 12),
+            }
+            .fail()
+            .map_err(ParserError::from)
+            .context(Parsing);
             assert_eq!(expected_error, result);
         });
     }
 
+    #[test]
+    fn parser_error_is_rendered_with_the_offending_region_underlined() {
+        let xpath = "/root/child)";
+        let error = Factory::new().build(xpath).unwrap_err();
+
+        assert_eq!(Span::new(11, 12), error.span());
+        assert_eq!(
+            concat!(
+                "extra unparsed tokens\n",
+                "1 | /root/child)\n",
+                "  |            ^",
+            ),
+            error.render(xpath)
+        );
+    }
+
+    #[test]
+    fn parser_error_underlines_the_entire_token() {
+        let xpath = "count(//a) ancestor::b";
+        let error = Factory::new().build(xpath).unwrap_err();
+
+        assert_eq!(
+            concat!(
+                "extra unparsed tokens\n",
+                "1 | count(//a) ancestor::b\n",
+                "  |            ^^^^^^^^^^",
+            ),
+            error.render(xpath)
+        );
+    }
+
+    #[test]
+    fn parser_error_is_rendered_on_the_line_where_it_occurs() {
+        let xpath = "/root\n  | child/";
+        let error = Factory::new().build(xpath).unwrap_err();
+
+        assert_eq!(
+            concat!("trailing slash\n", "2 |   | child/\n", "  |           ^",),
+            error.render(xpath)
+        );
+    }
+
+    #[test]
+    fn parser_error_is_rendered_when_the_span_splits_a_character() {
+        let mut factory = Factory::new();
+        factory.set_limits(ParserLimits {
+            max_length: Some(5),
+            ..Default::default()
+        });
+
+        let xpath = "/rööt/child";
+        let error = factory.build(xpath).unwrap_err();
+
+        assert_eq!(Span::new(5, 13), error.span());
+        assert_eq!(
+            concat!(
+                "expression exceeded the limit on length\n",
+                "1 | /rööt/child\n",
+                "  |    ^^^^^^^^",
+            ),
+            error.render(xpath)
+        );
+    }
+
+    #[test]
+    fn parser_limits_reject_long_xpaths() {
+        let mut factory = Factory::new();
+        factory.set_limits(ParserLimits {
+            max_length: Some(5),
+            ..Default::default()
+        });
+
+        assert!(factory.build("/root").is_ok());
+
+        let error = factory.build("/root/child").unwrap_err();
+        assert_eq!(Some(ParserLimit::Length), error.limit_exceeded());
+        assert_eq!(Span::new(5, 11), error.span());
+    }
+
+    #[test]
+    fn parser_limits_reject_deeply_nested_xpaths() {
+        let mut factory = Factory::new();
+        factory.set_limits(ParserLimits {
+            max_depth: Some(32),
+            ..Default::default()
+        });
+
+        let xpath = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        let error = factory.build(&xpath).unwrap_err();
+
+        assert_eq!(Some(ParserLimit::Depth), error.limit_exceeded());
+    }
+
+    #[test]
+    fn parser_limits_reject_long_chains() {
+        let mut factory = Factory::new();
+        factory.set_limits(ParserLimits {
+            max_depth: Some(32),
+            max_chain_length: Some(1000),
+            ..Default::default()
+        });
+
+        let xpath = vec!["1"; 3000].join("+");
+        let error = factory.build(&xpath).unwrap_err();
+
+        assert_eq!(Some(ParserLimit::ChainLength), error.limit_exceeded());
+
+        let xpath = format!("$a{}", "[1]".repeat(3000));
+        let error = factory.build(&xpath).unwrap_err();
+
+        assert_eq!(Some(ParserLimit::ChainLength), error.limit_exceeded());
+    }
+
+    #[test]
+    fn long_chains_are_not_limited_by_default() {
+        let sum = (0..40).map(|n| n.to_string()).collect::<Vec<_>>();
+        let any = (0..40).map(|n| format!("@a{}='x'", n)).collect::<Vec<_>>();
+
+        assert!(Factory::new().build(&sum.join(" + ")).is_ok());
+        assert!(Factory::new().build(&any.join(" or ")).is_ok());
+    }
+
     #[test]
     fn xpath_evaluation_execution_error() {
         with_document("<root><child>content</child></root>", |doc| {
@@ -633,4 +1415,28 @@ mod test {
             assert_eq!(expected_error, result);
         });
     }
+
+    #[test]
+    fn nested_evaluations_count_towards_the_outer_limits() {
+        with_document("<root/>", |doc| {
+            let mut context = Context::new();
+            context.set_limits(context::EvaluationLimits {
+                max_nodes_visited: Some(2),
+                ..Default::default()
+            });
+            let xpath = Factory::new().build("/root").unwrap();
+
+            {
+                let _running = context.start_evaluation();
+                let outer = context::Evaluation::new(&context, doc.root().into());
+                outer.visit_node().unwrap();
+                outer.visit_node().unwrap();
+
+                let error = xpath.evaluate(&context, doc.root()).unwrap_err();
+                assert_eq!(Some(context::Limit::NodesVisited), error.limit_exceeded());
+            }
+
+            assert!(xpath.evaluate(&context, doc.root()).is_ok());
+        });
+    }
 }
@@ -27,7 +27,9 @@ where
 
 pub type SubNodeTest = Box<dyn NodeTest + 'static>;
 
-#[derive(Debug, Clone, PartialEq)]
+/// A possibly-prefixed name to match against. A local part of `*`
+/// matches any name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NameTest {
     pub prefix: Option<String>,
     pub local_part: String,
@@ -258,7 +260,7 @@ mod test {
     }
 
     impl<'d> Setup<'d> {
-        fn new(package: &'d Package) -> Setup<'_> {
+        fn new(package: &'d Package) -> Setup<'d> {
             Setup {
                 doc: package.as_document(),
                 context: Context::without_core_functions(),
@@ -270,10 +272,10 @@ mod test {
         }
 
         fn context_for_attribute<'n, N>(
-            &'d self,
+            &self,
             name: N,
             val: &str,
-        ) -> (dom::Attribute<'d>, context::Evaluation<'d, 'd>)
+        ) -> (dom::Attribute<'d>, context::Evaluation<'_, 'd>)
         where
             N: Into<QName<'n>>,
         {
@@ -284,20 +286,20 @@ mod test {
         }
 
         fn context_for_ns_attribute(
-            &'d mut self,
+            &mut self,
             prefix: &str,
             nsuri: &str,
             local: &str,
             value: &str,
-        ) -> (dom::Attribute<'d>, context::Evaluation<'d, 'd>) {
+        ) -> (dom::Attribute<'d>, context::Evaluation<'_, 'd>) {
             self.register_prefix(prefix, nsuri);
             self.context_for_attribute((nsuri, local), value)
         }
 
         fn context_for_element<'n, N>(
-            &'d self,
+            &self,
             name: N,
-        ) -> (dom::Element<'d>, context::Evaluation<'d, 'd>)
+        ) -> (dom::Element<'d>, context::Evaluation<'_, 'd>)
         where
             N: Into<QName<'n>>,
         {
@@ -307,18 +309,18 @@ mod test {
         }
 
         fn context_for_ns_element(
-            &'d mut self,
+            &mut self,
             prefix: &str,
             nsuri: &str,
             local: &str,
-        ) -> (dom::Element<'d>, context::Evaluation<'d, 'd>) {
+        ) -> (dom::Element<'d>, context::Evaluation<'_, 'd>) {
             self.register_prefix(prefix, nsuri);
             self.context_for_element((nsuri, local))
         }
     }
 
     fn run_attribute<'d>(
-        context: &context::Evaluation<'d, 'd>,
+        context: &context::Evaluation<'_, 'd>,
         prefix: Option<&str>,
         local: &str,
     ) -> OrderedNodes<'d> {
@@ -417,7 +419,7 @@ mod test {
     }
 
     fn run_element<'d>(
-        context: &context::Evaluation<'d, 'd>,
+        context: &context::Evaluation<'_, 'd>,
         prefix: Option<&str>,
         local: &str,
     ) -> OrderedNodes<'d> {
@@ -1,10 +1,11 @@
 //! Support for collections of nodes.
 
 use std::borrow::ToOwned;
-use std::collections::hash_set;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::iter::{FromIterator, IntoIterator};
-use std::usize;
+use std::{slice, usize, vec};
 
 use sxd_document::dom;
 use sxd_document::QName;
@@ -48,6 +49,22 @@ pub struct Namespace<'d> {
 }
 
 impl<'d> Namespace<'d> {
+    /// The namespaces in scope for an element, ordered by prefix. The
+    /// DOM does not order them, but document order must be stable.
+    pub(crate) fn in_scope_of(element: dom::Element<'d>) -> Vec<Namespace<'d>> {
+        let mut namespaces: Vec<_> = element
+            .namespaces_in_scope()
+            .into_iter()
+            .map(|ns| Namespace {
+                parent: element,
+                prefix: ns.prefix(),
+                uri: ns.uri(),
+            })
+            .collect();
+        namespaces.sort_by_key(|ns| ns.prefix);
+        namespaces
+    }
+
     pub fn document(&self) -> dom::Document<'d> {
         self.parent.document()
     }
@@ -110,7 +127,7 @@ impl<'d> Node<'d> {
             } else {
                 name.local_part().to_owned()
             }
-        };
+        }
 
         match *self {
             Root(_) => None,
@@ -234,7 +251,7 @@ impl<'d> Node<'d> {
                     _ => {}
                 }
             }
-        };
+        }
 
         fn text_descendants_string_value(node: Node<'_>) -> String {
             let mut result = String::new();
@@ -312,10 +329,18 @@ impl<'d> Into<Node<'d>> for dom::ParentOfChild<'d> {
     }
 }
 
-/// An unordered collection of unique nodes
-#[derive(Debug, Default, Clone, PartialEq)]
+/// A collection of unique nodes.
+///
+/// Nodesets produced while evaluating an XPath remember that they are
+/// already in [document order], so retrieving the nodes in order does
+/// not need to examine the document.
+///
+/// [document order]: https://www.w3.org/TR/xpath/#dt-document-order
+#[derive(Default, Clone)]
 pub struct Nodeset<'d> {
-    nodes: HashSet<Node<'d>>,
+    nodes: Vec<Node<'d>>,
+    members: HashSet<Node<'d>>,
+    in_document_order: bool,
 }
 
 impl<'d> Nodeset<'d> {
@@ -323,12 +348,22 @@ impl<'d> Nodeset<'d> {
         Default::default()
     }
 
+    /// Creates a nodeset from nodes that are already unique and in
+    /// document order.
+    pub(crate) fn from_document_order(nodes: Vec<Node<'d>>) -> Nodeset<'d> {
+        Nodeset {
+            members: nodes.iter().cloned().collect(),
+            nodes,
+            in_document_order: true,
+        }
+    }
+
     /// Checks if the node is present in the set
     pub fn contains<N>(&self, node: N) -> bool
     where
         N: Into<Node<'d>>,
     {
-        self.nodes.contains(&node.into())
+        self.members.contains(&node.into())
     }
 
     /// Add the given node to the set
@@ -336,31 +371,59 @@ impl<'d> Nodeset<'d> {
     where
         N: Into<Node<'d>>,
     {
-        self.nodes.insert(node.into());
+        let node = node.into();
+        if self.members.insert(node) {
+            self.nodes.push(node);
+            self.in_document_order = self.nodes.len() == 1;
+        }
     }
 
+    /// Iterates over the nodes in the order they were added, which is
+    /// document order for nodesets produced by evaluating an XPath.
     pub fn iter<'a>(&'a self) -> Iter<'a, 'd> {
         IntoIterator::into_iter(self)
     }
 
+    /// The nodes that are also in the other nodeset.
+    pub fn intersection(&self, other: &Nodeset<'d>) -> Nodeset<'d> {
+        self.filtered(|node| other.members.contains(node))
+    }
+
+    /// The nodes that are not in the other nodeset.
+    pub fn difference(&self, other: &Nodeset<'d>) -> Nodeset<'d> {
+        self.filtered(|node| !other.members.contains(node))
+    }
+
+    /// Keeps the nodes that satisfy the test, in the same order.
+    fn filtered<F>(&self, test: F) -> Nodeset<'d>
+    where
+        F: Fn(&Node<'d>) -> bool,
+    {
+        let nodes: Vec<_> = self.nodes.iter().cloned().filter(|n| test(n)).collect();
+        Nodeset {
+            members: nodes.iter().cloned().collect(),
+            nodes,
+            in_document_order: self.in_document_order,
+        }
+    }
+
     pub fn size(&self) -> usize {
         self.nodes.len()
     }
 
+    fn is_in_document_order(&self) -> bool {
+        self.in_document_order || self.nodes.len() <= 1
+    }
+
     /// Returns the node that occurs first in [document order]
     ///
     /// [document order]: https://www.w3.org/TR/xpath/#dt-document-order
     pub fn document_order_first(&self) -> Option<Node<'d>> {
-        let node = match self.nodes.iter().next() {
-            Some(n) => n,
-            None => return None,
-        };
-
-        if self.nodes.len() == 1 {
-            return Some(*node);
+        if self.is_in_document_order() {
+            return self.nodes.first().cloned();
         }
 
-        let order = DocOrder::new(node.document());
+        let order = DocOrder::new(self.nodes[0].document());
 
         self.nodes
             .iter()
@@ -369,19 +432,42 @@ impl<'d> Nodeset<'d> {
     }
 
     pub fn document_order(&self) -> Vec<Node<'d>> {
-        let mut nodes: Vec<_> = self.iter().collect();
-        if nodes.len() == 1 {
-            return nodes;
+        let mut nodes = self.nodes.clone();
+        if !self.is_in_document_order() {
+            DocumentOrder::default().sort(&mut nodes);
+        }
+        nodes
+    }
+
+    /// Consumes the nodeset, returning the nodes in document order.
+    pub fn into_document_order(mut self) -> Vec<Node<'d>> {
+        if !self.is_in_document_order() {
+            DocumentOrder::default().sort(&mut self.nodes);
         }
+        self.nodes
+    }
 
-        let doc = match nodes.first().map(Node::document) {
-            Some(doc) => doc,
-            None => return nodes,
-        };
+    /// Puts the nodes in document order, reusing the order of the
+    /// document's nodes if it has already been computed.
+    pub(crate) fn sort_with(&mut self, order: &DocumentOrder<'d>) {
+        if !self.is_in_document_order() {
+            order.sort(&mut self.nodes);
+            self.in_document_order = true;
+        }
+    }
+}
 
-        let order = DocOrder::new(doc);
-        nodes.sort_by_key(|&n| order.order_of(n));
-        nodes
+impl<'d> PartialEq for Nodeset<'d> {
+    fn eq(&self, other: &Nodeset<'d>) -> bool {
+        self.members == other.members
+    }
+}
+
+impl<'d> fmt::Debug for Nodeset<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Nodeset")
+            .field("nodes", &self.nodes)
+            .finish()
     }
 }
 
@@ -390,16 +476,52 @@ impl<'d> Extend<Node<'d>> for Nodeset<'d> {
     where
         I: IntoIterator<Item = Node<'d>>,
     {
-        self.nodes.extend(iter)
+        for node in iter {
+            self.add(node);
+        }
     }
 }
 
-// Rebuilding this multiple times cannot possibly be performant,
-// but I want to see how widely used this is first before
-// picking an appropriate caching point.
-struct DocOrder<'d>(HashMap<Node<'d>, usize>);
+/// Remembers the document order of every node in a document, so that
+/// the document only needs to be walked once per evaluation.
+#[derive(Default)]
+pub(crate) struct DocumentOrder<'d>(RefCell<Option<DocOrder<'d>>>);
+
+impl<'d> DocumentOrder<'d> {
+    /// Forgets the remembered order, as the document may have been
+    /// modified since it was computed.
+    pub fn clear(&self) {
+        *self.0.borrow_mut() = None;
+    }
+
+    fn sort(&self, nodes: &mut [Node<'d>]) {
+        let doc = match nodes.first() {
+            Some(node) => node.document(),
+            None => return,
+        };
+
+        let mut cached = self.0.borrow_mut();
+        let stale = match *cached {
+            Some(ref order) => order.root != doc.root(),
+            None => true,
+        };
+        if stale {
+            *cached = Some(DocOrder::new(doc));
+        }
+
+        if let Some(ref order) = *cached {
+            nodes.sort_by_key(|&n| order.order_of(n));
+        }
+    }
+}
+
+struct DocOrder<'d> {
+    root: dom::Root<'d>,
+    order: HashMap<Node<'d>, usize>,
+}
 
 impl<'d> DocOrder<'d> {
+    #[allow(clippy::mutable_key_type)]
     fn new(doc: dom::Document<'d>) -> Self {
         let mut idx = 0;
         let mut stack: Vec<Node<'_>> = vec![doc.root().into()];
@@ -411,18 +533,33 @@ impl<'d> DocOrder<'d> {
 
             stack.extend(n.children().into_iter().rev());
 
+            // An element's attributes come before its children. Its
+            // namespaces are placed when they are looked up.
             if let Node::Element(e) = n {
-                // TODO: namespaces
-                stack.extend(e.attributes().into_iter().map(Node::Attribute));
+                stack.extend(e.attributes().into_iter().rev().map(Node::Attribute));
             }
         }
 
-        DocOrder(order)
+        DocOrder {
+            root: doc.root(),
+            order,
+        }
+    }
+
+    /// Namespaces share the position of their element, directly after
+    /// it, and are ordered among themselves by prefix. This avoids
+    /// finding the namespaces in scope for every element of the
+    /// document when few nodesets ever contain them.
+    fn order_of(&self, node: Node<'d>) -> (usize, Option<&'d str>) {
+        match node {
+            Node::Namespace(ns) => (self.position(ns.parent().into()), Some(ns.prefix())),
+            node => (self.position(node), None),
+        }
     }
 
-    fn order_of(&self, node: Node<'d>) -> usize {
+    fn position(&self, node: Node<'d>) -> usize {
         // See the library-level docs for rationale on this MAX
-        self.0.get(&node).cloned().unwrap_or(usize::MAX)
+        self.order.get(&node).cloned().unwrap_or(usize::MAX)
     }
 }
 
@@ -459,14 +596,14 @@ impl<'d> FromIterator<Node<'d>> for Nodeset<'d> {
     where
         I: IntoIterator<Item = Node<'d>>,
     {
-        Nodeset {
-            nodes: iterator.into_iter().collect(),
-        }
+        let mut nodeset = Nodeset::new();
+        nodeset.extend(iterator);
+        nodeset
     }
 }
 
 pub struct Iter<'a, 'd> {
-    iter: hash_set::Iter<'a, Node<'d>>,
+    iter: slice::Iter<'a, Node<'d>>,
 }
 
 impl<'a, 'd: 'a> Iterator for Iter<'a, 'd> {
@@ -477,7 +614,7 @@ impl<'a, 'd: 'a> Iterator for Iter<'a, 'd> {
 }
 
 pub struct IntoIter<'d> {
-    iter: hash_set::IntoIter<Node<'d>>,
+    iter: vec::IntoIter<Node<'d>>,
 }
 
 impl<'d> Iterator for IntoIter<'d> {
@@ -531,7 +668,7 @@ mod test {
     use sxd_document::Package;
 
     use super::Node::*;
-    use super::{Node, Nodeset};
+    use super::{DocumentOrder, Node, Nodeset};
 
     fn into_node<'d, T: Into<Node<'d>>>(n: T) -> Node<'d> {
         n.into()
@@ -589,6 +726,23 @@ mod test {
         assert_eq!(all_nodes, nodes1);
     }
 
+    #[test]
+    fn nodesets_can_be_intersected_and_subtracted() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let e1 = doc.create_element("element1");
+        let e2 = doc.create_element("element2");
+        let e3 = doc.create_element("element3");
+
+        let nodes1 = nodeset![e1, e2];
+        let nodes2 = nodeset![e3, e2];
+
+        assert_eq!(nodeset![e2], nodes1.intersection(&nodes2));
+        assert_eq!(nodeset![e1], nodes1.difference(&nodes2));
+        assert_eq!(nodeset![e3], nodes2.difference(&nodes1));
+    }
+
     #[test]
     fn nodeset_knows_first_node_in_document_order() {
         let package = Package::new();
@@ -621,6 +775,89 @@ mod test {
         assert_eq!(Some(attr.into()), nodes.document_order_first());
     }
 
+    #[test]
+    fn namespaces_come_between_their_element_and_its_attributes_in_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        parent.register_prefix("p", "uri:p");
+        let attr = parent.set_attribute_value("a", "v");
+        doc.root().append_child(parent);
+
+        let namespaces = super::Namespace::in_scope_of(parent);
+        let mut nodes = nodeset![attr, parent];
+        nodes.extend(namespaces.iter().rev().cloned().map(Namespace));
+
+        let mut expected = vec![into_node(parent)];
+        expected.extend(namespaces.into_iter().map(Namespace));
+        expected.push(attr.into());
+
+        assert_eq!(expected, nodes.document_order());
+    }
+
+    #[test]
+    fn nodeset_remembers_when_it_is_in_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let c1 = doc.create_comment("1");
+        let c2 = doc.create_comment("2");
+        doc.root().append_child(c1);
+        doc.root().append_child(c2);
+
+        let mut nodes = nodeset![c2, c1];
+        nodes.sort_with(&DocumentOrder::default());
+
+        let in_order: Vec<_> = nodes.iter().collect();
+        assert_eq!(vec![into_node(c1), into_node(c2)], in_order);
+        assert_eq!(Some(into_node(c1)), nodes.document_order_first());
+    }
+
+    #[test]
+    fn nodeset_iterates_in_the_order_nodes_were_added() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let c1 = doc.create_comment("1");
+        let c2 = doc.create_comment("2");
+        doc.root().append_child(c1);
+        doc.root().append_child(c2);
+
+        let nodes = nodeset![c2, c1];
+
+        assert_eq!(vec![into_node(c1), into_node(c2)], nodes.document_order());
+        let added: Vec<_> = nodes.iter().collect();
+        assert_eq!(vec![into_node(c2), into_node(c1)], added);
+    }
+
+    #[test]
+    fn document_order_is_recomputed_for_a_different_document() {
+        let package1 = Package::new();
+        let doc1 = package1.as_document();
+        let a1 = doc1.create_comment("a");
+        let b1 = doc1.create_comment("b");
+        doc1.root().append_child(a1);
+        doc1.root().append_child(b1);
+
+        let package2 = Package::new();
+        let doc2 = package2.as_document();
+        let a2 = doc2.create_comment("a");
+        let b2 = doc2.create_comment("b");
+        doc2.root().append_child(a2);
+        doc2.root().append_child(b2);
+
+        let order = DocumentOrder::default();
+
+        let mut nodes1 = nodeset![b1, a1];
+        nodes1.sort_with(&order);
+        let mut nodes2 = nodeset![b2, a2];
+        nodes2.sort_with(&order);
+
+        assert_eq!(vec![into_node(a1), into_node(b1)], nodes1.document_order());
+        assert_eq!(vec![into_node(a2), into_node(b2)], nodes2.document_order());
+    }
+
     #[test]
     fn prefixed_name_of_element_with_preferred_prefix() {
         let package = Package::new();
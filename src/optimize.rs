@@ -0,0 +1,391 @@
+//! Simplifies XPaths before they are compiled, so that work that does
+//! not depend on the document is done once instead of each time the
+//! XPath is evaluated.
+//!
+//! Unprefixed function calls are assumed to call the core function of
+//! the same name.
+
+use sxd_document::{Package, QName};
+
+use crate::ast::{Axis, BinaryOperator, Expr, Literal, NodeTest, Step};
+use crate::context::{self, Context};
+use crate::fold::{self, Fold};
+use crate::nodeset::Node;
+use crate::{OwnedPrefixedName, Value};
+
+/// Evaluates constant subexpressions, removes boolean operations and
+/// conditionals with constant operands, rewrites `[position() = n]` as
+/// `[n]`, and `//a` as `/descendant::a`.
+pub(crate) fn optimize(expr: Expr) -> Expr {
+    // Constant expressions never look at the document, but evaluation
+    // still needs a context node.
+    let package = Package::new();
+    let document = package.as_document();
+    let context = Context::new();
+
+    let mut optimizer = Optimizer {
+        context: &context,
+        node: document.root().into(),
+    };
+    optimizer.fold_expr(expr)
+}
+
+fn core_name(name: &OwnedPrefixedName) -> Option<&str> {
+    match name.prefix() {
+        None => Some(name.local_part()),
+        Some(_) => None,
+    }
+}
+
+fn call(name: &str, arguments: Vec<Expr>) -> Expr {
+    Expr::Function {
+        name: name.into(),
+        arguments,
+    }
+}
+
+fn literal(expr: &Expr) -> Option<&Literal> {
+    match *expr {
+        Expr::Literal(ref literal) => Some(literal),
+        _ => None,
+    }
+}
+
+fn truth(expr: &Expr) -> Option<bool> {
+    literal(expr).map(|literal| match *literal {
+        Literal::Boolean(b) => b,
+        Literal::Number(n) => n != 0.0 && !n.is_nan(),
+        Literal::String(ref s) => !s.is_empty(),
+    })
+}
+
+/// Whether the expression always evaluates to a boolean.
+fn is_boolean(expr: &Expr) -> bool {
+    use crate::ast::BinaryOperator::*;
+
+    match *expr {
+        Expr::Literal(Literal::Boolean(_)) => true,
+        Expr::Binary { operator, .. } => match operator {
+            Or | And | Equal | NotEqual | LessThan | LessThanOrEqual | GreaterThan
+            | GreaterThanOrEqual => true,
+            _ => false,
+        },
+        Expr::Function { ref name, .. } => match core_name(name) {
+            Some("boolean") | Some("not") | Some("true") | Some("false") | Some("lang")
+            | Some("starts-with") | Some("contains") => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The argument of a call to `not`.
+fn negated(expr: &Expr) -> Option<&Expr> {
+    match *expr {
+        Expr::Function {
+            ref name,
+            ref arguments,
+        } if core_name(name) == Some("not") && arguments.len() == 1 => Some(&arguments[0]),
+        _ => None,
+    }
+}
+
+fn boolean(expr: Expr) -> Expr {
+    if is_boolean(&expr) {
+        expr
+    } else {
+        call("boolean", vec![expr])
+    }
+}
+
+/// The position selected by a predicate of the form `position() = n`
+/// or `n = position()`.
+fn position_test(predicate: &Expr) -> Option<f64> {
+    let is_position = |expr: &Expr| match *expr {
+        Expr::Function {
+            ref name,
+            ref arguments,
+        } => core_name(name) == Some("position") && arguments.is_empty(),
+        _ => false,
+    };
+    let number = |expr: &Expr| match literal(expr) {
+        // A number predicate matches the position it rounds down to,
+        // so only whole numbers can be used in its place.
+        Some(&Literal::Number(n)) if n >= 1.0 && n.fract() == 0.0 => Some(n),
+        _ => None,
+    };
+
+    match *predicate {
+        Expr::Binary {
+            ref left,
+            operator: BinaryOperator::Equal,
+            ref right,
+        } => {
+            if is_position(left) {
+                number(right)
+            } else if is_position(right) {
+                number(left)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Joins `descendant-or-self::node()/child::a` into `descendant::a`,
+/// which selects the same nodes without first selecting every node
+/// along the way.
+fn join_descendants(steps: Vec<Step>) -> Vec<Step> {
+    let any_descendant = |step: &Step| {
+        step.axis == Axis::DescendantOrSelf
+            && step.node_test == NodeTest::Node
+            && step.predicates.is_empty()
+    };
+
+    let mut joined: Vec<Step> = Vec::with_capacity(steps.len());
+    for step in steps {
+        // Positions in a predicate would count from a different node
+        let joinable = step.axis == Axis::Child && step.predicates.is_empty();
+        if joinable && joined.last().map_or(false, any_descendant) {
+            joined.pop();
+            joined.push(Step {
+                axis: Axis::Descendant,
+                ..step
+            });
+        } else {
+            joined.push(step);
+        }
+    }
+    joined
+}
+
+struct Optimizer<'c, 'd> {
+    context: &'c Context<'d>,
+    node: Node<'d>,
+}
+
+impl<'c, 'd> Optimizer<'c, 'd> {
+    fn is_pure(&self, name: &OwnedPrefixedName) -> bool {
+        core_name(name)
+            .and_then(|name| self.context.function_for_name(QName::new(name)))
+            .map_or(false, |function| function.is_pure())
+    }
+
+    /// Replaces the expression with its value. Expressions that fail
+    /// are kept so that they fail when the XPath is evaluated.
+    fn evaluate(&self, expr: Expr) -> Expr {
+        let value = {
+            let compiled = expr.clone().compile();
+            let context = context::Evaluation::new(self.context, self.node);
+            compiled.evaluate(&context)
+        };
+
+        match value {
+            Ok(Value::Boolean(b)) => Expr::Literal(Literal::Boolean(b)),
+            Ok(Value::Number(n)) => Expr::Literal(Literal::Number(n)),
+            Ok(Value::String(s)) => Expr::Literal(Literal::String(s)),
+            Ok(Value::Nodeset(_)) | Ok(Value::Sequence(_)) | Err(_) => expr,
+        }
+    }
+}
+
+impl<'c, 'd> Fold for Optimizer<'c, 'd> {
+    fn fold_binary(&mut self, left: Expr, operator: BinaryOperator, right: Expr) -> Expr {
+        let left = self.fold_expr(left);
+        let right = self.fold_expr(right);
+
+        // Ranges are kept, as they can be too large to build ahead of
+        // time and are never replaced by a literal anyway
+        match (operator, truth(&left), truth(&right)) {
+            (BinaryOperator::Union, _, _)
+            | (BinaryOperator::Intersect, _, _)
+            | (BinaryOperator::Except, _, _)
+            | (BinaryOperator::Range, _, _) => Expr::binary(left, operator, right),
+            (_, Some(_), Some(_)) => self.evaluate(Expr::binary(left, operator, right)),
+            (BinaryOperator::And, Some(false), _) | (BinaryOperator::Or, Some(true), _) => {
+                // The right side is never evaluated
+                Expr::Literal(Literal::Boolean(operator == BinaryOperator::Or))
+            }
+            (BinaryOperator::And, Some(true), _) | (BinaryOperator::Or, Some(false), _) => {
+                boolean(right)
+            }
+            (BinaryOperator::And, _, Some(true)) | (BinaryOperator::Or, _, Some(false)) => {
+                boolean(left)
+            }
+            _ => Expr::binary(left, operator, right),
+        }
+    }
+
+    fn fold_conditional(&mut self, condition: Expr, then: Expr, otherwise: Expr) -> Expr {
+        let condition = self.fold_expr(condition);
+
+        match truth(&condition) {
+            Some(true) => self.fold_expr(then),
+            Some(false) => self.fold_expr(otherwise),
+            None => Expr::Conditional {
+                condition: Box::new(condition),
+                then: Box::new(self.fold_expr(then)),
+                otherwise: Box::new(self.fold_expr(otherwise)),
+            },
+        }
+    }
+
+    fn fold_function(&mut self, name: OwnedPrefixedName, arguments: Vec<Expr>) -> Expr {
+        let expr = fold::fold_function(self, name, arguments);
+
+        let (name, arguments) = match expr {
+            Expr::Function {
+                ref name,
+                ref arguments,
+            } => (name, arguments),
+            _ => return expr,
+        };
+
+        if self.is_pure(name) && arguments.iter().all(|a| literal(a).is_some()) {
+            return self.evaluate(expr);
+        }
+
+        let argument = match arguments.len() {
+            1 => &arguments[0],
+            _ => return expr,
+        };
+        match core_name(name) {
+            Some("boolean") if is_boolean(argument) => argument.clone(),
+            Some("not") => match negated(argument) {
+                Some(inner) => boolean(inner.clone()),
+                None => expr,
+            },
+            _ => expr,
+        }
+    }
+
+    fn fold_negation(&mut self, expression: Expr) -> Expr {
+        let expr = fold::fold_negation(self, expression);
+
+        let constant = match expr {
+            Expr::Negation(ref expression) => literal(expression).is_some(),
+            _ => false,
+        };
+        if constant {
+            self.evaluate(expr)
+        } else {
+            expr
+        }
+    }
+
+    fn fold_path(&mut self, start: Expr, steps: Vec<Step>) -> Expr {
+        match fold::fold_path(self, start, steps) {
+            Expr::Path { start, steps } => Expr::Path {
+                start,
+                steps: join_descendants(steps),
+            },
+            expr => expr,
+        }
+    }
+
+    fn fold_predicate(&mut self, predicate: Expr) -> Expr {
+        let predicate = fold::fold_predicate(self, predicate);
+
+        match position_test(&predicate) {
+            Some(position) => Expr::Literal(Literal::Number(position)),
+            None => predicate,
+        }
+    }
+
+    fn fold_step(&mut self, step: Step) -> Step {
+        let mut step = fold::fold_step(self, step);
+        // Every node matches these predicates
+        step.predicates
+            .retain(|predicate| *predicate != Expr::Literal(Literal::Boolean(true)));
+        step
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Factory, LanguageLevel};
+
+    fn optimized(xpath: &str) -> String {
+        let mut factory = Factory::new();
+        factory.set_language_level(LanguageLevel::XPath2);
+        factory.set_optimize(true);
+        let xpath = factory.build(xpath).expect("Unable to build XPath");
+        xpath.to_string()
+    }
+
+    #[test]
+    fn constant_expressions_are_evaluated() {
+        assert_eq!(optimized("1 + 2 * -3"), "-5");
+        assert_eq!(optimized("concat('a', substring('bcd', 2))"), "'acd'");
+        assert_eq!(optimized("$x + (1 div 0)"), "$x + 1 div 0");
+        assert_eq!(optimized("f(2 = 2)"), "f(true())");
+    }
+
+    #[test]
+    fn calls_that_are_not_known_to_be_pure_are_kept() {
+        assert_eq!(optimized("string(1)"), "string(1)");
+        assert_eq!(optimized("p:concat('a', 'b')"), "p:concat('a', 'b')");
+        assert_eq!(optimized("substring('a')"), "substring('a')");
+        assert_eq!(optimized("count(/) = 1"), "count(/) = 1");
+    }
+
+    #[test]
+    fn ranges_are_not_built() {
+        assert_eq!(optimized("1 to 1 div 0"), "1 to 1 div 0");
+        assert_eq!(optimized("1 to 20000000"), "1 to 20000000");
+        assert_eq!(optimized("count(1 to 2 + 1)"), "count(1 to 3)");
+    }
+
+    #[test]
+    fn boolean_identities_are_simplified() {
+        assert_eq!(optimized("true() and $x"), "boolean($x)");
+        assert_eq!(optimized("$x or false()"), "boolean($x)");
+        assert_eq!(optimized("false() and $x"), "false()");
+        assert_eq!(optimized("1 or $x"), "true()");
+        assert_eq!(optimized("$x and false()"), "$x and false()");
+        assert_eq!(optimized("not(not($x))"), "boolean($x)");
+        assert_eq!(optimized("not(not(a = 1))"), "child::a = 1");
+        assert_eq!(optimized("boolean($x < 1)"), "$x < 1");
+    }
+
+    #[test]
+    fn conditionals_with_constant_conditions_are_replaced_by_a_branch() {
+        assert_eq!(optimized("if (1 = 1) then $a else $b"), "$a");
+        assert_eq!(optimized("if ('') then $a else $b + 1"), "$b + 1");
+        assert_eq!(
+            optimized("if ($c) then 1 + 1 else $b"),
+            "if ($c) then 2 else $b"
+        );
+    }
+
+    #[test]
+    fn position_tests_become_positional_predicates() {
+        assert_eq!(optimized("a[position() = 1]"), "child::a[1]");
+        assert_eq!(optimized("$a[1 + 1 = position()]"), "$a[2]");
+        assert_eq!(
+            optimized("a[position() = 1.5]"),
+            "child::a[position() = 1.5]"
+        );
+    }
+
+    #[test]
+    fn descendants_are_selected_in_one_step() {
+        assert_eq!(optimized("//a"), "/descendant::a");
+        assert_eq!(optimized("a//b/c"), "child::a/descendant::b/child::c");
+        assert_eq!(
+            optimized("//a[1]"),
+            "/descendant-or-self::node()/child::a[1]"
+        );
+        assert_eq!(
+            optimized("//@a"),
+            "/descendant-or-self::node()/attribute::a"
+        );
+    }
+
+    #[test]
+    fn predicates_that_are_always_true_are_removed() {
+        assert_eq!(optimized("a[1 = 1][true()]/b"), "child::a/child::b");
+        assert_eq!(optimized("a[1 = 2]"), "child::a[false()]");
+    }
+}
@@ -1,59 +1,177 @@
-use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, Snafu};
 use std::iter::Peekable;
 
-use crate::axis::{Axis, AxisLike, PrincipalNodeType};
-use crate::expression::{self, SubExpression};
-use crate::node_test::{self, SubNodeTest};
+use crate::ast::{Axis, BinaryOperator, Expr, Literal, NodeTest, Quantifier, Step};
 use crate::token::{AxisName, NodeTestName, Token};
 use crate::tokenizer::{self, TokenResult};
-use crate::Value;
+use crate::{LanguageLevel, OwnedPrefixedName, ParserLimit, ParserLimits, Span};
 
-#[allow(missing_copy_implementations)]
-pub struct Parser;
+pub struct Parser {
+    limits: ParserLimits,
+    language_level: LanguageLevel,
+}
 
 impl Parser {
     pub fn new() -> Parser {
-        Parser
+        Parser::with_limits(Default::default())
+    }
+
+    pub fn with_limits(limits: ParserLimits) -> Parser {
+        Parser {
+            limits,
+            language_level: LanguageLevel::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: ParserLimits) {
+        self.limits = limits;
+    }
+
+    pub fn language_level(&self) -> LanguageLevel {
+        self.language_level
+    }
+
+    pub fn set_language_level(&mut self, language_level: LanguageLevel) {
+        self.language_level = language_level;
+    }
+
+    /// Fails if the XPath is too long to be parsed, reporting the
+    /// region beyond the limit.
+    pub fn check_length(&self, xpath: &str) -> Result<(), Error> {
+        match self.limits.max_length {
+            Some(maximum) if xpath.len() > maximum => LimitExceeded {
+                limit: ParserLimit::Length,
+                span: Span::new(maximum, xpath.len()),
+            }
+            .fail(),
+            _ => Ok(()),
+        }
     }
 }
 
+/// Every error records the region of the XPath that it applies to.
 #[derive(Debug, Snafu, Clone, PartialEq)]
 #[cfg_attr(test, snafu(visibility(pub(crate))))]
 pub enum Error {
     /// XPath was empty
-    NoXPath,
+    NoXPath { span: Span },
     /// empty predicate
-    EmptyPredicate,
+    EmptyPredicate { span: Span },
     /// extra unparsed tokens
-    ExtraUnparsedTokens,
+    ExtraUnparsedTokens { span: Span },
     /// ran out of input
-    RanOutOfInput,
+    RanOutOfInput { span: Span },
     /// right hand side of expression is missing
-    RightHandSideExpressionMissing,
+    RightHandSideExpressionMissing { span: Span },
     /// function argument is missing
-    ArgumentMissing,
+    ArgumentMissing { span: Span },
+    /// expression is missing
+    ExpressionMissing { span: Span },
     #[snafu(display("tokenizer error: {}", source))]
-    Tokenizer { source: tokenizer::Error },
+    Tokenizer {
+        source: tokenizer::Error,
+        span: Span,
+    },
     /// trailing slash
-    TrailingSlash,
+    TrailingSlash { span: Span },
     #[snafu(display("unexpected token: {:?}", token))]
-    UnexpectedToken { token: Token },
+    UnexpectedToken { token: Token, span: Span },
+    /// expression is not a valid pattern
+    InvalidPattern { span: Span },
+    #[snafu(display("expression exceeded the limit on {}", limit))]
+    LimitExceeded { limit: ParserLimit, span: Span },
 }
 
-pub type ParseResult<T = Option<SubExpression>> = Result<T, Error>;
+impl Error {
+    pub fn span(&self) -> Span {
+        use self::Error::*;
+
+        match *self {
+            NoXPath { span }
+            | EmptyPredicate { span }
+            | ExtraUnparsedTokens { span }
+            | RanOutOfInput { span }
+            | RightHandSideExpressionMissing { span }
+            | ArgumentMissing { span }
+            | ExpressionMissing { span }
+            | Tokenizer { span, .. }
+            | TrailingSlash { span }
+            | UnexpectedToken { span, .. }
+            | InvalidPattern { span }
+            | LimitExceeded { span, .. } => span,
+        }
+    }
+}
 
-type BinaryExpressionBuilder = fn(SubExpression, SubExpression) -> SubExpression;
+pub type ParseResult<T = Option<Expr>> = Result<T, Error>;
 
 struct BinaryRule {
     token: Token,
-    builder: BinaryExpressionBuilder,
+    operator: BinaryOperator,
 }
 
 struct LeftAssociativeBinaryParser {
     rules: Vec<BinaryRule>,
 }
 
-type TokenSource<'a, I> = &'a mut Peekable<I>;
+/// The stream of tokens being parsed. Remembers where the last
+/// consumed token ended so that errors at the end of the input can be
+/// located.
+struct Tokens<I>
+where
+    I: Iterator,
+{
+    tokens: Peekable<I>,
+    end: usize,
+    depth: usize,
+    steps: usize,
+}
+
+impl<I> Tokens<I>
+where
+    I: Iterator<Item = TokenResult>,
+{
+    fn new(tokens: I) -> Self {
+        Tokens {
+            tokens: tokens.peekable(),
+            end: 0,
+            depth: 0,
+            steps: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&TokenResult> {
+        self.tokens.peek()
+    }
+
+    fn next(&mut self) -> Option<TokenResult> {
+        let next = self.tokens.next();
+        if let Some(Ok((span, _))) = next {
+            self.end = span.end;
+        }
+        next
+    }
+
+    /// The region of the next token, or an empty region at the end of
+    /// the input if there are no more tokens.
+    fn span(&mut self) -> Span {
+        match self.peek() {
+            Some(Ok((span, _))) | Some(Err((span, _))) => *span,
+            None => Span::new(self.end, self.end),
+        }
+    }
+
+    fn next_token(&mut self) -> Result<(Span, Token), Error> {
+        let span = self.span();
+        match self.next() {
+            Some(Ok(token)) => Ok(token),
+            Some(Err((span, source))) => Err(Error::Tokenizer { source, span }),
+            None => RanOutOfInput { span }.fail(),
+        }
+    }
+}
+
+type TokenSource<'a, I> = &'a mut Tokens<I>;
 
 trait XCompat {
     fn has_more_tokens(&mut self) -> bool;
@@ -61,7 +179,7 @@ trait XCompat {
     fn consume(&mut self, token: &Token) -> Result<(), Error>;
 }
 
-impl<I> XCompat for Peekable<I>
+impl<I> XCompat for Tokens<I>
 where
     I: Iterator<Item = TokenResult>,
 {
@@ -71,14 +189,14 @@ where
 
     fn next_token_is(&mut self, token: &Token) -> bool {
         match self.peek() {
-            Some(&Ok(ref t)) => t == token,
+            Some(Ok((_, t))) => t == token,
             _ => false,
         }
     }
 
     fn consume(&mut self, token: &Token) -> Result<(), Error> {
-        let x = self.next().context(RanOutOfInput)?.context(Tokenizer)?;
-        ensure!(&x == token, UnexpectedToken { token: x });
+        let (span, x) = self.next_token()?;
+        ensure!(&x == token, UnexpectedToken { token: x, span });
         Ok(())
     }
 }
@@ -87,11 +205,11 @@ where
 /// single value.
 macro_rules! consume_value(
     ($source:expr, Token::$token:ident) => ({
-        let next = $source.next().context(RanOutOfInput)?.context(Tokenizer)?;
+        let (span, next) = $source.next_token()?;
 
         match next {
             Token::$token(x) => x,
-            token => return UnexpectedToken { token }.fail(),
+            token => return UnexpectedToken { token, span }.fail(),
         }
     });
 );
@@ -101,7 +219,7 @@ macro_rules! consume_value(
 macro_rules! next_token_is(
     ($source:expr, Token::$token:ident) => (
         match $source.peek() {
-            Some(&Ok(Token::$token(_))) => true,
+            Some(&Ok((_, Token::$token(_)))) => true,
             _ => false,
         }
     );
@@ -112,7 +230,13 @@ impl LeftAssociativeBinaryParser {
         LeftAssociativeBinaryParser { rules }
     }
 
-    fn parse<F, I>(&self, source: TokenSource<'_, I>, child_parse: F) -> ParseResult
+    /// Each operator counts towards the chain length limit.
+    fn parse<F, I>(
+        &self,
+        parser: &Parser,
+        source: TokenSource<'_, I>,
+        child_parse: F,
+    ) -> ParseResult
     where
         F: Fn(TokenSource<'_, I>) -> ParseResult,
         I: Iterator<Item = TokenResult>,
@@ -124,6 +248,7 @@ impl LeftAssociativeBinaryParser {
             Some(x) => x,
         };
 
+        let mut length = 1;
         while source.has_more_tokens() {
             let mut found = false;
 
@@ -131,9 +256,14 @@ impl LeftAssociativeBinaryParser {
                 if source.next_token_is(&rule.token) {
                     source.consume(&rule.token)?;
 
-                    let right = child_parse(source)?.context(RightHandSideExpressionMissing)?;
+                    let span = source.span();
+                    length += 1;
+                    parser.check_limit(ParserLimit::ChainLength, length, span)?;
+
+                    let right =
+                        child_parse(source)?.context(RightHandSideExpressionMissing { span })?;
 
-                    left = (rule.builder)(left, right);
+                    left = Expr::binary(left, rule.operator, right);
 
                     found = true;
                     break;
@@ -165,6 +295,46 @@ where
 }
 
 impl Parser {
+    fn check_limit(&self, limit: ParserLimit, value: usize, span: Span) -> Result<(), Error> {
+        let maximum = match limit {
+            ParserLimit::Length => self.limits.max_length,
+            ParserLimit::Depth => self.limits.max_depth,
+            ParserLimit::Steps => self.limits.max_steps,
+            ParserLimit::ChainLength => self.limits.max_chain_length,
+        };
+
+        match maximum {
+            Some(maximum) if value > maximum => LimitExceeded { limit, span }.fail(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a subexpression that may itself contain arbitrarily
+    /// nested subexpressions, guarding against unbounded recursion.
+    fn parse_nested<I, F>(&self, source: TokenSource<'_, I>, parse: F) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+        F: FnOnce(TokenSource<'_, I>) -> ParseResult,
+    {
+        source.depth += 1;
+        let span = source.span();
+        let result = self
+            .check_limit(ParserLimit::Depth, source.depth, span)
+            .and_then(|_| parse(source));
+        source.depth -= 1;
+        result
+    }
+
+    /// Counts a step or predicate, found in the given region, towards
+    /// the limit.
+    fn count_step<I>(&self, source: TokenSource<'_, I>, span: Span) -> Result<(), Error>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        source.steps += 1;
+        self.check_limit(ParserLimit::Steps, source.steps, span)
+    }
+
     fn parse_axis<I>(&self, source: TokenSource<'_, I>) -> Result<Axis, Error>
     where
         I: Iterator<Item = TokenResult>,
@@ -192,7 +362,7 @@ impl Parser {
         }
     }
 
-    fn parse_node_test<I>(&self, source: TokenSource<'_, I>) -> Result<Option<SubNodeTest>, Error>
+    fn parse_node_test<I>(&self, source: TokenSource<'_, I>) -> Result<Option<NodeTest>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
@@ -200,36 +370,25 @@ impl Parser {
             let name = consume_value!(source, Token::NodeTest);
 
             match name {
-                NodeTestName::Node => Ok(Some(Box::new(node_test::Node))),
-                NodeTestName::Text => Ok(Some(Box::new(node_test::Text))),
-                NodeTestName::Comment => Ok(Some(Box::new(node_test::Comment))),
-                NodeTestName::ProcessingInstruction(target) => Ok(Some(Box::new(
-                    node_test::ProcessingInstruction::new(target),
-                ))),
+                NodeTestName::Node => Ok(Some(NodeTest::Node)),
+                NodeTestName::Text => Ok(Some(NodeTest::Text)),
+                NodeTestName::Comment => Ok(Some(NodeTest::Comment)),
+                NodeTestName::ProcessingInstruction(target) => {
+                    Ok(Some(NodeTest::ProcessingInstruction(target)))
+                }
             }
         } else {
             Ok(None)
         }
     }
 
-    fn default_node_test<I>(
-        &self,
-        source: TokenSource<'_, I>,
-        axis: Axis,
-    ) -> Result<Option<SubNodeTest>, Error>
+    fn default_node_test<I>(&self, source: TokenSource<'_, I>) -> Result<Option<NodeTest>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
         if next_token_is!(source, Token::NameTest) {
             let name = consume_value!(source, Token::NameTest);
-
-            let test: SubNodeTest = match axis.principal_node_type() {
-                PrincipalNodeType::Attribute => Box::new(node_test::Attribute::new(name)),
-                PrincipalNodeType::Element => Box::new(node_test::Element::new(name)),
-                PrincipalNodeType::Namespace => Box::new(node_test::Namespace::new(name)),
-            };
-
-            Ok(Some(test))
+            Ok(Some(NodeTest::Name(name)))
         } else {
             Ok(None)
         }
@@ -241,7 +400,13 @@ impl Parser {
     {
         if source.next_token_is(&Token::LeftParen) {
             source.consume(&Token::LeftParen)?;
-            let result = self.parse_expression(source)?;
+            if self.language_level >= LanguageLevel::XPath2
+                && source.next_token_is(&Token::RightParen)
+            {
+                source.consume(&Token::RightParen)?;
+                return Ok(Some(Expr::Sequence(Vec::new())));
+            }
+            let result = self.parse_sequence_expression(source)?;
             source.consume(&Token::RightParen)?;
             Ok(result)
         } else {
@@ -255,7 +420,7 @@ impl Parser {
     {
         if next_token_is!(source, Token::Variable) {
             let name = consume_value!(source, Token::Variable);
-            Ok(Some(Box::new(expression::Variable { name })))
+            Ok(Some(Expr::Variable(name)))
         } else {
             Ok(None)
         }
@@ -267,9 +432,7 @@ impl Parser {
     {
         if next_token_is!(source, Token::Literal) {
             let value = consume_value!(source, Token::Literal);
-            Ok(Some(Box::new(expression::Literal::from(Value::String(
-                value,
-            )))))
+            Ok(Some(Expr::Literal(Literal::String(value))))
         } else {
             Ok(None)
         }
@@ -281,9 +444,7 @@ impl Parser {
     {
         if next_token_is!(source, Token::Number) {
             let value = consume_value!(source, Token::Number);
-            Ok(Some(Box::new(expression::Literal::from(Value::Number(
-                value,
-            )))))
+            Ok(Some(Expr::Literal(Literal::Number(value))))
         } else {
             Ok(None)
         }
@@ -292,25 +453,24 @@ impl Parser {
     fn parse_function_args_tail<I>(
         &self,
         source: TokenSource<'_, I>,
-        mut arguments: Vec<SubExpression>,
-    ) -> Result<Vec<SubExpression>, Error>
+        mut arguments: Vec<Expr>,
+    ) -> Result<Vec<Expr>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
         while source.next_token_is(&Token::Comma) {
             source.consume(&Token::Comma)?;
 
-            let arg = self.parse_expression(source)?.context(ArgumentMissing)?;
+            let arg = self.parse_expression(source)?.context(ArgumentMissing {
+                span: source.span(),
+            })?;
             arguments.push(arg);
         }
 
         Ok(arguments)
     }
 
-    fn parse_function_args<I>(
-        &self,
-        source: TokenSource<'_, I>,
-    ) -> Result<Vec<SubExpression>, Error>
+    fn parse_function_args<I>(&self, source: TokenSource<'_, I>) -> Result<Vec<Expr>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
@@ -335,7 +495,7 @@ impl Parser {
             let arguments = self.parse_function_args(source)?;
             source.consume(&Token::RightParen)?;
 
-            Ok(Some(Box::new(expression::Function { name, arguments })))
+            Ok(Some(Expr::Function { name, arguments }))
         } else {
             Ok(None)
         }
@@ -361,9 +521,15 @@ impl Parser {
         I: Iterator<Item = TokenResult>,
     {
         if source.next_token_is(&Token::LeftBracket) {
+            let span = source.span();
+            self.count_step(source, span)?;
             source.consume(&Token::LeftBracket)?;
 
-            let predicate = self.parse_expression(source)?.context(EmptyPredicate)?;
+            let predicate = self
+                .parse_sequence_expression(source)?
+                .context(EmptyPredicate {
+                    span: source.span(),
+                })?;
             source.consume(&Token::RightBracket)?;
             Ok(Some(predicate))
         } else {
@@ -371,7 +537,7 @@ impl Parser {
         }
     }
 
-    fn parse_predicates<I>(&self, source: TokenSource<'_, I>) -> Result<Vec<SubExpression>, Error>
+    fn parse_predicates<I>(&self, source: TokenSource<'_, I>) -> Result<Vec<Expr>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
@@ -384,31 +550,38 @@ impl Parser {
         Ok(predicates)
     }
 
-    fn parse_step<I>(&self, source: TokenSource<'_, I>) -> Result<Option<expression::Step>, Error>
+    fn parse_step<I>(&self, source: TokenSource<'_, I>) -> Result<Option<Step>, Error>
     where
         I: Iterator<Item = TokenResult>,
     {
+        let start = source.span().start;
         let axis = self.parse_axis(source)?;
 
         let node_test = match self.parse_node_test(source)? {
             Some(test) => Some(test),
-            None => self.default_node_test(source, axis)?,
+            None => self.default_node_test(source)?,
         };
 
         let node_test = match node_test {
             Some(test) => test,
             None => return Ok(None),
         };
+        let span = Span::new(start, source.end);
+        self.count_step(source, span)?;
 
         let predicates = self.parse_predicates(source)?;
 
-        Ok(Some(expression::Step::new(axis, node_test, predicates)))
+        Ok(Some(Step {
+            axis,
+            node_test,
+            predicates,
+        }))
     }
 
     fn parse_relative_location_path_raw<I>(
         &self,
         source: TokenSource<'_, I>,
-        start_point: SubExpression,
+        start_point: Expr,
     ) -> ParseResult
     where
         I: Iterator<Item = TokenResult>,
@@ -420,11 +593,16 @@ impl Parser {
                 while source.next_token_is(&Token::Slash) {
                     source.consume(&Token::Slash)?;
 
-                    let next = self.parse_step(source)?.context(TrailingSlash)?;
+                    let next = self.parse_step(source)?.context(TrailingSlash {
+                        span: source.span(),
+                    })?;
                     steps.push(next);
                 }
 
-                Ok(Some(expression::Path::new(start_point, steps)))
+                Ok(Some(Expr::Path {
+                    start: Box::new(start_point),
+                    steps,
+                }))
             }
             None => Ok(None),
         }
@@ -434,8 +612,7 @@ impl Parser {
     where
         I: Iterator<Item = TokenResult>,
     {
-        let start_point = Box::new(expression::ContextNode);
-        self.parse_relative_location_path_raw(source, start_point)
+        self.parse_relative_location_path_raw(source, Expr::ContextNode)
     }
 
     fn parse_absolute_location_path<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -445,10 +622,9 @@ impl Parser {
         if source.next_token_is(&Token::Slash) {
             source.consume(&Token::Slash)?;
 
-            let start_point = Box::new(expression::RootNode);
-            match self.parse_relative_location_path_raw(source, start_point)? {
+            match self.parse_relative_location_path_raw(source, Expr::RootNode)? {
                 Some(expr) => Ok(Some(expr)),
-                None => Ok(Some(Box::new(expression::RootNode))),
+                None => Ok(Some(Expr::RootNode)),
             }
         } else {
             Ok(None)
@@ -471,15 +647,27 @@ impl Parser {
     where
         I: Iterator<Item = TokenResult>,
     {
-        match self.parse_primary_expression(source)? {
-            Some(expr) => {
-                let predicates = self.parse_predicates(source)?;
+        let mut expr = match self.parse_primary_expression(source)? {
+            Some(expr) => expr,
+            None => return Ok(None),
+        };
 
-                Ok(Some(predicates.into_iter().fold(expr, |expr, pred| {
-                    expression::Filter::new(expr, pred)
-                })))
-            }
-            None => Ok(None),
+        // Each predicate counts towards the chain length limit
+        let mut length = 1;
+        loop {
+            let span = source.span();
+            let predicate = match self.parse_predicate_expression(source)? {
+                Some(predicate) => predicate,
+                None => return Ok(Some(expr)),
+            };
+
+            length += 1;
+            self.check_limit(ParserLimit::ChainLength, length, span)?;
+
+            expr = Expr::Filter {
+                expression: Box::new(expr),
+                predicate: Box::new(predicate),
+            };
         }
     }
 
@@ -499,7 +687,9 @@ impl Parser {
 
                     let expr = self
                         .parse_relative_location_path_raw(source, expr)?
-                        .context(TrailingSlash)?;
+                        .context(TrailingSlash {
+                            span: source.span(),
+                        })?;
                     Ok(Some(expr))
                 } else {
                     Ok(Some(expr))
@@ -515,11 +705,32 @@ impl Parser {
     {
         let rules = vec![BinaryRule {
             token: Token::Pipe,
-            builder: expression::Union::new,
+            operator: BinaryOperator::Union,
         }];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_path_expression(source))
+        parser.parse(self, source, |source| {
+            self.parse_intersect_except_expression(source)
+        })
+    }
+
+    fn parse_intersect_except_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let rules = vec![
+            BinaryRule {
+                token: Token::Intersect,
+                operator: BinaryOperator::Intersect,
+            },
+            BinaryRule {
+                token: Token::Except,
+                operator: BinaryOperator::Except,
+            },
+        ];
+
+        let parser = LeftAssociativeBinaryParser::new(rules);
+        parser.parse(self, source, |source| self.parse_path_expression(source))
     }
 
     fn parse_unary_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -535,10 +746,11 @@ impl Parser {
             source.consume(&Token::MinusSign)?;
 
             let expression = self
-                .parse_unary_expression(source)?
-                .context(RightHandSideExpressionMissing)?;
-            let expression: SubExpression = Box::new(expression::Negation { expression });
-            Ok(Some(expression))
+                .parse_nested(source, |source| self.parse_unary_expression(source))?
+                .context(RightHandSideExpressionMissing {
+                    span: source.span(),
+                })?;
+            Ok(Some(Expr::Negation(Box::new(expression))))
         } else {
             Ok(None)
         }
@@ -551,20 +763,20 @@ impl Parser {
         let rules = vec![
             BinaryRule {
                 token: Token::Multiply,
-                builder: expression::Math::multiplication,
+                operator: BinaryOperator::Multiplication,
             },
             BinaryRule {
                 token: Token::Divide,
-                builder: expression::Math::division,
+                operator: BinaryOperator::Division,
             },
             BinaryRule {
                 token: Token::Remainder,
-                builder: expression::Math::remainder,
+                operator: BinaryOperator::Remainder,
             },
         ];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_unary_expression(source))
+        parser.parse(self, source, |source| self.parse_unary_expression(source))
     }
 
     fn parse_additive_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -574,20 +786,43 @@ impl Parser {
         let rules = vec![
             BinaryRule {
                 token: Token::PlusSign,
-                builder: expression::Math::addition,
+                operator: BinaryOperator::Addition,
             },
             BinaryRule {
                 token: Token::MinusSign,
-                builder: expression::Math::subtraction,
+                operator: BinaryOperator::Subtraction,
             },
         ];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| {
+        parser.parse(self, source, |source| {
             self.parse_multiplicative_expression(source)
         })
     }
 
+    /// Parses `a to b`. Unlike the other operators, ranges cannot be
+    /// chained.
+    fn parse_range_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let left = match self.parse_additive_expression(source)? {
+            Some(left) => left,
+            None => return Ok(None),
+        };
+        if !source.next_token_is(&Token::To) {
+            return Ok(Some(left));
+        }
+        source.consume(&Token::To)?;
+
+        let right =
+            self.parse_additive_expression(source)?
+                .context(RightHandSideExpressionMissing {
+                    span: source.span(),
+                })?;
+        Ok(Some(Expr::binary(left, BinaryOperator::Range, right)))
+    }
+
     fn parse_relational_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
     where
         I: Iterator<Item = TokenResult>,
@@ -595,24 +830,24 @@ impl Parser {
         let rules = vec![
             BinaryRule {
                 token: Token::LessThan,
-                builder: expression::Relational::less_than,
+                operator: BinaryOperator::LessThan,
             },
             BinaryRule {
                 token: Token::LessThanOrEqual,
-                builder: expression::Relational::less_than_or_equal,
+                operator: BinaryOperator::LessThanOrEqual,
             },
             BinaryRule {
                 token: Token::GreaterThan,
-                builder: expression::Relational::greater_than,
+                operator: BinaryOperator::GreaterThan,
             },
             BinaryRule {
                 token: Token::GreaterThanOrEqual,
-                builder: expression::Relational::greater_than_or_equal,
+                operator: BinaryOperator::GreaterThanOrEqual,
             },
         ];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_additive_expression(source))
+        parser.parse(self, source, |source| self.parse_range_expression(source))
     }
 
     fn parse_equality_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -622,16 +857,18 @@ impl Parser {
         let rules = vec![
             BinaryRule {
                 token: Token::Equal,
-                builder: expression::Equal::new,
+                operator: BinaryOperator::Equal,
             },
             BinaryRule {
                 token: Token::NotEqual,
-                builder: expression::NotEqual::new,
+                operator: BinaryOperator::NotEqual,
             },
         ];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_relational_expression(source))
+        parser.parse(self, source, |source| {
+            self.parse_relational_expression(source)
+        })
     }
 
     fn parse_and_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -640,11 +877,13 @@ impl Parser {
     {
         let rules = vec![BinaryRule {
             token: Token::And,
-            builder: expression::And::new,
+            operator: BinaryOperator::And,
         }];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_equality_expression(source))
+        parser.parse(self, source, |source| {
+            self.parse_equality_expression(source)
+        })
     }
 
     fn parse_or_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
@@ -653,31 +892,233 @@ impl Parser {
     {
         let rules = vec![BinaryRule {
             token: Token::Or,
-            builder: expression::Or::new,
+            operator: BinaryOperator::Or,
         }];
 
         let parser = LeftAssociativeBinaryParser::new(rules);
-        parser.parse(source, |source| self.parse_and_expression(source))
+        parser.parse(self, source, |source| self.parse_and_expression(source))
+    }
+
+    /// Parses an expression that must be present, such as a branch of
+    /// a conditional.
+    fn parse_required_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult<Expr>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let span = source.span();
+        self.parse_expression(source)?
+            .context(ExpressionMissing { span })
+    }
+
+    fn parse_if_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        if !source.next_token_is(&Token::If) {
+            return Ok(None);
+        }
+
+        source.consume(&Token::If)?;
+        source.consume(&Token::LeftParen)?;
+        let condition = self.parse_required_expression(source)?;
+        source.consume(&Token::RightParen)?;
+        source.consume(&Token::Then)?;
+        let then = self.parse_required_expression(source)?;
+        source.consume(&Token::Else)?;
+        let otherwise = self.parse_required_expression(source)?;
+
+        Ok(Some(Expr::Conditional {
+            condition: Box::new(condition),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        }))
+    }
+
+    /// Parses the variables bound by `for`, `let`, `some` and `every`,
+    /// such as `$a in b, $c in d`, where each variable is separated
+    /// from its expression by the given token.
+    fn parse_bindings<I>(
+        &self,
+        source: TokenSource<'_, I>,
+        separator: &Token,
+    ) -> ParseResult<Vec<(OwnedPrefixedName, Expr)>>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let mut bindings = Vec::new();
+        loop {
+            let variable = consume_value!(source, Token::Variable);
+            source.consume(separator)?;
+            let expr = self.parse_required_expression(source)?;
+            bindings.push((variable, expr));
+
+            if !source.next_token_is(&Token::Comma) {
+                return Ok(bindings);
+            }
+            source.consume(&Token::Comma)?;
+        }
+    }
+
+    /// Parses the bindings, the token that follows them and the
+    /// expression they are bound in. Each variable after the first is
+    /// bound in the expression of the one before it, which counts
+    /// towards the chain length limit.
+    fn parse_bound_expression<I>(
+        &self,
+        source: TokenSource<'_, I>,
+        separator: &Token,
+        terminator: &Token,
+    ) -> ParseResult<(Vec<(OwnedPrefixedName, Expr)>, Expr)>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let span = source.span();
+        let bindings = self.parse_bindings(source, separator)?;
+        self.check_limit(ParserLimit::ChainLength, bindings.len(), span)?;
+
+        source.consume(terminator)?;
+        let body = self.parse_required_expression(source)?;
+        Ok((bindings, body))
+    }
+
+    /// Parses `some $a in b, $c in d satisfies e` as if it were
+    /// `some $a in b satisfies some $c in d satisfies e`.
+    fn parse_quantified_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let quantifier = if source.next_token_is(&Token::Some) {
+            Quantifier::Some
+        } else if source.next_token_is(&Token::Every) {
+            Quantifier::Every
+        } else {
+            return Ok(None);
+        };
+        source.next_token()?;
+
+        let (bindings, condition) =
+            self.parse_bound_expression(source, &Token::In, &Token::Satisfies)?;
+
+        let expr = bindings
+            .into_iter()
+            .rev()
+            .fold(condition, |condition, (variable, sequence)| {
+                Expr::Quantified {
+                    quantifier,
+                    variable,
+                    sequence: Box::new(sequence),
+                    condition: Box::new(condition),
+                }
+            });
+        Ok(Some(expr))
+    }
+
+    /// Parses `for $a in b, $c in d return e` as if it were
+    /// `for $a in b return for $c in d return e`.
+    fn parse_for_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        if !source.next_token_is(&Token::For) {
+            return Ok(None);
+        }
+        source.consume(&Token::For)?;
+
+        let (bindings, body) = self.parse_bound_expression(source, &Token::In, &Token::Return)?;
+
+        let expr = bindings
+            .into_iter()
+            .rev()
+            .fold(body, |body, (variable, sequence)| Expr::For {
+                variable,
+                sequence: Box::new(sequence),
+                body: Box::new(body),
+            });
+        Ok(Some(expr))
+    }
+
+    /// Parses `let $a := b, $c := d return e` as if it were
+    /// `let $a := b return let $c := d return e`.
+    fn parse_let_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        if !source.next_token_is(&Token::Let) {
+            return Ok(None);
+        }
+        source.consume(&Token::Let)?;
+
+        let (bindings, body) =
+            self.parse_bound_expression(source, &Token::Assign, &Token::Return)?;
+
+        let expr = bindings
+            .into_iter()
+            .rev()
+            .fold(body, |body, (variable, value)| Expr::Let {
+                variable,
+                value: Box::new(value),
+                body: Box::new(body),
+            });
+        Ok(Some(expr))
     }
 
     fn parse_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
     where
         I: Iterator<Item = TokenResult>,
     {
-        self.parse_or_expression(source)
+        self.parse_nested(source, |source| {
+            let rules: &[&Rule<'_, I>] = &[
+                &|src: TokenSource<'_, I>| self.parse_if_expression(src),
+                &|src: TokenSource<'_, I>| self.parse_quantified_expression(src),
+                &|src: TokenSource<'_, I>| self.parse_for_expression(src),
+                &|src: TokenSource<'_, I>| self.parse_let_expression(src),
+                &|src: TokenSource<'_, I>| self.parse_or_expression(src),
+            ];
+
+            first_matching_rule(rules, source)
+        })
     }
 
-    pub fn parse<I>(&self, source: I) -> ParseResult<SubExpression>
+    /// Parses `a, b, c` as a sequence where XPath 2.0 allows it: in
+    /// parentheses, in predicates and as the entire XPath.
+    fn parse_sequence_expression<I>(&self, source: TokenSource<'_, I>) -> ParseResult
     where
         I: Iterator<Item = TokenResult>,
     {
-        let mut source = source.peekable();
+        let first = match self.parse_expression(source)? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        if self.language_level < LanguageLevel::XPath2 || !source.next_token_is(&Token::Comma) {
+            return Ok(Some(first));
+        }
 
-        let expr = self.parse_or_expression(&mut source)?;
+        let mut expressions = vec![first];
+        while source.next_token_is(&Token::Comma) {
+            source.consume(&Token::Comma)?;
+            expressions.push(self.parse_required_expression(source)?);
+        }
+        Ok(Some(Expr::Sequence(expressions)))
+    }
 
-        ensure!(!source.has_more_tokens(), ExtraUnparsedTokens);
+    pub fn parse<I>(&self, source: I) -> ParseResult<Expr>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        let mut source = Tokens::new(source);
 
-        let expr = expr.context(NoXPath)?;
+        let expr = self.parse_sequence_expression(&mut source)?;
+
+        ensure!(
+            !source.has_more_tokens(),
+            ExtraUnparsedTokens {
+                span: source.span()
+            }
+        );
+
+        let expr = expr.context(NoXPath {
+            span: source.span(),
+        })?;
 
         Ok(expr)
     }
@@ -685,7 +1126,6 @@ impl Parser {
 
 #[cfg(test)]
 mod test {
-    use snafu::ResultExt;
     use std::borrow::ToOwned;
     use sxd_document::dom::{self, Document, Element, Root, Text};
     use sxd_document::Package;
@@ -696,17 +1136,21 @@ mod test {
     use crate::nodeset::Node;
     use crate::token::{AxisName, NodeTestName, Token};
     use crate::tokenizer::{self, TokenResult};
-    use crate::Value;
     use crate::Value::{Boolean, Number, String};
+    use crate::{Item, Value};
 
     use super::*;
 
+    // Each token is given a one-byte span matching its position in
+    // the list.
     macro_rules! tokens(
         ($($e:expr),*) => ({
-            // leading _ to allow empty construction without a warning.
-            let mut _temp: Vec<TokenResult> = ::std::vec::Vec::new();
-            $(_temp.push(Ok($e));)*
-            _temp
+            let tokens: Vec<Token> = vec![$($e),*];
+            tokens
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| Ok((Span::new(i, i + 1), t)))
+                .collect::<Vec<TokenResult>>()
         });
         ($($e:expr),+,) => (tokens!($($e),+))
     );
@@ -731,7 +1175,7 @@ mod test {
     impl<'d> ApproxEq for Value<'d> {
         fn is_approx_eq(&self, other: &Value<'d>) -> bool {
             match (self, other) {
-                (&Number(ref x), &Number(ref y)) => x.is_approx_eq(y),
+                (Number(x), Number(y)) => x.is_approx_eq(y),
                 _ => panic!("It's nonsensical to compare these quantities"),
             }
         }
@@ -749,12 +1193,12 @@ mod test {
 
     impl<'d> TestDoc<'d> {
         fn root(&'d self) -> Root<'d> {
-            let &TestDoc(ref doc) = self;
+            let TestDoc(doc) = self;
             doc.root()
         }
 
         fn top_node(&'d self) -> Element<'d> {
-            let &TestDoc(ref doc) = self;
+            let TestDoc(doc) = self;
 
             let kids = doc.root().children();
             match kids.len() {
@@ -773,7 +1217,7 @@ mod test {
         }
 
         fn add_child(&'d self, parent: Element<'d>, name: &str) -> Element<'d> {
-            let &TestDoc(ref doc) = self;
+            let TestDoc(doc) = self;
 
             let n = doc.create_element(name);
             parent.append_child(n);
@@ -781,7 +1225,7 @@ mod test {
         }
 
         fn add_text(&'d self, parent: Element<'d>, value: &str) -> Text<'d> {
-            let &TestDoc(ref doc) = self;
+            let TestDoc(doc) = self;
 
             let tn = doc.create_text(value);
             parent.append_child(tn);
@@ -821,12 +1265,14 @@ mod test {
             }
         }
 
-        fn parse_raw(&self, tokens: Vec<TokenResult>) -> ParseResult<SubExpression> {
+        fn parse_raw(&self, tokens: Vec<TokenResult>) -> ParseResult<Expr> {
             self.parser.parse(tokens.into_iter())
         }
 
         fn parse(&self, tokens: Vec<TokenResult>) -> SubExpression {
-            self.parse_raw(tokens).expect("Unable to parse expression")
+            self.parse_raw(tokens)
+                .expect("Unable to parse expression")
+                .compile()
         }
 
         fn evaluate<E>(&self, expr: E) -> Value<'d>
@@ -1448,6 +1894,222 @@ mod test {
         assert_eq!(Boolean(true), ex.evaluate(expr));
     }
 
+    #[test]
+    fn conditional_expression() {
+        let tokens = tokens![
+            Token::If,
+            Token::LeftParen,
+            Token::Number(0.0),
+            Token::RightParen,
+            Token::Then,
+            Token::Variable("missing".into()),
+            Token::Else,
+            Token::Number(2.0),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let ex = Exercise::new(&doc);
+        let expr = ex.parse(tokens);
+
+        assert_eq!(Number(2.0), ex.evaluate(expr));
+    }
+
+    #[test]
+    fn quantified_expression_with_several_variables() {
+        // every $a in 1, $b in 2 satisfies $a < $b
+        let tokens = tokens![
+            Token::Every,
+            Token::Variable("a".into()),
+            Token::In,
+            Token::Number(1.0),
+            Token::Comma,
+            Token::Variable("b".into()),
+            Token::In,
+            Token::Number(2.0),
+            Token::Satisfies,
+            Token::Variable("a".into()),
+            Token::LessThan,
+            Token::Variable("b".into()),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.context.set_variable("b", 0.0);
+        let expr = ex.parse(tokens);
+
+        assert_eq!(Boolean(true), ex.evaluate(expr));
+    }
+
+    #[test]
+    fn let_expression_hides_other_variables() {
+        // let $a := $a + 1, $a := $a * 2 return $a
+        let tokens = tokens![
+            Token::Let,
+            Token::Variable("a".into()),
+            Token::Assign,
+            Token::Variable("a".into()),
+            Token::PlusSign,
+            Token::Number(1.0),
+            Token::Comma,
+            Token::Variable("a".into()),
+            Token::Assign,
+            Token::Variable("a".into()),
+            Token::Multiply,
+            Token::Number(2.0),
+            Token::Return,
+            Token::Variable("a".into()),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.context.set_variable("a", 1.0);
+        let expr = ex.parse(tokens);
+
+        assert_eq!(Number(4.0), ex.evaluate(expr));
+    }
+
+    #[test]
+    fn for_expression_selects_nodes_for_each_item() {
+        // for $a in * return $a/*
+        let tokens = tokens![
+            Token::For,
+            Token::Variable("a".into()),
+            Token::In,
+            name_test("*"),
+            Token::Return,
+            Token::Variable("a".into()),
+            Token::Slash,
+            name_test("*"),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+        let first = doc.add_child(doc.top_node(), "first");
+        let second = doc.add_child(doc.top_node(), "second");
+        let grandchild_1 = doc.add_child(first, "grandchild");
+        let grandchild_2 = doc.add_child(second, "grandchild");
+
+        let ex = Exercise::new(&doc);
+        let expr = ex.parse(tokens);
+
+        assert_eq!(nodeset![grandchild_1, grandchild_2], ex.evaluate(expr));
+    }
+
+    #[test]
+    fn range_expression_binds_looser_than_addition() {
+        // 1 + 1 to 3
+        let tokens = tokens![
+            Token::Number(1.0),
+            Token::PlusSign,
+            Token::Number(1.0),
+            Token::To,
+            Token::Number(3.0),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let ex = Exercise::new(&doc);
+        let expr = ex.parse(tokens);
+
+        assert_eq!(
+            Value::Sequence(vec![Item::Number(2.0), Item::Number(3.0)]),
+            ex.evaluate(expr)
+        );
+    }
+
+    #[test]
+    fn ranges_cannot_be_chained() {
+        let tokens = tokens![
+            Token::Number(1.0),
+            Token::To,
+            Token::Number(2.0),
+            Token::To,
+            Token::Number(3.0),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let ex = Exercise::new(&doc);
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::ExtraUnparsedTokens {
+                span: Span::new(3, 4),
+            }),
+            res.err()
+        );
+    }
+
+    #[test]
+    fn sequence_expression_requires_xpath_2() {
+        // (1, $a, ())
+        let tokens = || {
+            tokens![
+                Token::LeftParen,
+                Token::Number(1.0),
+                Token::Comma,
+                Token::Variable("a".into()),
+                Token::Comma,
+                Token::LeftParen,
+                Token::RightParen,
+                Token::RightParen,
+            ]
+        };
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.context.set_variable("a", "b");
+        assert_eq!(
+            Some(Error::UnexpectedToken {
+                token: Token::Comma,
+                span: Span::new(2, 3),
+            }),
+            ex.parse_raw(tokens()).err()
+        );
+
+        ex.parser.set_language_level(LanguageLevel::XPath2);
+        let expr = ex.parse(tokens());
+
+        assert_eq!(
+            Value::Sequence(vec![Item::Number(1.0), Item::String("b".into())]),
+            ex.evaluate(expr)
+        );
+    }
+
+    #[test]
+    fn missing_conditional_branch_is_reported_as_an_error() {
+        let tokens = tokens![
+            Token::If,
+            Token::LeftParen,
+            Token::Number(1.0),
+            Token::RightParen,
+            Token::Then,
+            Token::Number(1.0),
+            Token::Else,
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let ex = Exercise::new(&doc);
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::ExpressionMissing {
+                span: Span::new(7, 7),
+            }),
+            res.err()
+        );
+    }
+
     #[test]
     fn and_expression() {
         let tokens = tokens![Token::Number(1.2), Token::And, Token::Number(0.0),];
@@ -1658,6 +2320,36 @@ mod test {
         assert_eq!(nodeset![node1, node2], ex.evaluate(expr));
     }
 
+    #[test]
+    fn intersect_and_except_bind_tighter_than_union() {
+        // $a | $b except $c intersect $d
+        let tokens = tokens![
+            Token::Variable("a".into()),
+            Token::Pipe,
+            Token::Variable("b".into()),
+            Token::Except,
+            Token::Variable("c".into()),
+            Token::Intersect,
+            Token::Variable("d".into()),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+        let node1 = doc.add_top_child("first-node");
+        let node2 = doc.add_top_child("second-node");
+        let node3 = doc.add_top_child("third-node");
+
+        let mut ex = Exercise::new(&doc);
+        ex.context.set_variable("a", nodeset![node1]);
+        ex.context.set_variable("b", nodeset![node1, node2, node3]);
+        ex.context.set_variable("c", nodeset![node1, node2]);
+        ex.context.set_variable("d", nodeset![node3]);
+
+        let expr = ex.parse(tokens);
+
+        assert_eq!(nodeset![node1, node3], ex.evaluate(expr));
+    }
+
     #[test]
     fn absolute_path_expression() {
         let tokens = tokens![Token::Slash,];
@@ -1699,7 +2391,8 @@ mod test {
         let res = ex.parser.parse(tokens.into_iter());
         assert_eq!(
             Some(Error::UnexpectedToken {
-                token: Token::RightParen
+                token: Token::RightParen,
+                span: Span::new(1, 2),
             }),
             res.err()
         );
@@ -1714,7 +2407,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::NoXPath), res.err());
+        assert_eq!(
+            Some(Error::NoXPath {
+                span: Span::new(0, 0)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1726,7 +2424,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::RightHandSideExpressionMissing), res.err());
+        assert_eq!(
+            Some(Error::RightHandSideExpressionMissing {
+                span: Span::new(2, 2)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1738,7 +2441,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parser.parse(tokens.into_iter());
-        assert_eq!(Some(Error::RightHandSideExpressionMissing), res.err());
+        assert_eq!(
+            Some(Error::RightHandSideExpressionMissing {
+                span: Span::new(1, 1)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1750,7 +2458,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::EmptyPredicate), res.err());
+        assert_eq!(
+            Some(Error::EmptyPredicate {
+                span: Span::new(2, 3)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1762,7 +2475,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::TrailingSlash), res.err());
+        assert_eq!(
+            Some(Error::TrailingSlash {
+                span: Span::new(2, 2)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1774,7 +2492,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::TrailingSlash), res.err());
+        assert_eq!(
+            Some(Error::TrailingSlash {
+                span: Span::new(2, 2)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1786,7 +2509,12 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::RanOutOfInput), res.err());
+        assert_eq!(
+            Some(Error::RanOutOfInput {
+                span: Span::new(1, 1)
+            }),
+            res.err()
+        );
     }
 
     #[test]
@@ -1798,14 +2526,19 @@ mod test {
 
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
-        assert_eq!(Some(Error::ExtraUnparsedTokens), res.err());
+        assert_eq!(
+            Some(Error::ExtraUnparsedTokens {
+                span: Span::new(0, 1)
+            }),
+            res.err()
+        );
     }
 
     #[test]
     fn a_tokenizer_error_is_reported_as_an_error() {
         let tokens = vec![
-            Ok(Token::Function("func".into())),
-            Err(tokenizer::Error::UnableToCreateToken),
+            Ok((Span::new(0, 4), Token::Function("func".into()))),
+            Err((Span::new(4, 5), tokenizer::Error::UnableToCreateToken)),
         ];
 
         let package = Package::new();
@@ -1814,10 +2547,118 @@ mod test {
         let ex = Exercise::new(&doc);
         let res = ex.parse_raw(tokens);
         assert_eq!(
-            tokenizer::UnableToCreateToken
-                .fail::<()>()
-                .context(Tokenizer)
-                .err(),
+            Some(Error::Tokenizer {
+                source: tokenizer::Error::UnableToCreateToken,
+                span: Span::new(4, 5),
+            }),
+            res.err()
+        );
+    }
+
+    #[test]
+    fn nesting_too_deeply_is_reported_as_an_error() {
+        let tokens = tokens![
+            Token::LeftParen,
+            Token::LeftParen,
+            Token::Number(1.0),
+            Token::RightParen,
+            Token::RightParen,
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.parser = Parser::with_limits(ParserLimits {
+            max_depth: Some(2),
+            ..Default::default()
+        });
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::LimitExceeded {
+                limit: ParserLimit::Depth,
+                span: Span::new(2, 3),
+            }),
+            res.err()
+        );
+    }
+
+    #[test]
+    fn nested_negations_count_towards_the_depth() {
+        let tokens = tokens![Token::MinusSign, Token::MinusSign, Token::Number(1.0)];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.parser = Parser::with_limits(ParserLimits {
+            max_depth: Some(2),
+            ..Default::default()
+        });
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::LimitExceeded {
+                limit: ParserLimit::Depth,
+                span: Span::new(2, 3),
+            }),
+            res.err()
+        );
+    }
+
+    #[test]
+    fn chains_of_operators_that_are_too_long_are_reported_as_an_error() {
+        let tokens = tokens![
+            Token::Number(1.0),
+            Token::PlusSign,
+            Token::Number(2.0),
+            Token::PlusSign,
+            Token::Number(3.0),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.parser = Parser::with_limits(ParserLimits {
+            max_depth: Some(1),
+            max_chain_length: Some(2),
+            ..Default::default()
+        });
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::LimitExceeded {
+                limit: ParserLimit::ChainLength,
+                span: Span::new(4, 5),
+            }),
+            res.err()
+        );
+    }
+
+    #[test]
+    fn too_many_steps_and_predicates_is_reported_as_an_error() {
+        let tokens = tokens![
+            name_test("a"),
+            Token::LeftBracket,
+            Token::Number(1.0),
+            Token::RightBracket,
+            Token::Slash,
+            name_test("b"),
+        ];
+
+        let package = Package::new();
+        let doc = TestDoc(package.as_document());
+
+        let mut ex = Exercise::new(&doc);
+        ex.parser = Parser::with_limits(ParserLimits {
+            max_steps: Some(2),
+            ..Default::default()
+        });
+        let res = ex.parse_raw(tokens);
+        assert_eq!(
+            Some(Error::LimitExceeded {
+                limit: ParserLimit::Steps,
+                span: Span::new(5, 6),
+            }),
             res.err()
         );
     }
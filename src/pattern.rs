@@ -0,0 +1,536 @@
+//! XSLT patterns, which test whether a node has a given shape rather
+//! than selecting nodes.
+//!
+//! A pattern is a restricted XPath: a union of location paths that
+//! only use the `child` and `attribute` axes, optionally starting at
+//! the root or at a call to `id()` or `key()` with literal arguments.
+//!
+//! # Examples
+//!
+//! ```
+//! use sxd_document::parser;
+//! use sxd_xpath::{Context, Factory};
+//!
+//! let package = parser::parse("<book><title/></book>").expect("failed to parse XML");
+//! let document = package.as_document();
+//! let book = document.root().children()[0].element().unwrap();
+//! let title = book.children()[0];
+//!
+//! let pattern = Factory::new().build_pattern("book/title | chapter").unwrap();
+//! let context = Context::new();
+//!
+//! assert_eq!(Ok(true), pattern.matches(&context, title));
+//! assert_eq!(Ok(false), pattern.matches(&context, book));
+//! ```
+
+use crate::ast::{self, Axis, BinaryOperator, Expr, Literal};
+use crate::context::{self, Context};
+use crate::expression::{self, SubExpression};
+use crate::node_test::SubNodeTest;
+use crate::nodeset::{Node, OrderedNodes};
+use crate::{ExecutionError, OwnedPrefixedName, Value};
+
+/// A compiled XSLT pattern. Construct via [`Factory::build_pattern`][].
+///
+/// [`Factory::build_pattern`]: ../struct.Factory.html#method.build_pattern
+#[derive(Debug)]
+pub struct Pattern {
+    alternatives: Vec<Alternative>,
+}
+
+impl Pattern {
+    /// Converts an expression into a pattern, if it has the shape of
+    /// one.
+    pub(crate) fn new(expr: Expr) -> Option<Pattern> {
+        let mut exprs = Vec::new();
+        flatten_union(expr, &mut exprs);
+
+        let alternatives = exprs
+            .into_iter()
+            .map(Alternative::new)
+            .collect::<Option<_>>()?;
+
+        Some(Pattern { alternatives })
+    }
+
+    /// Checks if the node matches any of the alternatives of this
+    /// pattern.
+    pub fn matches<'d, N>(&self, context: &Context<'d>, node: N) -> Result<bool, ExecutionError>
+    where
+        N: Into<Node<'d>>,
+    {
+        let node = node.into();
+
+        for alternative in &self.alternatives {
+            if alternative.matches(context, node)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The alternatives separated by `|`, in the order they were
+    /// written. XSLT treats each as a separate rule with its own
+    /// priority.
+    pub fn alternatives(&self) -> &[Alternative] {
+        &self.alternatives
+    }
+}
+
+fn flatten_union(expr: Expr, exprs: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary {
+            left,
+            operator: BinaryOperator::Union,
+            right,
+        } => {
+            flatten_union(*left, exprs);
+            flatten_union(*right, exprs);
+        }
+        expr => exprs.push(expr),
+    }
+}
+
+/// One of the location path patterns that make up a pattern.
+#[derive(Debug)]
+pub struct Alternative {
+    root: Root,
+    steps: Vec<StepPattern>,
+    default_priority: f64,
+}
+
+impl Alternative {
+    fn new(expr: Expr) -> Option<Alternative> {
+        let (start, steps) = match expr {
+            Expr::Path { start, steps } => (*start, steps),
+            expr => (expr, Vec::new()),
+        };
+
+        let root = match start {
+            Expr::ContextNode if !steps.is_empty() => Root::Anywhere,
+            Expr::RootNode => Root::Document,
+            Expr::Function { name, arguments } => Root::id_or_key(name, arguments)?,
+            _ => return None,
+        };
+
+        let default_priority = match (&root, &steps[..]) {
+            (&Root::Anywhere, [step]) if step.predicates.is_empty() => {
+                node_test_priority(&step.node_test)
+            }
+            _ => 0.5,
+        };
+
+        let mut step_patterns = Vec::with_capacity(steps.len());
+        let mut descendant = false;
+
+        for step in steps {
+            if step.axis == Axis::DescendantOrSelf
+                && step.node_test == ast::NodeTest::Node
+                && step.predicates.is_empty()
+                && !descendant
+            {
+                // The expanded form of `//`
+                descendant = true;
+                continue;
+            }
+
+            step_patterns.push(StepPattern::new(step, descendant)?);
+            descendant = false;
+        }
+
+        let starts_anywhere = match root {
+            Root::Anywhere => step_patterns.first().map_or(true, |s| s.descendant),
+            _ => false,
+        };
+
+        if descendant || starts_anywhere {
+            return None;
+        }
+
+        Some(Alternative {
+            root,
+            steps: step_patterns,
+            default_priority,
+        })
+    }
+
+    /// Checks if the node matches this alternative.
+    pub fn matches<'d, N>(&self, context: &Context<'d>, node: N) -> Result<bool, ExecutionError>
+    where
+        N: Into<Node<'d>>,
+    {
+        let node = node.into();
+        let _running = context.start_evaluation();
+        let evaluation = context::Evaluation::new(context, node);
+        self.matches_steps(&evaluation, &self.steps, node)
+            .map_err(ExecutionError)
+    }
+
+    /// The priority that XSLT gives to this alternative when the rule
+    /// does not specify one.
+    pub fn default_priority(&self) -> f64 {
+        self.default_priority
+    }
+
+    // Works from the last step towards the root, so only the
+    // ancestors of the node are ever visited.
+    fn matches_steps<'d>(
+        &self,
+        context: &context::Evaluation<'_, 'd>,
+        steps: &[StepPattern],
+        node: Node<'d>,
+    ) -> Result<bool, expression::Error> {
+        let (step, rest) = match steps.split_last() {
+            Some(x) => x,
+            None => return self.root.matches(context, node),
+        };
+
+        if !step.matches(context, node)? {
+            return Ok(false);
+        }
+
+        if let (Root::Anywhere, true) = (&self.root, rest.is_empty()) {
+            return Ok(true);
+        }
+
+        let mut parent = node.parent();
+        while let Some(p) = parent {
+            if self.matches_steps(context, rest, p)? {
+                return Ok(true);
+            }
+            if !step.descendant {
+                break;
+            }
+            parent = p.parent();
+        }
+
+        Ok(false)
+    }
+}
+
+fn node_test_priority(node_test: &ast::NodeTest) -> f64 {
+    match *node_test {
+        ast::NodeTest::Name(ref name) if name.local_part != "*" => 0.0,
+        ast::NodeTest::Name(ref name) if name.prefix.is_some() => -0.25,
+        ast::NodeTest::ProcessingInstruction(Some(_)) => 0.0,
+        _ => -0.5,
+    }
+}
+
+#[derive(Debug)]
+enum Root {
+    /// The path may start anywhere in the document
+    Anywhere,
+    /// The path starts at the root node
+    Document,
+    /// The path starts at one of the nodes selected by `id()` or `key()`
+    Nodes(SubExpression),
+}
+
+impl Root {
+    fn id_or_key(name: OwnedPrefixedName, arguments: Vec<Expr>) -> Option<Root> {
+        let literal_count = arguments
+            .iter()
+            .filter(|a| match **a {
+                Expr::Literal(Literal::String(_)) => true,
+                _ => false,
+            })
+            .count();
+
+        let expected = match (name.prefix.as_ref(), name.local_part.as_str()) {
+            (None, "id") => 1,
+            (None, "key") => 2,
+            _ => return None,
+        };
+
+        if arguments.len() != expected || literal_count != expected {
+            return None;
+        }
+
+        let expr = Expr::Function { name, arguments };
+        Some(Root::Nodes(expr.compile()))
+    }
+
+    fn matches<'d>(
+        &self,
+        context: &context::Evaluation<'_, 'd>,
+        node: Node<'d>,
+    ) -> Result<bool, expression::Error> {
+        match *self {
+            Root::Anywhere => Ok(true),
+            Root::Document => Ok(match node {
+                Node::Root(_) => true,
+                _ => false,
+            }),
+            Root::Nodes(ref expr) => {
+                let evaluation = context.new_context_for(node);
+                match expr.evaluate(&evaluation)? {
+                    Value::Nodeset(nodes) => Ok(nodes.contains(node)),
+                    _ => Err(expression::Error::NotANodeset),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StepPattern {
+    axis: Axis,
+    node_test: SubNodeTest,
+    /// Predicates may depend on the node's position among its
+    /// siblings, so the whole step is evaluated from the parent.
+    step: Option<expression::Step>,
+    /// Separated from the previous step by `//`
+    descendant: bool,
+}
+
+impl StepPattern {
+    fn new(step: ast::Step, descendant: bool) -> Option<StepPattern> {
+        match step.axis {
+            Axis::Child | Axis::Attribute => {}
+            _ => return None,
+        }
+
+        let node_test = step.node_test.clone().compile(step.axis);
+        let axis = step.axis;
+        let step = if step.predicates.is_empty() {
+            None
+        } else {
+            Some(step.compile())
+        };
+
+        Some(StepPattern {
+            axis,
+            node_test,
+            step,
+            descendant,
+        })
+    }
+
+    fn matches<'d>(
+        &self,
+        context: &context::Evaluation<'_, 'd>,
+        node: Node<'d>,
+    ) -> Result<bool, expression::Error> {
+        let on_axis = match (self.axis, node) {
+            (Axis::Attribute, Node::Attribute(_)) => true,
+            (Axis::Attribute, _) => false,
+            (_, Node::Attribute(_)) | (_, Node::Namespace(_)) => false,
+            _ => true,
+        };
+
+        let parent = match node.parent() {
+            Some(parent) if on_axis => parent,
+            _ => return Ok(false),
+        };
+
+        match self.step {
+            Some(ref step) => {
+                let evaluation = context.new_context_for(parent);
+                let selected = step.evaluate(&evaluation, nodeset![parent], None)?;
+                Ok(selected.contains(node))
+            }
+            None => {
+                let evaluation = context.new_context_for(node);
+                let mut result = OrderedNodes::new();
+                self.node_test.test(&evaluation, &mut result);
+                Ok(!Vec::from(result).is_empty())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sxd_document::{dom, parser, Package};
+
+    use crate::context::Context;
+    use crate::function::{self, Function};
+    use crate::nodeset::Nodeset;
+    use crate::{Factory, Value};
+
+    use super::*;
+
+    fn pattern(pattern: &str) -> Pattern {
+        Factory::new()
+            .build_pattern(pattern)
+            .expect("Unable to build pattern")
+    }
+
+    fn elements<'d>(doc: dom::Document<'d>) -> Vec<dom::Element<'d>> {
+        fn collect<'d>(element: dom::Element<'d>, all: &mut Vec<dom::Element<'d>>) {
+            all.push(element);
+            for child in element.children() {
+                if let Some(child) = child.element() {
+                    collect(child, all);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        for child in doc.root().children() {
+            if let Some(child) = child.element() {
+                collect(child, &mut all);
+            }
+        }
+        all
+    }
+
+    fn names_matching(pattern_str: &str, xml: &str) -> Vec<String> {
+        let package = parser::parse(xml).expect("Unable to parse XML");
+        let doc = package.as_document();
+        let pattern = pattern(pattern_str);
+        let context = Context::new();
+
+        elements(doc)
+            .into_iter()
+            .filter(|&e| pattern.matches(&context, e).expect("Unable to match"))
+            .map(|e| e.name().local_part().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn child_steps_match_from_the_node_upwards() {
+        let xml = "<a><b><c/></b><c/><d><b><c/></b></d></a>";
+        assert_eq!(names_matching("b/c", xml), ["c", "c"]);
+        assert_eq!(names_matching("a/c", xml), ["c"]);
+        assert_eq!(names_matching("/a", xml), ["a"]);
+        assert_eq!(names_matching("/b", xml), Vec::<String>::new());
+    }
+
+    #[test]
+    fn descendant_separators_search_all_ancestors() {
+        let xml = "<a><b><c/></b><d><e><c/></e></d></a>";
+        assert_eq!(names_matching("d//c", xml), ["c"]);
+        assert_eq!(names_matching("a//c", xml), ["c", "c"]);
+        assert_eq!(names_matching("//e/c", xml), ["c"]);
+    }
+
+    #[test]
+    fn predicates_use_the_position_among_siblings() {
+        let xml = "<a><b/><c/><b/><b x='1'/></a>";
+        let package = parser::parse(xml).expect("Unable to parse XML");
+        let doc = package.as_document();
+        let context = Context::new();
+
+        let children: Vec<_> = elements(doc)[1..].to_vec();
+        let matches = |p: &str| -> Vec<bool> {
+            let pattern = pattern(p);
+            children
+                .iter()
+                .map(|&e| pattern.matches(&context, e).unwrap())
+                .collect()
+        };
+
+        assert_eq!(matches("b[2]"), [false, false, true, false]);
+        assert_eq!(matches("a/b[last()]"), [false, false, false, true]);
+        assert_eq!(matches("*[@x]"), [false, false, false, true]);
+    }
+
+    #[test]
+    fn attributes_and_other_node_types_are_matched() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let a = doc.create_element("a");
+        let text = doc.create_text("t");
+        doc.root().append_child(a);
+        a.append_child(text);
+        let attr = a.set_attribute_value("x", "1");
+        let context = Context::new();
+
+        assert_eq!(Ok(true), pattern("@x").matches(&context, attr));
+        assert_eq!(Ok(true), pattern("a/@*").matches(&context, attr));
+        assert_eq!(Ok(false), pattern("x").matches(&context, attr));
+        assert_eq!(Ok(false), pattern("node()").matches(&context, attr));
+        assert_eq!(Ok(true), pattern("text()").matches(&context, text));
+        assert_eq!(Ok(true), pattern("/").matches(&context, doc.root()));
+        assert_eq!(Ok(false), pattern("node()").matches(&context, doc.root()));
+    }
+
+    #[derive(Debug)]
+    struct Key;
+
+    impl Function for Key {
+        fn evaluate<'c, 'd>(
+            &self,
+            context: &context::Evaluation<'c, 'd>,
+            args: Vec<Value<'d>>,
+        ) -> Result<Value<'d>, function::Error> {
+            let value = args[1].string();
+            let mut root = context.node;
+            while let Some(parent) = root.parent() {
+                root = parent;
+            }
+
+            let mut nodes = Nodeset::new();
+            for node in root.children() {
+                for child in node.children() {
+                    if child.string_value() == value {
+                        nodes.add(child);
+                    }
+                }
+            }
+            Ok(Value::Nodeset(nodes))
+        }
+    }
+
+    #[test]
+    fn key_roots_select_the_starting_nodes() {
+        let xml = "<a><b>1<c/></b><b>2<c/></b></a>";
+        let package = parser::parse(xml).expect("Unable to parse XML");
+        let doc = package.as_document();
+        let mut context = Context::new();
+        context.set_function("key", Key);
+
+        let all = elements(doc);
+        let pattern = pattern("key('k', '2')/c");
+        let matched: Vec<_> = all
+            .iter()
+            .map(|&e| pattern.matches(&context, e).unwrap())
+            .collect();
+
+        assert_eq!(matched, [false, false, false, false, true]);
+    }
+
+    #[test]
+    fn default_priorities_follow_the_step_shape() {
+        let priorities: Vec<_> = pattern(
+            "a | p:a | @a | processing-instruction('x') | p:* | * | node() | @* | a/b | a[1] | / | id('x')",
+        )
+        .alternatives()
+        .iter()
+        .map(Alternative::default_priority)
+        .collect();
+
+        assert_eq!(
+            priorities,
+            [0.0, 0.0, 0.0, 0.0, -0.25, -0.5, -0.5, -0.5, 0.5, 0.5, 0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn expressions_that_are_not_patterns_are_rejected() {
+        let factory = Factory::new();
+        for xpath in &[
+            "1",
+            "$a",
+            "a or b",
+            "..",
+            "a/..",
+            "ancestor::a",
+            "a//",
+            "count(a)",
+            "id($x)",
+            "key('k')",
+            "descendant-or-self::node()/a",
+            "(a)[1]",
+        ] {
+            assert!(
+                factory.build_pattern(xpath).is_err(),
+                "{} should not be a pattern",
+                xpath
+            );
+        }
+    }
+}
@@ -0,0 +1,634 @@
+//! Converts a compiled XPath back into XPath 1.0 source, or XPath 2.0
+//! source for the expressions only XPath 2.0 has.
+//!
+//! The output is canonical: axes are never abbreviated, operators are
+//! surrounded by a single space, and parentheses are only added where
+//! the parser would otherwise build a different tree.
+
+use crate::axis::Axis;
+use crate::expression::{
+    Expression, MathOperator, Predicate, Quantifier, RelationalOperator, Step, StepTest,
+    SubExpression,
+};
+use crate::visitor::{Visitable, Visitor};
+use crate::{LiteralValue, OwnedPrefixedName, Value};
+
+/// How tightly an expression binds, from loosest to tightest. An
+/// operand with a lower precedence than its position requires is
+/// wrapped in parentheses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    /// Only safe where commas cannot separate anything else, such as
+    /// the entire XPath or a predicate.
+    Sequence,
+    /// Only safe when the expression is delimited on both sides, such
+    /// as a function argument.
+    Delimited,
+    Or,
+    And,
+    Equality,
+    Relational,
+    Range,
+    Additive,
+    Multiplicative,
+    Unary,
+    Union,
+    IntersectExcept,
+    Path,
+    Primary,
+}
+
+impl Precedence {
+    fn tighter(self) -> Precedence {
+        use self::Precedence::*;
+        match self {
+            Sequence => Delimited,
+            Delimited => Or,
+            Or => And,
+            And => Equality,
+            Equality => Relational,
+            Relational => Range,
+            Range => Additive,
+            Additive => Multiplicative,
+            Multiplicative => Unary,
+            Unary => Union,
+            Union => IntersectExcept,
+            IntersectExcept => Path,
+            Path | Primary => Primary,
+        }
+    }
+}
+
+enum Printed {
+    RootNode,
+    ContextNode,
+    Text(String, Precedence),
+}
+
+impl Printed {
+    fn into_text(self) -> (String, Precedence) {
+        match self {
+            // A lone `/` followed by `*` or an operator name would
+            // treat them as a name test, so it only stays bare when
+            // nothing can follow it.
+            Printed::RootNode => ("/".to_string(), Precedence::Delimited),
+            Printed::ContextNode => ("self::node()".to_string(), Precedence::Path),
+            Printed::Text(text, precedence) => (text, precedence),
+        }
+    }
+}
+
+struct Printer(Printed);
+
+impl Printer {
+    fn output(&mut self, text: String, precedence: Precedence) {
+        self.0 = Printed::Text(text, precedence);
+    }
+
+    fn binary(
+        &mut self,
+        left: &SubExpression,
+        operator: &str,
+        right: &SubExpression,
+        precedence: Precedence,
+    ) {
+        let text = format!(
+            "{} {} {}",
+            operand(left, precedence),
+            operator,
+            operand(right, precedence.tighter())
+        );
+        self.output(text, precedence);
+    }
+}
+
+fn printed<V>(visitable: &V) -> Printed
+where
+    V: Visitable + ?Sized,
+{
+    let mut printer = Printer(Printed::Text(String::new(), Precedence::Primary));
+    visitable.visit(&mut printer);
+    printer.0
+}
+
+fn text<V>(visitable: &V) -> String
+where
+    V: Visitable + ?Sized,
+{
+    printed(visitable).into_text().0
+}
+
+fn parenthesize(text: String, precedence: Precedence, minimum: Precedence) -> String {
+    if precedence < minimum {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn operand(expression: &SubExpression, minimum: Precedence) -> String {
+    let (text, precedence) = printed(expression).into_text();
+    parenthesize(text, precedence, minimum)
+}
+
+fn predicate(predicate: &Predicate) -> String {
+    format!("[{}]", operand(&predicate.expression, Precedence::Sequence))
+}
+
+fn name_test(prefix: Option<&str>, local_part: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, local_part),
+        None => local_part.to_string(),
+    }
+}
+
+fn string_literal(value: &str) -> String {
+    if !value.contains('\'') {
+        format!("'{}'", value)
+    } else if !value.contains('"') {
+        format!("\"{}\"", value)
+    } else {
+        // XPath 1.0 literals cannot contain both kinds of quote
+        let pieces: Vec<_> = value
+            .split('\'')
+            .map(|piece| format!("'{}'", piece))
+            .collect();
+        format!("concat({})", pieces.join(", \"'\", "))
+    }
+}
+
+fn number_literal(value: f64) -> (String, Precedence) {
+    if value.is_nan() {
+        ("0 div 0".to_string(), Precedence::Multiplicative)
+    } else if value.is_infinite() {
+        let sign = if value < 0.0 { "-" } else { "" };
+        (format!("{}1 div 0", sign), Precedence::Multiplicative)
+    } else if value.is_sign_negative() {
+        (format!("-{}", -value), Precedence::Unary)
+    } else {
+        (value.to_string(), Precedence::Primary)
+    }
+}
+
+/// Produces XPath source that compiles back into an equivalent
+/// expression.
+pub fn print(xpath: &dyn Expression) -> String {
+    text(xpath)
+}
+
+impl Visitor for Printer {
+    fn visit_and(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "and", right, Precedence::And);
+    }
+
+    fn visit_attribute(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.output(name_test(prefix, local_part), Precedence::Primary);
+    }
+
+    fn visit_comment(&mut self) {
+        self.output("comment()".to_string(), Precedence::Primary);
+    }
+
+    fn visit_conditional(
+        &mut self,
+        condition: &SubExpression,
+        then: &SubExpression,
+        otherwise: &SubExpression,
+    ) {
+        // Only the `else` branch extends to the end of the expression
+        let text = format!(
+            "if ({}) then {} else {}",
+            operand(condition, Precedence::Delimited),
+            operand(then, Precedence::Or),
+            operand(otherwise, Precedence::Delimited)
+        );
+        self.output(text, Precedence::Delimited);
+    }
+
+    fn visit_context_node(&mut self) {
+        self.0 = Printed::ContextNode;
+    }
+
+    fn visit_element(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.output(name_test(prefix, local_part), Precedence::Primary);
+    }
+
+    fn visit_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "=", right, Precedence::Equality);
+    }
+
+    fn visit_except(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "except", right, Precedence::IntersectExcept);
+    }
+
+    fn visit_filter(&mut self, node_selector: &SubExpression, predicate: &Predicate) {
+        let text = format!(
+            "{}{}",
+            operand(node_selector, Precedence::Primary),
+            self::predicate(predicate)
+        );
+        self.output(text, Precedence::Primary);
+    }
+
+    fn visit_for(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        body: &SubExpression,
+    ) {
+        let text = format!(
+            "for ${} in {} return {}",
+            variable,
+            operand(sequence, Precedence::Or),
+            operand(body, Precedence::Delimited)
+        );
+        self.output(text, Precedence::Delimited);
+    }
+
+    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        let arguments: Vec<_> = arguments
+            .iter()
+            .map(|argument| operand(argument, Precedence::Delimited))
+            .collect();
+        let text = format!("{}({})", name, arguments.join(", "));
+        self.output(text, Precedence::Primary);
+    }
+
+    fn visit_intersect(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "intersect", right, Precedence::IntersectExcept);
+    }
+
+    fn visit_let(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        value: &SubExpression,
+        body: &SubExpression,
+    ) {
+        let text = format!(
+            "let ${} := {} return {}",
+            variable,
+            operand(value, Precedence::Or),
+            operand(body, Precedence::Delimited)
+        );
+        self.output(text, Precedence::Delimited);
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue) {
+        let (text, precedence) = match *value {
+            Value::Boolean(true) => ("true()".to_string(), Precedence::Primary),
+            Value::Boolean(false) => ("false()".to_string(), Precedence::Primary),
+            Value::Number(n) => number_literal(n),
+            Value::String(ref s) => (string_literal(s), Precedence::Primary),
+            // Only an empty nodeset can outlive every document
+            Value::Nodeset(_) => ("/parent::node()".to_string(), Precedence::Path),
+            Value::Sequence(_) => unreachable!("Literals are never sequences"),
+        };
+        self.output(text, precedence);
+    }
+
+    fn visit_math(&mut self, left: &SubExpression, right: &SubExpression, operator: MathOperator) {
+        let (operator, precedence) = match operator {
+            MathOperator::Addition => ("+", Precedence::Additive),
+            MathOperator::Subtraction => ("-", Precedence::Additive),
+            MathOperator::Multiplication => ("*", Precedence::Multiplicative),
+            MathOperator::Division => ("div", Precedence::Multiplicative),
+            MathOperator::Remainder => ("mod", Precedence::Multiplicative),
+        };
+        self.binary(left, operator, right, precedence);
+    }
+
+    fn visit_namespace(&mut self, prefix: Option<&str>, local_part: &str) {
+        self.output(name_test(prefix, local_part), Precedence::Primary);
+    }
+
+    fn visit_node(&mut self) {
+        self.output("node()".to_string(), Precedence::Primary);
+    }
+
+    fn visit_negation(&mut self, expression: &SubExpression) {
+        let text = format!("-{}", operand(expression, Precedence::Unary));
+        self.output(text, Precedence::Unary);
+    }
+
+    fn visit_not_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "!=", right, Precedence::Equality);
+    }
+
+    fn visit_or(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "or", right, Precedence::Or);
+    }
+
+    fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]) {
+        let steps: Vec<_> = steps.iter().map(text).collect();
+        let steps = steps.join("/");
+
+        match printed(start_point) {
+            Printed::RootNode if steps.is_empty() => self.0 = Printed::RootNode,
+            Printed::RootNode => self.output(format!("/{}", steps), Precedence::Path),
+            Printed::ContextNode if steps.is_empty() => self.0 = Printed::ContextNode,
+            Printed::ContextNode => self.output(steps, Precedence::Path),
+            Printed::Text(text, precedence) if steps.is_empty() => self.output(text, precedence),
+            Printed::Text(text, precedence) => {
+                let start_point = parenthesize(text, precedence, Precedence::Primary);
+                self.output(format!("{}/{}", start_point, steps), Precedence::Path);
+            }
+        }
+    }
+
+    fn visit_processing_instruction(&mut self, target: Option<&str>) {
+        let target = target.map(string_literal).unwrap_or_default();
+        let text = format!("processing-instruction({})", target);
+        self.output(text, Precedence::Primary);
+    }
+
+    fn visit_quantified(
+        &mut self,
+        quantifier: Quantifier,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        condition: &SubExpression,
+    ) {
+        let quantifier = match quantifier {
+            Quantifier::Some => "some",
+            Quantifier::Every => "every",
+        };
+        let text = format!(
+            "{} ${} in {} satisfies {}",
+            quantifier,
+            variable,
+            operand(sequence, Precedence::Or),
+            operand(condition, Precedence::Delimited)
+        );
+        self.output(text, Precedence::Delimited);
+    }
+
+    fn visit_range(&mut self, left: &SubExpression, right: &SubExpression) {
+        // Ranges cannot be chained, so neither side may be a range
+        let text = format!(
+            "{} to {}",
+            operand(left, Precedence::Additive),
+            operand(right, Precedence::Additive)
+        );
+        self.output(text, Precedence::Range);
+    }
+
+    fn visit_relational(
+        &mut self,
+        left: &SubExpression,
+        right: &SubExpression,
+        operator: RelationalOperator,
+    ) {
+        let operator = match operator {
+            RelationalOperator::LessThan => "<",
+            RelationalOperator::LessThanOrEqual => "<=",
+            RelationalOperator::GreaterThan => ">",
+            RelationalOperator::GreaterThanOrEqual => ">=",
+        };
+        self.binary(left, operator, right, Precedence::Relational);
+    }
+
+    fn visit_root_node(&mut self) {
+        self.0 = Printed::RootNode;
+    }
+
+    fn visit_sequence(&mut self, expressions: &[SubExpression]) {
+        let mut items: Vec<_> = expressions
+            .iter()
+            .map(|expression| operand(expression, Precedence::Delimited))
+            .collect();
+        match items.len() {
+            0 => return self.output("()".to_string(), Precedence::Primary),
+            // A lone expression in parentheses is not a sequence
+            1 => items.push("()".to_string()),
+            _ => {}
+        }
+        self.output(items.join(", "), Precedence::Sequence);
+    }
+
+    fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
+        let mut text = format!("{}::{}", axis, self::text(node_test));
+        for p in predicates {
+            text.push_str(&predicate(p));
+        }
+        self.output(text, Precedence::Primary);
+    }
+
+    fn visit_text(&mut self) {
+        self.output("text()".to_string(), Precedence::Primary);
+    }
+
+    fn visit_union(&mut self, left: &SubExpression, right: &SubExpression) {
+        self.binary(left, "|", right, Precedence::Union);
+    }
+
+    fn visit_variable(&mut self, name: &OwnedPrefixedName) {
+        self.output(format!("${}", name), Precedence::Primary);
+    }
+
+    fn visit_xpath(&mut self, xpath: &Box<dyn Expression + 'static>) {
+        xpath.visit(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::Expr;
+    use crate::{Factory, LanguageLevel, XPath};
+
+    use super::{number_literal, string_literal, Precedence};
+
+    fn canonical(xpath: &str) -> String {
+        canonical_at(LanguageLevel::XPath1, xpath)
+    }
+
+    /// Prints the XPath as written, without the simplifications of
+    /// `Factory::build`.
+    fn canonical_at(language_level: LanguageLevel, xpath: &str) -> String {
+        let mut factory = Factory::new();
+        factory.set_language_level(language_level);
+        let compile = |xpath: &str| factory.parse(xpath).map(XPath::from);
+        let compiled = compile(xpath).expect("Unable to compile XPath");
+        let printed = compiled.to_string();
+
+        let recompiled =
+            compile(&printed).unwrap_or_else(|e| panic!("Unable to reparse {:?}: {}", printed, e));
+        assert_eq!(format!("{:?}", compiled), format!("{:?}", recompiled));
+        assert_eq!(printed, recompiled.to_string());
+
+        printed
+    }
+
+    #[test]
+    fn abbreviations_are_expanded() {
+        assert_eq!(
+            canonical("//a/@b"),
+            "/descendant-or-self::node()/child::a/attribute::b"
+        );
+        assert_eq!(canonical("../."), "parent::node()/self::node()");
+    }
+
+    #[test]
+    fn node_tests_are_printed() {
+        assert_eq!(
+            canonical("ns:*/text() | comment() | processing-instruction('x')"),
+            "child::ns:*/child::text() | child::comment() | child::processing-instruction('x')"
+        );
+        assert_eq!(canonical("namespace::*"), "namespace::*");
+    }
+
+    #[test]
+    fn operators_keep_their_grouping() {
+        assert_eq!(canonical("1 - (2 - 3)"), "1 - (2 - 3)");
+        assert_eq!(canonical("(1 - 2) - 3"), "1 - 2 - 3");
+        assert_eq!(canonical("(1 + 2) * 3 mod 4"), "(1 + 2) * 3 mod 4");
+        assert_eq!(canonical("1 < 2 = (3 >= 4)"), "1 < 2 = 3 >= 4");
+        assert_eq!(canonical("1 < (2 = 3)"), "1 < (2 = 3)");
+        assert_eq!(
+            canonical("$a or $b and ($c or $d)"),
+            "$a or $b and ($c or $d)"
+        );
+        assert_eq!(canonical("--1 != -(a | b)"), "--1 != -child::a | child::b");
+    }
+
+    #[test]
+    fn filters_and_paths_are_parenthesized() {
+        assert_eq!(canonical("(a/b)[1]/c"), "(child::a/child::b)[1]/child::c");
+        assert_eq!(
+            canonical("$x[1][2]//y"),
+            "$x[1][2]/descendant-or-self::node()/child::y"
+        );
+        assert_eq!(canonical("(/)[1]"), "(/)[1]");
+    }
+
+    #[test]
+    fn root_node_is_parenthesized_when_an_operator_follows() {
+        assert_eq!(canonical("/"), "/");
+        assert_eq!(canonical("(/) * 2"), "(/) * 2");
+        assert_eq!(canonical("(/) or count(/)"), "(/) or count(/)");
+    }
+
+    #[test]
+    fn conditionals_are_parenthesized_unless_delimited() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath2, xpath);
+
+        assert_eq!(
+            canonical("if (a or b) then 1 + 2 else /"),
+            "if (child::a or child::b) then 1 + 2 else /"
+        );
+        assert_eq!(
+            canonical("(if ($a) then 1 else 2) * 3"),
+            "(if ($a) then 1 else 2) * 3"
+        );
+        assert_eq!(
+            canonical("if ($a) then (/) else if ($b) then $c else $d"),
+            "if ($a) then (/) else if ($b) then $c else $d"
+        );
+        assert_eq!(
+            canonical("f(if ($a) then (if ($b) then 1 else 2) else 3)"),
+            "f(if ($a) then (if ($b) then 1 else 2) else 3)"
+        );
+    }
+
+    #[test]
+    fn quantified_expressions_are_parenthesized_unless_delimited() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath2, xpath);
+
+        assert_eq!(
+            canonical("some $a in b, $c in (/) satisfies $a = $c"),
+            "some $a in child::b satisfies some $c in (/) satisfies $a = $c"
+        );
+        assert_eq!(
+            canonical("(every $a in b satisfies $a) or $c"),
+            "(every $a in child::b satisfies $a) or $c"
+        );
+    }
+
+    #[test]
+    fn sequences_are_parenthesized_unless_delimited() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath2, xpath);
+
+        assert_eq!(
+            canonical("a, (b, c)[1], f((1, ()))"),
+            "child::a, (child::b, child::c)[1], f((1, ()))"
+        );
+        assert_eq!(canonical("x[1, 2]"), "child::x[1, 2]");
+        assert_eq!(
+            canonical("if ($a) then (1, 2) else ()"),
+            "if ($a) then (1, 2) else ()"
+        );
+
+        let single = XPath::from(Expr::Sequence(vec![Expr::Variable("a".into())]));
+        assert_eq!(single.to_string(), "$a, ()");
+    }
+
+    #[test]
+    fn ranges_bind_between_comparisons_and_addition() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath2, xpath);
+
+        assert_eq!(canonical("(1 to 2 + 3) = 2"), "1 to 2 + 3 = 2");
+        assert_eq!(canonical("(1 to 2) + 3"), "(1 to 2) + 3");
+        assert_eq!(canonical("(1 to 2) to 3"), "(1 to 2) to 3");
+    }
+
+    #[test]
+    fn set_operators_bind_tighter_than_union() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath2, xpath);
+
+        assert_eq!(
+            canonical("a | (b except c)"),
+            "child::a | child::b except child::c"
+        );
+        assert_eq!(
+            canonical("(a | b) intersect c"),
+            "(child::a | child::b) intersect child::c"
+        );
+        assert_eq!(
+            canonical("a except (b intersect c)"),
+            "child::a except (child::b intersect child::c)"
+        );
+    }
+
+    #[test]
+    fn bindings_are_nested() {
+        let canonical = |xpath| canonical_at(LanguageLevel::XPath3, xpath);
+
+        assert_eq!(
+            canonical("for $a in b, $c in $a/d return $c"),
+            "for $a in child::b return for $c in $a/child::d return $c"
+        );
+        assert_eq!(
+            canonical("let $a := (/), $b := 1 return (let $c := $a return $c) | $b"),
+            "let $a := (/) return let $b := 1 return (let $c := $a return $c) | $b"
+        );
+    }
+
+    #[test]
+    fn literals_are_quoted() {
+        assert_eq!(canonical("'a\"b'"), "'a\"b'");
+        assert_eq!(canonical("\"a'b\""), "\"a'b\"");
+        assert_eq!(canonical("1.50 + .5"), "1.5 + 0.5");
+    }
+
+    #[test]
+    fn functions_and_variables_keep_their_prefixes() {
+        assert_eq!(canonical("ns:f($p:v, 2)"), "ns:f($p:v, 2)");
+    }
+
+    #[test]
+    fn strings_with_both_quotes_are_concatenated() {
+        assert_eq!(
+            string_literal("it's \"here\""),
+            "concat('it', \"'\", 's \"here\"')"
+        );
+    }
+
+    #[test]
+    fn numbers_without_a_literal_form_are_computed() {
+        assert_eq!(
+            number_literal(-2.5),
+            ("-2.5".to_string(), Precedence::Unary)
+        );
+        assert_eq!(number_literal(::std::f64::NAN).0, "0 div 0");
+        assert_eq!(number_literal(::std::f64::NEG_INFINITY).0, "-1 div 0");
+    }
+}
@@ -31,18 +31,27 @@ pub enum NodeTestName {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     And,
+    Assign,
     AtSign,
     Comma,
     CurrentNode,
     Divide,
     DoubleSlash,
+    Else,
     Equal,
+    Every,
+    Except,
+    For,
     GreaterThan,
     GreaterThanOrEqual,
+    If,
+    In,
+    Intersect,
     LeftBracket,
     LeftParen,
     LessThan,
     LessThanOrEqual,
+    Let,
     Literal(string::String),
     MinusSign,
     Multiply,
@@ -54,8 +63,13 @@ pub enum Token {
     PlusSign,
     Remainder,
     RightBracket,
+    Return,
     RightParen,
+    Satisfies,
     Slash,
+    Some,
+    Then,
+    To,
 
     // Specializations
     Axis(AxisName),
@@ -75,7 +89,20 @@ impl Token {
 
     pub fn precedes_expression(&self) -> bool {
         match *self {
-            Token::LeftParen | Token::LeftBracket => true,
+            Token::LeftParen
+            | Token::LeftBracket
+            | Token::Comma
+            | Token::If
+            | Token::Then
+            | Token::Else
+            | Token::Some
+            | Token::Every
+            | Token::In
+            | Token::Satisfies
+            | Token::For
+            | Token::Let
+            | Token::Assign
+            | Token::Return => true,
             _ => false,
         }
     }
@@ -97,7 +124,10 @@ impl Token {
             | Token::Or
             | Token::Remainder
             | Token::Divide
-            | Token::Multiply => true,
+            | Token::Multiply
+            | Token::To
+            | Token::Intersect
+            | Token::Except => true,
             _ => false,
         }
     }
@@ -4,20 +4,25 @@ use std::borrow::ToOwned;
 use std::collections::VecDeque;
 use std::string;
 use sxd_document::parser::XmlParseExt;
+use sxd_document::XmlChar;
 
 use crate::node_test;
 use crate::token::{AxisName, NodeTestName, Token};
+use crate::{LanguageLevel, Span};
 
 pub struct Tokenizer {
     xpath: string::String,
     start: usize,
     prefer_recognition_of_operator_names: bool,
+    language_level: LanguageLevel,
 }
 
 type XPathMaster<'a> = ParseMaster<StringPoint<'a>, Error>;
 type XPathProgress<'a, T, E> = peresil::Progress<StringPoint<'a>, T, E>;
 
-pub type TokenResult = Result<Token, Error>;
+/// A token, or the reason one could not be created, along with the
+/// region of the XPath it was found in.
+pub type TokenResult = Result<(Span, Token), (Span, Error)>;
 
 #[derive(Debug, Snafu, Copy, Clone, PartialEq)]
 #[cfg_attr(test, snafu(visibility(pub(crate))))]
@@ -30,6 +35,8 @@ pub enum Error {
     ExpectedCurrentNode,
     /// expected a named operator
     ExpectedNamedOperator,
+    /// expected a keyword
+    ExpectedKeyword,
     /// expected an axis name
     ExpectedAxis,
     /// expected an axis separator
@@ -87,7 +94,7 @@ trait XPathParseExt<'a> {
 
 impl<'a> XPathParseExt<'a> for StringPoint<'a> {
     fn consume_quoted_string(&self, quote: &str) -> XPathProgress<'a, &'a str, ()> {
-        let end_of_str = self.s.find(quote).or_else(|| Some(self.s.len()));
+        let end_of_str = self.s.find(quote).or(Some(self.s.len()));
         self.consume_to(end_of_str)
     }
 }
@@ -124,6 +131,30 @@ static NAMED_OPERATORS: [Identifier<'static, Token>; 5] = [
     ("*", Token::Multiply),
 ];
 
+// Each is paired with the first language level that has it.
+// These will be matched in order, so substrings should come later.
+static KEYWORDS: [Identifier<'static, (Token, LanguageLevel)>; 9] = [
+    ("then", (Token::Then, LanguageLevel::XPath2)),
+    ("else", (Token::Else, LanguageLevel::XPath2)),
+    ("intersect", (Token::Intersect, LanguageLevel::XPath2)),
+    ("in", (Token::In, LanguageLevel::XPath2)),
+    ("satisfies", (Token::Satisfies, LanguageLevel::XPath2)),
+    ("return", (Token::Return, LanguageLevel::XPath2)),
+    ("to", (Token::To, LanguageLevel::XPath2)),
+    ("except", (Token::Except, LanguageLevel::XPath2)),
+    (":=", (Token::Assign, LanguageLevel::XPath3)),
+];
+
+// Each is also paired with what must follow it to tell it apart from a
+// name.
+static OPENING_KEYWORDS: [Identifier<'static, (Token, &'static str, LanguageLevel)>; 5] = [
+    ("if", (Token::If, "(", LanguageLevel::XPath2)),
+    ("some", (Token::Some, "$", LanguageLevel::XPath2)),
+    ("every", (Token::Every, "$", LanguageLevel::XPath2)),
+    ("for", (Token::For, "$", LanguageLevel::XPath2)),
+    ("let", (Token::Let, "$", LanguageLevel::XPath3)),
+];
+
 // These will be matched in order, so substrings should come later.
 static AXES: [Identifier<'static, AxisName>; 13] = [
     ("ancestor-or-self", AxisName::AncestorOrSelf),
@@ -236,6 +267,47 @@ fn parse_named_operators(
     }
 }
 
+/// Keywords are recognized where the names of operators are, except
+/// for those that begin an expression, such as `if`, which are told
+/// apart from names by what follows them.
+fn parse_keyword(
+    p: StringPoint<'_>,
+    language_level: LanguageLevel,
+    prefer_named_ops: bool,
+) -> XPathProgress<'_, Token, Error> {
+    fn keyword(p: StringPoint<'_>, language_level: LanguageLevel) -> XPathProgress<'_, Token, ()> {
+        let start = p;
+        let (p, (token, level)) = try_parse!(p.consume_identifier(&KEYWORDS));
+        if language_level < level {
+            return peresil::Progress::failure(start, ());
+        }
+
+        peresil::Progress::success(p, token)
+    }
+
+    fn opening_keyword(
+        p: StringPoint<'_>,
+        language_level: LanguageLevel,
+    ) -> XPathProgress<'_, Token, ()> {
+        let start = p;
+        let (p, (token, follower, level)) = try_parse!(p.consume_identifier(&OPENING_KEYWORDS));
+        if language_level < level {
+            return peresil::Progress::failure(start, ());
+        }
+        // Do not advance the point past what follows
+        let (after_space, _) = p.consume_space().optional(p);
+        try_parse!(after_space.consume_literal(follower));
+
+        peresil::Progress::success(p, token)
+    }
+
+    if prefer_named_ops {
+        keyword(p, language_level).context(ExpectedKeyword)
+    } else {
+        opening_keyword(p, language_level).context(ExpectedKeyword)
+    }
+}
+
 fn parse_axis_specifier(p: StringPoint<'_>) -> XPathProgress<'_, Token, Error> {
     // Ideally, we would check for the pair of the name and the ::,
     // then loop. This would prevent us from having to order AXES.
@@ -330,11 +402,17 @@ fn parse_variable_reference(p: StringPoint<'_>) -> XPathProgress<'_, Token, Erro
 }
 
 impl Tokenizer {
+    #[cfg(test)]
     pub fn new(xpath: &str) -> Tokenizer {
+        Tokenizer::with_language_level(xpath, LanguageLevel::XPath1)
+    }
+
+    pub fn with_language_level(xpath: &str, language_level: LanguageLevel) -> Tokenizer {
         Tokenizer {
             xpath: xpath.to_owned(),
             start: 0,
             prefer_recognition_of_operator_names: false,
+            language_level,
         }
     }
 
@@ -346,8 +424,9 @@ impl Tokenizer {
         &self,
         pm: &mut XPathMaster<'a>,
         p: StringPoint<'a>,
-    ) -> XPathProgress<'a, Token, Error> {
+    ) -> XPathProgress<'a, (Span, Token), Error> {
         let (p, _) = p.consume_space().optional(p);
+        let start = p.offset;
 
         let (p, tok) = try_parse!({
             pm.alternate()
@@ -363,6 +442,13 @@ impl Tokenizer {
                 .one(|pm| parse_number(pm, p))
                 .one(|_| parse_current_node(p))
                 .one(|_| parse_named_operators(p, self.prefer_recognition_of_operator_names))
+                .one(|_| {
+                    parse_keyword(
+                        p,
+                        self.language_level,
+                        self.prefer_recognition_of_operator_names,
+                    )
+                })
                 .one(|_| parse_axis_specifier(p))
                 .one(|pm| parse_node_type(pm, p))
                 .one(|_| parse_function_call(p))
@@ -370,10 +456,23 @@ impl Tokenizer {
                 .one(|_| parse_variable_reference(p))
                 .finish()
         });
+        let span = Span::new(start, p.offset);
 
         let (p, _) = p.consume_space().optional(p);
 
-        peresil::Progress::success(p, tok)
+        peresil::Progress::success(p, (span, tok))
+    }
+
+    /// The region to blame when no token could be created: from the
+    /// first non-space character up to where tokenizing gave up, but
+    /// always covering at least one character.
+    fn error_span(&self, failed_at: usize) -> Span {
+        let remaining = &self.xpath[self.start..];
+        let trimmed = remaining.trim_start_matches(XmlChar::is_space_char);
+        let start = self.start + (remaining.len() - trimmed.len());
+        let first_char = trimmed.chars().next().map_or(0, char::len_utf8);
+
+        Span::new(start, failed_at.max(start + first_char))
     }
 
     fn raw_next_token(&mut self) -> TokenResult {
@@ -396,18 +495,19 @@ impl Tokenizer {
                 status: peresil::Status::Failure(mut e),
                 point,
             } => {
+                let span = self.error_span(point.offset);
                 if point.offset == self.start {
-                    UnableToCreateToken.fail()
+                    Err((span, Error::UnableToCreateToken))
                 } else {
                     // Should always have one error, otherwise we wouldn't be here!
-                    Err(e.pop().expect("Unknown error while parsing"))
+                    Err((span, e.pop().expect("Unknown error while parsing")))
                 }
             }
         }
     }
 
     fn next_token(&mut self) -> TokenResult {
-        let token = self.raw_next_token()?;
+        let (span, token) = self.raw_next_token()?;
 
         if !(token.precedes_node_test() || token.precedes_expression() || token.is_operator()) {
             // See http://www.w3.org/TR/xpath/#exprlex
@@ -416,7 +516,7 @@ impl Tokenizer {
             self.prefer_recognition_of_operator_names = false;
         }
 
-        Ok(token)
+        Ok((span, token))
     }
 }
 
@@ -434,14 +534,16 @@ impl Iterator for Tokenizer {
 
 pub struct TokenDeabbreviator<I> {
     source: I,
-    buffer: VecDeque<Token>,
+    buffer: VecDeque<(Span, Token)>,
 }
 
 // Avoid adding the first element to the buffer, but keep the
-// expansion values nicely co-located.
+// expansion values nicely co-located. Every expanded token is
+// attributed to the span of the abbreviation.
 macro_rules! deabbrev {
-    ($this:expr, $head:expr $(, $tail:expr)*) => {{
-        $this.buffer.extend([$( $tail, )*].iter().cloned());
+    ($this:expr, $span:expr, $head:expr $(, $tail:expr)*) => {{
+        let span = $span;
+        $this.buffer.extend([$( $tail, )*].iter().cloned().map(|t| (span, t)));
         $head
     }}
 }
@@ -454,11 +556,12 @@ impl<I> TokenDeabbreviator<I> {
         }
     }
 
-    fn expand_token(&mut self, token: Token) -> Token {
+    fn expand_token(&mut self, span: Span, token: Token) -> Token {
         match token {
-            Token::AtSign => deabbrev!(self, Token::Axis(AxisName::Attribute)),
+            Token::AtSign => deabbrev!(self, span, Token::Axis(AxisName::Attribute)),
             Token::DoubleSlash => deabbrev!(
                 self,
+                span,
                 Token::Slash,
                 Token::Axis(AxisName::DescendantOrSelf),
                 Token::NodeTest(NodeTestName::Node),
@@ -466,11 +569,13 @@ impl<I> TokenDeabbreviator<I> {
             ),
             Token::CurrentNode => deabbrev!(
                 self,
+                span,
                 Token::Axis(AxisName::SelfAxis),
                 Token::NodeTest(NodeTestName::Node)
             ),
             Token::ParentNode => deabbrev!(
                 self,
+                span,
                 Token::Axis(AxisName::Parent),
                 Token::NodeTest(NodeTestName::Node)
             ),
@@ -494,7 +599,7 @@ where
 
         match token {
             None | Some(Err(_)) => token,
-            Some(Ok(token)) => Some(Ok(self.expand_token(token))),
+            Some(Ok((span, token))) => Some(Ok((span, self.expand_token(span, token)))),
         }
     }
 }
@@ -505,6 +610,7 @@ mod test {
 
     use crate::node_test;
     use crate::token::{AxisName, NodeTestName, Token};
+    use crate::{LanguageLevel, Span};
 
     use super::Error::*;
     use super::{Error, TokenDeabbreviator, TokenResult, Tokenizer};
@@ -517,7 +623,28 @@ mod test {
     where
         I: Iterator<Item = TokenResult>,
     {
-        tokenizer.collect()
+        tokenizer
+            .map(|r| r.map(|(_, t)| t).map_err(|(_, e)| e))
+            .collect()
+    }
+
+    fn all_spans<I>(tokenizer: I) -> Vec<Span>
+    where
+        I: Iterator<Item = TokenResult>,
+    {
+        tokenizer
+            .map(|r| match r {
+                Ok((span, _)) => span,
+                Err((_, e)) => panic!("{:?}", e),
+            })
+            .collect()
+    }
+
+    fn unspanned(tokens: Vec<Token>) -> Vec<TokenResult> {
+        tokens
+            .into_iter()
+            .map(|t| Ok((Span::default(), t)))
+            .collect()
     }
 
     fn all_tokens<I>(tokenizer: I) -> Vec<Token>
@@ -946,6 +1073,140 @@ mod test {
         );
     }
 
+    #[test]
+    fn tokenizes_conditional_keywords() {
+        let tokenizer =
+            Tokenizer::with_language_level("if ($a) then then else if()", LanguageLevel::XPath2);
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                Token::If,
+                Token::LeftParen,
+                Token::Variable("a".into()),
+                Token::RightParen,
+                Token::Then,
+                name_test("then"),
+                Token::Else,
+                Token::If,
+                Token::LeftParen,
+                Token::RightParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_quantified_keywords() {
+        let tokenizer = Tokenizer::with_language_level(
+            "some $a in in satisfies every$b",
+            LanguageLevel::XPath2,
+        );
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                Token::Some,
+                Token::Variable("a".into()),
+                Token::In,
+                name_test("in"),
+                Token::Satisfies,
+                Token::Every,
+                Token::Variable("b".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_binding_keywords() {
+        let tokenizer = Tokenizer::with_language_level(
+            "for $a in b return let $c := $a return $c",
+            LanguageLevel::XPath3,
+        );
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                Token::For,
+                Token::Variable("a".into()),
+                Token::In,
+                name_test("b"),
+                Token::Return,
+                Token::Let,
+                Token::Variable("c".into()),
+                Token::Assign,
+                Token::Variable("a".into()),
+                Token::Return,
+                Token::Variable("c".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_range_keyword_and_expressions_after_commas() {
+        let tokenizer =
+            Tokenizer::with_language_level("1 to to, if ($a) then 2 else 3", LanguageLevel::XPath2);
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                Token::Number(1.0),
+                Token::To,
+                name_test("to"),
+                Token::Comma,
+                Token::If,
+                Token::LeftParen,
+                Token::Variable("a".into()),
+                Token::RightParen,
+                Token::Then,
+                Token::Number(2.0),
+                Token::Else,
+                Token::Number(3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_set_operators_after_names() {
+        let tokenizer =
+            Tokenizer::with_language_level("a intersect except except b", LanguageLevel::XPath2);
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                name_test("a"),
+                Token::Intersect,
+                name_test("except"),
+                Token::Except,
+                name_test("b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn let_is_a_name_before_xpath_3() {
+        let tokenizer = Tokenizer::with_language_level("let $c", LanguageLevel::XPath2);
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![name_test("let"), Token::Variable("c".into())]
+        );
+    }
+
+    #[test]
+    fn conditional_keywords_are_names_in_xpath_1() {
+        let tokenizer = Tokenizer::new("if(then)");
+
+        assert_eq!(
+            all_tokens(tokenizer),
+            vec![
+                Token::Function("if".into()),
+                Token::LeftParen,
+                name_test("then"),
+                Token::RightParen,
+            ]
+        );
+    }
+
     #[test]
     fn tokenizes_function_call_with_prefixed_name() {
         let tokenizer = Tokenizer::new("ns:hello()");
@@ -984,9 +1245,57 @@ mod test {
         assert_eq!(Err(MismatchedQuoteCharacters), res);
     }
 
+    #[test]
+    fn tokens_know_their_location_without_surrounding_whitespace() {
+        let tokenizer = Tokenizer::new(" hello / 'world' ");
+
+        assert_eq!(
+            all_spans(tokenizer),
+            vec![Span::new(1, 6), Span::new(7, 8), Span::new(9, 16)]
+        );
+    }
+
+    #[test]
+    fn errors_know_their_location() {
+        let mut tokenizer = Tokenizer::new("a | !");
+
+        tokenizer.next();
+        tokenizer.next();
+        let res = tokenizer.next();
+
+        assert_eq!(Some(Err((Span::new(4, 5), UnableToCreateToken))), res);
+    }
+
+    #[test]
+    fn mismatched_quote_error_covers_the_literal() {
+        let tokenizer = Tokenizer::new("a = 'hello\"");
+        let res: Result<Vec<_>, _> = tokenizer.collect();
+
+        assert_eq!(Err((Span::new(4, 11), MismatchedQuoteCharacters)), res);
+    }
+
+    #[test]
+    fn expanded_abbreviations_share_the_location_of_the_abbreviation() {
+        let tokenizer = Tokenizer::new("a//b");
+
+        let deabbrv = TokenDeabbreviator::new(tokenizer);
+
+        assert_eq!(
+            all_spans(deabbrv),
+            vec![
+                Span::new(0, 1),
+                Span::new(1, 3),
+                Span::new(1, 3),
+                Span::new(1, 3),
+                Span::new(1, 3),
+                Span::new(3, 4)
+            ]
+        );
+    }
+
     #[test]
     fn converts_at_sign_to_attribute_axis() {
-        let input_tokens: Vec<TokenResult> = vec![Ok(Token::AtSign)];
+        let input_tokens = unspanned(vec![Token::AtSign]);
 
         let deabbrv = TokenDeabbreviator::new(input_tokens.into_iter());
 
@@ -995,7 +1304,7 @@ mod test {
 
     #[test]
     fn converts_double_slash_to_descendant_or_self() {
-        let input_tokens: Vec<TokenResult> = vec![Ok(Token::DoubleSlash)];
+        let input_tokens = unspanned(vec![Token::DoubleSlash]);
 
         let deabbrv = TokenDeabbreviator::new(input_tokens.into_iter());
 
@@ -1012,7 +1321,7 @@ mod test {
 
     #[test]
     fn converts_current_node_to_self_node() {
-        let input_tokens: Vec<TokenResult> = vec![Ok(Token::CurrentNode)];
+        let input_tokens = unspanned(vec![Token::CurrentNode]);
 
         let deabbrv = TokenDeabbreviator::new(input_tokens.into_iter());
 
@@ -1027,7 +1336,7 @@ mod test {
 
     #[test]
     fn converts_parent_node_to_parent_node() {
-        let input_tokens: Vec<TokenResult> = vec![Ok(Token::ParentNode)];
+        let input_tokens = unspanned(vec![Token::ParentNode]);
 
         let deabbrv = TokenDeabbreviator::new(input_tokens.into_iter());
 
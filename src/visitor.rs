@@ -1,7 +1,49 @@
 //! Support for visiting XPaths.
-
-use crate::axis::Axis;
-use crate::expression::{Expression, Step, StepTest, Predicate, SubExpression};
+//!
+//! Every method of [`Visitor`][] has a default implementation that
+//! visits the children of the expression with the matching `walk_*`
+//! function, so a visitor only implements the methods for the
+//! expressions it is interested in. An overriding method can call the
+//! `walk_*` function itself to keep visiting the children.
+//!
+//! # Examples
+//!
+//! ```
+//! use sxd_xpath::visitor::{self, Predicate, Visitable, Visitor};
+//! use sxd_xpath::Factory;
+//!
+//! #[derive(Default)]
+//! struct Elements {
+//!     names: Vec<String>,
+//!     predicates: usize,
+//! }
+//!
+//! impl Visitor for Elements {
+//!     fn visit_element(&mut self, _prefix: Option<&str>, local_part: &str) {
+//!         self.names.push(local_part.to_string());
+//!     }
+//!
+//!     fn visit_predicate(&mut self, predicate: &Predicate) {
+//!         self.predicates += 1;
+//!         visitor::walk_predicate(self, predicate);
+//!     }
+//! }
+//!
+//! let xpath = Factory::new().build("/a/b[c]").expect("Could not compile XPath");
+//! let mut elements = Elements::default();
+//! xpath.visit(&mut elements);
+//!
+//! assert_eq!(elements.names, ["a", "b", "c"]);
+//! assert_eq!(elements.predicates, 1);
+//! ```
+//!
+//! [`Visitor`]: trait.Visitor.html
+
+pub use crate::axis::Axis;
+pub use crate::expression::{
+    Expression, MathOperator, Predicate, Quantifier, RelationalOperator, Step, StepTest,
+    SubExpression,
+};
 use crate::{LiteralValue, OwnedPrefixedName};
 
 pub trait Visitable {
@@ -17,193 +59,525 @@ where
     }
 }
 
-pub trait Visitor {
-    fn visit_and(&mut self, left: &SubExpression, right: &SubExpression);
+/// Converts a visitor into a trait object, so that the children of an
+/// expression can be visited with it. Implemented for every visitor.
+pub trait AsVisitor {
+    fn as_visitor(&mut self) -> &mut dyn Visitor;
+}
+
+impl<T: Visitor> AsVisitor for T {
+    fn as_visitor(&mut self) -> &mut dyn Visitor {
+        self
+    }
+}
+
+pub trait Visitor: AsVisitor {
+    fn visit_and(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_and(self, left, right)
+    }
 
-    fn visit_attribute(&mut self, prefix: Option<&str>, local_part: &str);
+    fn visit_attribute(&mut self, _prefix: Option<&str>, _local_part: &str) {}
 
-    fn visit_comment(&mut self);
+    fn visit_comment(&mut self) {}
 
-    fn visit_context_node(&mut self);
+    /// Visits an `if (condition) then a else b` expression.
+    fn visit_conditional(
+        &mut self,
+        condition: &SubExpression,
+        then: &SubExpression,
+        otherwise: &SubExpression,
+    ) {
+        walk_conditional(self, condition, then, otherwise)
+    }
 
-    fn visit_element(&mut self, prefix: Option<&str>, local_part: &str);
+    fn visit_context_node(&mut self) {}
 
-    fn visit_equal(&mut self, left: &SubExpression, right: &SubExpression);
+    fn visit_element(&mut self, _prefix: Option<&str>, _local_part: &str) {}
 
-    fn visit_filter(&mut self, node_selector: &SubExpression, predicate: &Predicate);
+    fn visit_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_equal(self, left, right)
+    }
 
-    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]);
+    fn visit_except(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_except(self, left, right)
+    }
 
-    fn visit_literal(&mut self, value: &LiteralValue);
+    fn visit_filter(&mut self, node_selector: &SubExpression, predicate: &Predicate) {
+        walk_filter(self, node_selector, predicate)
+    }
 
-    fn visit_math(
+    /// Visits a `for $x in a return b` expression. The variable is only
+    /// bound within the body.
+    fn visit_for(
         &mut self,
-        left: &SubExpression,
-        right: &SubExpression,
-        operation: &fn(f64, f64) -> f64,
-    );
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        body: &SubExpression,
+    ) {
+        walk_for(self, variable, sequence, body)
+    }
 
-    fn visit_namespace(&mut self, prefix: Option<&str>, local_part: &str);
+    fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+        walk_function(self, name, arguments)
+    }
 
-    fn visit_node(&mut self);
+    fn visit_intersect(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_intersect(self, left, right)
+    }
 
-    fn visit_negation(&mut self, expression: &SubExpression);
+    /// Visits a `let $x := a return b` expression. The variable is only
+    /// bound within the body.
+    fn visit_let(
+        &mut self,
+        variable: &OwnedPrefixedName,
+        value: &SubExpression,
+        body: &SubExpression,
+    ) {
+        walk_let(self, variable, value, body)
+    }
 
-    fn visit_not_equal(&mut self, left: &SubExpression, right: &SubExpression);
+    fn visit_literal(&mut self, _value: &LiteralValue) {}
 
-    fn visit_or(&mut self, left: &SubExpression, right: &SubExpression);
+    fn visit_math(&mut self, left: &SubExpression, right: &SubExpression, operator: MathOperator) {
+        walk_math(self, left, right, operator)
+    }
 
-    fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]);
+    fn visit_namespace(&mut self, _prefix: Option<&str>, _local_part: &str) {}
 
-    fn visit_processing_instruction(&mut self, target: Option<&str>);
+    fn visit_node(&mut self) {}
+
+    /// Visits the node test of a step, which in turn visits the kind
+    /// of node it selects, such as `visit_element` or `visit_text`.
+    fn visit_node_test(&mut self, node_test: &StepTest) {
+        walk_node_test(self, node_test)
+    }
+
+    fn visit_negation(&mut self, expression: &SubExpression) {
+        walk_negation(self, expression)
+    }
+
+    fn visit_not_equal(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_not_equal(self, left, right)
+    }
+
+    fn visit_or(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_or(self, left, right)
+    }
+
+    fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]) {
+        walk_path(self, start_point, steps)
+    }
+
+    /// Visits a predicate of a step or a filter.
+    fn visit_predicate(&mut self, predicate: &Predicate) {
+        walk_predicate(self, predicate)
+    }
+
+    fn visit_processing_instruction(&mut self, _target: Option<&str>) {}
+
+    /// Visits a `some $x in a satisfies b` or `every $x in a satisfies
+    /// b` expression. The variable is only bound within the condition.
+    fn visit_quantified(
+        &mut self,
+        quantifier: Quantifier,
+        variable: &OwnedPrefixedName,
+        sequence: &SubExpression,
+        condition: &SubExpression,
+    ) {
+        walk_quantified(self, quantifier, variable, sequence, condition)
+    }
+
+    fn visit_range(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_range(self, left, right)
+    }
 
     fn visit_relational(
         &mut self,
         left: &SubExpression,
         right: &SubExpression,
-        operation: &fn(f64, f64) -> bool,
-    );
+        operator: RelationalOperator,
+    ) {
+        walk_relational(self, left, right, operator)
+    }
 
-    fn visit_root_node(&mut self);
+    fn visit_root_node(&mut self) {}
 
-    fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]);
-    
-    fn visit_text(&mut self);
+    fn visit_sequence(&mut self, expressions: &[SubExpression]) {
+        walk_sequence(self, expressions)
+    }
+
+    fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
+        walk_step(self, axis, node_test, predicates)
+    }
 
-    fn visit_union(&mut self, left: &SubExpression, right: &SubExpression);
+    fn visit_text(&mut self) {}
+
+    fn visit_union(&mut self, left: &SubExpression, right: &SubExpression) {
+        walk_union(self, left, right)
+    }
 
-    fn visit_variable(&mut self, name: &OwnedPrefixedName);
+    fn visit_variable(&mut self, _name: &OwnedPrefixedName) {}
 
-    fn visit_xpath(&mut self, xpath: &std::boxed::Box<(dyn Expression + 'static)>);
+    fn visit_xpath(&mut self, xpath: &SubExpression) {
+        walk_xpath(self, xpath)
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::{Factory, LiteralValue, OwnedPrefixedName};
-    use crate::axis::Axis;
-    use crate::expression::{Expression, Predicate, Step, StepTest, SubExpression};
-    use crate::node_test::{Element, NameTest};
-    use crate::visitor::{Visitable, Visitor};
+pub fn walk_and<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-    #[derive(Debug)]
-    enum Node {
-	XPath(Box<Node>),
-	Path(Box<Node>, Vec<Node>),
-	RootNode,
-	None,
-	Step(Axis, StepTest, Vec<Node>),
+pub fn walk_conditional<V>(
+    visitor: &mut V,
+    condition: &SubExpression,
+    then: &SubExpression,
+    otherwise: &SubExpression,
+) where
+    V: Visitor + ?Sized,
+{
+    condition.visit(visitor.as_visitor());
+    then.visit(visitor.as_visitor());
+    otherwise.visit(visitor.as_visitor());
+}
+
+pub fn walk_equal<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
+
+pub fn walk_except<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
+
+pub fn walk_filter<V>(visitor: &mut V, node_selector: &SubExpression, predicate: &Predicate)
+where
+    V: Visitor + ?Sized,
+{
+    node_selector.visit(visitor.as_visitor());
+    visitor.visit_predicate(predicate);
+}
+
+pub fn walk_for<V>(
+    visitor: &mut V,
+    _variable: &OwnedPrefixedName,
+    sequence: &SubExpression,
+    body: &SubExpression,
+) where
+    V: Visitor + ?Sized,
+{
+    sequence.visit(visitor.as_visitor());
+    body.visit(visitor.as_visitor());
+}
+
+pub fn walk_function<V>(visitor: &mut V, _name: &OwnedPrefixedName, arguments: &[SubExpression])
+where
+    V: Visitor + ?Sized,
+{
+    for argument in arguments {
+        argument.visit(visitor.as_visitor());
     }
+}
 
-    impl Visitor for Node {
-	fn visit_and(&mut self, _left: &SubExpression, _right: &SubExpression) { }
+pub fn walk_intersect<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_attribute(&mut self, _prefix: Option<&str>, _local_part: &str) { }
+pub fn walk_let<V>(
+    visitor: &mut V,
+    _variable: &OwnedPrefixedName,
+    value: &SubExpression,
+    body: &SubExpression,
+) where
+    V: Visitor + ?Sized,
+{
+    value.visit(visitor.as_visitor());
+    body.visit(visitor.as_visitor());
+}
 
-	fn visit_comment(&mut self) { }
+pub fn walk_math<V>(
+    visitor: &mut V,
+    left: &SubExpression,
+    right: &SubExpression,
+    _operator: MathOperator,
+) where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_context_node(&mut self) { }
+pub fn walk_node_test<V>(visitor: &mut V, node_test: &StepTest)
+where
+    V: Visitor + ?Sized,
+{
+    node_test.visit(visitor.as_visitor());
+}
 
-	fn visit_element(&mut self, _prefix: Option<&str>, _local_part: &str) { }
+pub fn walk_negation<V>(visitor: &mut V, expression: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    expression.visit(visitor.as_visitor());
+}
 
-	fn visit_equal(&mut self, _left: &SubExpression, _right: &SubExpression) { }
+pub fn walk_not_equal<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_filter(&mut self, _node_selector: &SubExpression, _predicate: &Predicate) { }
+pub fn walk_or<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_function(&mut self, _name: &OwnedPrefixedName, _arguments: &[SubExpression]) { }
+pub fn walk_path<V>(visitor: &mut V, start_point: &SubExpression, steps: &[Step])
+where
+    V: Visitor + ?Sized,
+{
+    start_point.visit(visitor.as_visitor());
+    for step in steps {
+        step.visit(visitor.as_visitor());
+    }
+}
 
-	fn visit_literal(&mut self, _value: &LiteralValue) { }
+pub fn walk_predicate<V>(visitor: &mut V, predicate: &Predicate)
+where
+    V: Visitor + ?Sized,
+{
+    predicate.expression.visit(visitor.as_visitor());
+}
 
-	fn visit_math(
-	    &mut self,
-	    _left: &SubExpression,
-	    _right: &SubExpression,
-	    _operation: &fn(f64, f64) -> f64,
-	) { }
+pub fn walk_quantified<V>(
+    visitor: &mut V,
+    _quantifier: Quantifier,
+    _variable: &OwnedPrefixedName,
+    sequence: &SubExpression,
+    condition: &SubExpression,
+) where
+    V: Visitor + ?Sized,
+{
+    sequence.visit(visitor.as_visitor());
+    condition.visit(visitor.as_visitor());
+}
 
-	fn visit_namespace(&mut self, _prefix: Option<&str>, _local_part: &str) { }
+pub fn walk_range<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_node(&mut self) { }
+pub fn walk_relational<V>(
+    visitor: &mut V,
+    left: &SubExpression,
+    right: &SubExpression,
+    _operator: RelationalOperator,
+) where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_negation(&mut self, _expression: &SubExpression) { }
+pub fn walk_sequence<V>(visitor: &mut V, expressions: &[SubExpression])
+where
+    V: Visitor + ?Sized,
+{
+    for expression in expressions {
+        expression.visit(visitor.as_visitor());
+    }
+}
 
-	fn visit_not_equal(&mut self, _left: &SubExpression, _right: &SubExpression) { }
+pub fn walk_step<V>(visitor: &mut V, _axis: &Axis, node_test: &StepTest, predicates: &[Predicate])
+where
+    V: Visitor + ?Sized,
+{
+    visitor.visit_node_test(node_test);
+    for predicate in predicates {
+        visitor.visit_predicate(predicate);
+    }
+}
+
+pub fn walk_union<V>(visitor: &mut V, left: &SubExpression, right: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    left.visit(visitor.as_visitor());
+    right.visit(visitor.as_visitor());
+}
 
-	fn visit_or(&mut self, _left: &SubExpression, _right: &SubExpression) { }
+pub fn walk_xpath<V>(visitor: &mut V, xpath: &SubExpression)
+where
+    V: Visitor + ?Sized,
+{
+    xpath.visit(visitor.as_visitor());
+}
 
-	fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]) {
-	    let mut start_point_node = Node::None;
-	    start_point.visit(&mut start_point_node);
+#[cfg(test)]
+mod test {
+    use crate::axis::Axis;
+    use crate::expression::{Expression, Predicate, Step, StepTest, SubExpression};
+    use crate::node_test::{Element, NameTest};
+    use crate::visitor::{self, Visitable, Visitor};
+    use crate::{Factory, OwnedPrefixedName};
+
+    #[allow(dead_code, clippy::enum_variant_names)]
+    #[derive(Debug)]
+    enum Node {
+        XPath(Box<Node>),
+        Path(Box<Node>, Vec<Node>),
+        RootNode,
+        None,
+        Step(Axis, StepTest, Vec<Node>),
+    }
+
+    impl Visitor for Node {
+        fn visit_path(&mut self, start_point: &SubExpression, steps: &[Step]) {
+            let mut start_point_node = Node::None;
+            start_point.visit(&mut start_point_node);
+
+            let step_nodes = steps
+                .iter()
+                .map(|step| {
+                    let mut step_node = Node::None;
+                    step.visit(&mut step_node);
+                    step_node
+                })
+                .collect::<Vec<_>>();
+
+            *self = Node::Path(Box::new(start_point_node), step_nodes);
+        }
 
-	    let step_nodes = steps.iter().map(|step| {
-		let mut step_node = Node::None;
-		step.visit(&mut step_node);
-		step_node
-	    }).collect::<Vec<_>>();
-	    
-	    *self = Node::Path(Box::new(start_point_node), step_nodes);
-	}
+        fn visit_root_node(&mut self) {
+            *self = Node::RootNode;
+        }
 
-	fn visit_processing_instruction(&mut self, _target: Option<&str>) { }
+        fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
+            let predicate_nodes = predicates
+                .iter()
+                .map(|predicate| {
+                    let mut predicate_node = Node::None;
+                    predicate.expression.visit(&mut predicate_node);
+                    predicate_node
+                })
+                .collect::<Vec<_>>();
+
+            *self = Node::Step(*axis, node_test.clone_box(), predicate_nodes);
+        }
 
-	fn visit_relational(
-	    &mut self,
-	    _left: &SubExpression,
-	    _right: &SubExpression,
-	    _operation: &fn(f64, f64) -> bool,
-	) { }
+        fn visit_xpath(&mut self, xpath: &std::boxed::Box<dyn Expression + 'static>) {
+            let mut xpath_node = Node::None;
+            xpath.visit(&mut xpath_node);
 
-	fn visit_root_node(&mut self) {
-	    *self = Node::RootNode;
+            *self = Node::XPath(Box::new(xpath_node));
         }
+    }
 
-	fn visit_step(&mut self, axis: &Axis, node_test: &StepTest, predicates: &[Predicate]) {
-	    let predicate_nodes = predicates.iter().map(|predicate| {
-		let mut predicate_node = Node::None;
-		predicate.expression.visit(&mut predicate_node);
-		predicate_node
-	    }).collect::<Vec<_>>();
+    #[test]
+    fn visit() {
+        let xpath = Factory::new()
+            .build("/root")
+            .expect("Could not compile XPath");
+
+        let mut node = Node::None;
+        xpath.visit(&mut node);
+
+        assert_eq!(
+            format!("{:?}", node),
+            format!(
+                "{:?}",
+                Node::XPath(Box::new(Node::Path(
+                    Box::new(Node::RootNode),
+                    vec![Node::Step(
+                        Axis::Child,
+                        Box::new(Element::new(NameTest {
+                            prefix: None,
+                            local_part: "root".to_string()
+                        })),
+                        Vec::new()
+                    )],
+                ))),
+            ),
+        );
+    }
 
-	    *self = Node::Step(axis.clone(), node_test.clone_box(), predicate_nodes);
-	}
+    /// Records the names it visits, relying on the default methods to
+    /// reach them.
+    #[derive(Default)]
+    struct Names {
+        names: Vec<String>,
+        skip_predicates: bool,
+    }
 
-	fn visit_text(&mut self) { }
+    impl Visitor for Names {
+        fn visit_element(&mut self, _prefix: Option<&str>, local_part: &str) {
+            self.names.push(local_part.to_string());
+        }
 
-	fn visit_union(&mut self, _left: &SubExpression, _right: &SubExpression) { }
+        fn visit_attribute(&mut self, _prefix: Option<&str>, local_part: &str) {
+            self.names.push(format!("@{}", local_part));
+        }
 
-	fn visit_variable(&mut self, _name: &OwnedPrefixedName) { }
+        fn visit_function(&mut self, name: &OwnedPrefixedName, arguments: &[SubExpression]) {
+            self.names.push(format!("{}()", name));
+            visitor::walk_function(self, name, arguments);
+        }
+
+        fn visit_variable(&mut self, name: &OwnedPrefixedName) {
+            self.names.push(format!("${}", name));
+        }
 
-	fn visit_xpath(&mut self, xpath: &std::boxed::Box<(dyn Expression + 'static)>) {
-	    let mut xpath_node = Node::None;
-	    xpath.visit(&mut xpath_node);
+        fn visit_predicate(&mut self, predicate: &Predicate) {
+            if !self.skip_predicates {
+                visitor::walk_predicate(self, predicate);
+            }
+        }
+    }
 
-	    *self = Node::XPath(Box::new(xpath_node));
-	}
+    fn names(xpath: &str, skip_predicates: bool) -> Vec<String> {
+        let xpath = Factory::new()
+            .build(xpath)
+            .expect("Could not compile XPath");
+        let mut names = Names {
+            skip_predicates,
+            ..Default::default()
+        };
+        xpath.visit(&mut names);
+        names.names
     }
 
     #[test]
-    fn visit() {
-	let xpath = Factory::new().build("/root").expect("Could not compile XPath");
-
-	let mut node = Node::None;
-	xpath.visit(&mut node);
-
-	assert_eq!(
-	    format!("{:?}", node),
-	    format!("{:?}", Node::XPath(
-		Box::new(Node::Path(
-		    Box::new(Node::RootNode),
-		    vec![
-			Node::Step(
-			    Axis::Child,
-			    Box::new(Element::new(NameTest {
-				prefix: None,
-				local_part: "root".to_string()
-			    })),
-			    Vec::new()
-			)
-		    ],
-		))
-	    )),
-	);
+    fn default_methods_visit_every_child() {
+        assert_eq!(
+            names("-count(a[@b or $c]) + f($d)[e] | (g = h) * 2", false),
+            ["count()", "a", "@b", "$c", "f()", "$d", "e", "g", "h"]
+        );
+    }
+
+    #[test]
+    fn overriding_methods_can_stop_walking() {
+        assert_eq!(names("a[b]/c[d] | $e[f]", true), ["a", "c", "$e"]);
     }
 }
@@ -1,7 +1,18 @@
 use std::borrow::ToOwned;
+use std::cell::Cell;
+use std::rc::Rc;
+use sxd_document::QName;
 use sxd_document::{dom, parser};
+use sxd_xpath::ast::{Expr, Literal};
+use sxd_xpath::context::{
+    CancellationToken, EvaluationLimits, FunctionResolver, Limit, VariableResolver,
+};
+use sxd_xpath::fold::Fold;
 use sxd_xpath::{context, function, nodeset};
-use sxd_xpath::{evaluate_xpath, Context, Factory, Value};
+use sxd_xpath::{
+    evaluate_xpath, Context, ContextDependence, ExecutionError, Factory, Item, LanguageLevel,
+    OwnedPrefixedName, Value,
+};
 
 #[test]
 fn functions_accept_arguments() {
@@ -75,6 +86,35 @@ fn functions_with_qualified_names() {
     });
 }
 
+#[test]
+fn namespace_nodes_are_in_document_order() {
+    with_document("<a xmlns:z='uri:z' xmlns:b='uri:b' z:c='1'/>", |doc| {
+        let first = evaluate_xpath(&doc, "name((//namespace::*)[1])");
+        let last = evaluate_xpath(&doc, "name((/a/@* | /a/namespace::*)[last()])");
+
+        assert_eq!(Ok(Value::String("b".to_owned())), first);
+        assert_eq!(Ok(Value::String("z:c".to_owned())), last);
+    });
+}
+
+#[test]
+fn id_selects_elements_by_their_id_attribute() {
+    with_document("<a><b id='x'>1</b><b xml:id='y'>2</b>y x</a>", |doc| {
+        let result = evaluate_xpath(&doc, "sum(id(/a/text()))");
+
+        assert_eq!(Ok(Value::Number(3.0)), result);
+    });
+}
+
+#[test]
+fn lang_selects_by_the_nearest_language() {
+    with_document("<a xml:lang='en'><b xml:lang='de'/><b/></a>", |doc| {
+        let result = evaluate_xpath(&doc, "count(//b[lang('EN')])");
+
+        assert_eq!(Ok(Value::Number(1.0)), result);
+    });
+}
+
 #[test]
 fn nodesets_are_unique() {
     with_document("<a/>", |doc| {
@@ -84,6 +124,613 @@ fn nodesets_are_unique() {
     });
 }
 
+#[test]
+fn evaluation_stops_after_visiting_too_many_nodes() {
+    with_document("<a><b/><b/><b/></a>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_limits(EvaluationLimits {
+            max_nodes_visited: Some(5),
+            ..Default::default()
+        });
+
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a/b"));
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a/b"));
+        assert_eq!(
+            Some(Limit::NodesVisited),
+            setup.limit_exceeded(&doc, "/a/b/following-sibling::b")
+        );
+    });
+}
+
+#[test]
+fn positional_predicates_stop_selecting_early() {
+    with_document("<a><b/><b/><b/><b/><b/><b/><b/><b/></a>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_limits(EvaluationLimits {
+            max_nodes_visited: Some(5),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            None,
+            setup.limit_exceeded(&doc, "/a/b[1]/following-sibling::b[1]")
+        );
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a/b[position() < 3]"));
+        assert_eq!(
+            Some(Limit::NodesVisited),
+            setup.limit_exceeded(&doc, "/a/b[last()]")
+        );
+        assert_eq!(None, setup.limit_exceeded(&doc, "(//b)[1]"));
+        assert_eq!(None, setup.limit_exceeded(&doc, "(/descendant::b)[2]"));
+    });
+}
+
+#[test]
+fn reverse_axes_stop_selecting_early() {
+    with_document("<a><b><c>1</c><c>2</c><c>3</c></b><d/></a>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_limits(EvaluationLimits {
+            max_nodes_visited: Some(5),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            None,
+            setup.limit_exceeded(&doc, "/a/d/preceding::text()[1]")
+        );
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a/d/preceding::c[1]"));
+        assert_eq!(
+            Some(Limit::NodesVisited),
+            setup.limit_exceeded(&doc, "/a/d/preceding::b")
+        );
+
+        let setup = Setup::new();
+        let string = |xpath| setup.evaluate(&doc, xpath).string();
+
+        assert_eq!("3", string("/a/d/preceding::c[1]"));
+        assert_eq!("1", string("/a/d/preceding::c[last()]"));
+        assert_eq!("2", string("/a/d/preceding::text()[2]"));
+    });
+}
+
+#[test]
+fn positional_predicates_count_along_the_axis() {
+    with_document("<a><b>1</b><b>2</b><b>3</b><b>4</b></a>", |doc| {
+        let setup = Setup::new();
+        let string = |xpath| setup.evaluate(&doc, xpath).string();
+
+        assert_eq!("4", string("/a/b[last()]"));
+        assert_eq!("3", string("/a/b[last()]/preceding-sibling::b[1]"));
+        assert_eq!("1", string("(/a/b[last()]/preceding-sibling::b)[1]"));
+        assert_eq!("2", string("/a/b[2 >= position()][last()]"));
+        assert_eq!("", string("/a/b[5]"));
+    });
+}
+
+#[test]
+fn evaluation_stops_when_selecting_too_many_nodes() {
+    with_document("<a><b/><b/><b/></a>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_limits(EvaluationLimits {
+            max_nodeset_size: Some(2),
+            ..Default::default()
+        });
+
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a/b[1] | /a/b[2]"));
+        assert_eq!(Some(Limit::NodesetSize), setup.limit_exceeded(&doc, "/a/b"));
+        assert_eq!(
+            Some(Limit::NodesetSize),
+            setup.limit_exceeded(&doc, "/a/b[1] | /a/b[2] | /a/b[3]")
+        );
+    });
+}
+
+#[test]
+fn evaluation_stops_when_nested_too_deeply() {
+    with_document("<a><b><c/></b></a>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_limits(EvaluationLimits {
+            max_depth: Some(3),
+            ..Default::default()
+        });
+
+        assert_eq!(None, setup.limit_exceeded(&doc, "/a[b]"));
+        assert_eq!(Some(Limit::Depth), setup.limit_exceeded(&doc, "/a[b[c]]"));
+    });
+}
+
+#[test]
+fn evaluation_stops_when_cancelled() {
+    with_document("<a/>", |doc| {
+        let token = CancellationToken::new();
+        let mut setup = Setup::new();
+        setup.context.set_cancellation_token(token.clone());
+
+        assert!(setup.try_evaluate(&doc, "/a").is_ok());
+
+        token.cancel();
+        let result = setup.try_evaluate(&doc, "/a");
+
+        assert!(result
+            .expect_err("Evaluation was not cancelled")
+            .is_cancelled());
+    });
+}
+
+struct CountingResolver(Rc<Cell<usize>>);
+
+impl VariableResolver for CountingResolver {
+    fn resolve_variable<'c, 'd>(
+        &self,
+        context: &context::Evaluation<'c, 'd>,
+        name: QName<'_>,
+    ) -> Option<Value<'d>> {
+        self.0.set(self.0.get() + 1);
+        match name.local_part() {
+            "lazy" => Some(Value::Number(1.0)),
+            "here" => Some(Value::Nodeset(nodeset![context.node])),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn variables_are_resolved_when_they_are_not_set() {
+    with_document("<a/>", |doc| {
+        let resolved = Rc::new(Cell::new(0));
+        let mut setup = Setup::new();
+        setup.context.set_variable("eager", 2.0);
+        setup
+            .context
+            .set_variable_resolver(CountingResolver(resolved.clone()));
+
+        assert_eq!(Value::Number(3.0), setup.evaluate(&doc, "$lazy + $eager"));
+        assert_eq!(1, resolved.get());
+
+        let result = setup.try_evaluate(&doc, "$missing");
+        assert!(result.is_err());
+        assert_eq!(2, resolved.get());
+
+        assert_eq!(Value::Number(1.0), setup.evaluate(&doc, "count($here/a)"));
+    });
+}
+
+struct Constants;
+
+impl FunctionResolver for Constants {
+    fn resolve_function(&self, name: QName<'_>) -> Option<Rc<dyn function::Function>> {
+        match name.local_part() {
+            "one" => Some(Rc::new(ConstantValueFunction(1.0))),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn functions_are_resolved_when_they_are_not_registered() {
+    with_document("<a/>", |doc| {
+        let mut setup = Setup::new();
+        setup.context.set_function_resolver(Constants);
+
+        assert_eq!(
+            Value::Number(2.0),
+            setup.evaluate(&doc, "one() + count(/a)")
+        );
+        assert!(setup.try_evaluate(&doc, "two()").is_err());
+    });
+}
+
+fn number_of<'d>(context: &Context<'d>, root: dom::Root<'d>, xpath: &str) -> f64 {
+    let xpath = Factory::new().build(xpath).expect("Unable to build XPath");
+    xpath
+        .evaluate(context, root)
+        .expect("Unable to evaluate XPath")
+        .number()
+}
+
+#[test]
+fn child_contexts_fall_back_to_their_parent() {
+    with_document("<a xmlns='uri:a'><b/></a>", |doc| {
+        let mut base = Context::new();
+        base.set_namespace("a", "uri:a");
+        base.set_variable("shadowed", 1.0);
+        base.set_function("constant", ConstantValueFunction(10.0));
+        let base = Rc::new(base);
+
+        let mut child = Context::child(base.clone());
+        child.set_variable("shadowed", 2.0);
+        let child = Rc::new(child);
+
+        let mut grandchild = Context::child(child.clone());
+        grandchild.set_variable("own", 100.0);
+
+        let xpath = "count(/a:a/a:b) + constant() + $shadowed";
+        let own = format!("{} + $own", xpath);
+        assert_eq!(12.0, number_of(&base, doc.root(), xpath));
+        assert_eq!(13.0, number_of(&child, doc.root(), xpath));
+        assert_eq!(113.0, number_of(&grandchild, doc.root(), &own));
+    });
+}
+
+fn clamp(x: f64, low: Option<f64>, high: Option<f64>) -> f64 {
+    x.max(low.unwrap_or(0.0)).min(high.unwrap_or(1.0))
+}
+
+#[test]
+fn rust_functions_can_be_registered_with_typed_parameters() {
+    with_document("<a><b>2</b><b>3</b></a>", |doc| {
+        let mut context = Context::new();
+        context.set_function_fn("clamp", clamp);
+        context.set_function_fn("product", |xs: Vec<f64>| xs.iter().product::<f64>());
+
+        assert_eq!(1.0, number_of(&context, doc.root(), "clamp(/a/b[1])"));
+        assert_eq!(
+            2.0,
+            number_of(&context, doc.root(), "clamp(/a/b[1], 0, 10)")
+        );
+        assert_eq!(
+            6.0,
+            number_of(&context, doc.root(), "product(/a/b[1], /a/b[2])")
+        );
+    });
+}
+
+fn nonempty<'d>(nodes: nodeset::Nodeset<'d>) -> nodeset::Nodeset<'d> {
+    nodes
+        .into_iter()
+        .filter(|node| !node.string_value().is_empty())
+        .collect()
+}
+
+fn first<'d>(values: Vec<Value<'d>>) -> Value<'d> {
+    values.into_iter().next().unwrap_or(Value::Boolean(false))
+}
+
+#[test]
+fn rust_functions_can_take_and_return_nodes() {
+    with_document("<a><b>2</b><b/><b>3</b></a>", |doc| {
+        let mut context = Context::new();
+        context.set_function_fn("nonempty", nonempty);
+        context.set_function_fn("first", first);
+        context.set_function_fn("size", |nodes: nodeset::Nodeset| nodes.size() as f64);
+
+        assert_eq!(2.0, number_of(&context, doc.root(), "size(nonempty(//b))"));
+        assert_eq!(
+            5.0,
+            number_of(&context, doc.root(), "sum(first(nonempty(//b), 1))")
+        );
+
+        let xpath = Factory::new()
+            .build("size('b')")
+            .expect("Unable to build XPath");
+        assert_eq!(
+            Err(
+                "error while evaluating function: argument was expected to be a nodeset but was a String"
+                    .to_owned()
+            ),
+            xpath
+                .evaluate(&context, doc.root())
+                .map_err(|e| e.to_string())
+        );
+    });
+}
+
+#[test]
+fn core_functions_replaced_in_the_context_are_called() {
+    with_document("<a><b>1</b><b>2</b></a>", |doc| {
+        let mut context = Context::new();
+        context.set_function_fn("concat", |a: String, b: String| {
+            format!("{}{}", a, b).to_uppercase()
+        });
+        context.set_function_fn("last", || 1.0);
+        let evaluate = |xpath| {
+            let xpath = Factory::new().build(xpath).expect("Unable to build XPath");
+            xpath
+                .evaluate(&context, doc.root())
+                .expect("Unable to evaluate XPath")
+        };
+
+        assert_eq!("AB", evaluate("concat('a', 'b')").string());
+        assert_eq!("1", evaluate("/a/b[last()]").string());
+
+        let mut child = Context::child(Rc::new(Context::new()));
+        child.set_function_fn("position", || 2.0);
+        let xpath = Factory::new()
+            .build("/a/b[position() = 1]")
+            .expect("Unable to build XPath");
+        let value = xpath
+            .evaluate(&child, doc.root())
+            .expect("Unable to evaluate XPath");
+        assert_eq!("", value.string());
+    });
+}
+
+#[test]
+fn function_calls_are_checked_before_evaluation() {
+    let mut context = Context::new();
+    context.set_function_fn("double", |x: f64| x * 2.0);
+    let factory = Factory::new();
+    let check = |xpath| {
+        let xpath = factory.build(xpath).expect("Unable to build XPath");
+        xpath.check(&context).map_err(|e| e.to_string())
+    };
+
+    assert_eq!(Ok(()), check("double(count(//a))"));
+    assert_eq!(
+        Err("substring() expects at least 2 arguments but was given 1".to_owned()),
+        check("false() and substring('a')")
+    );
+    assert_eq!(
+        Err("double() expects at most 1 argument but was given 2".to_owned()),
+        check("double(1, 2)")
+    );
+}
+
+#[test]
+fn unresolved_references_are_found_without_evaluating() {
+    let mut context = Context::new();
+    context.set_namespace("a", "uri:a");
+    context.set_variable("known", 1.0);
+
+    let xpath = Factory::new()
+        .build("$known or /a:x[b:y] or $unknown[missing()]")
+        .expect("Unable to build XPath");
+    let diagnostics: Vec<_> = xpath
+        .validate(&context)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    assert_eq!(
+        vec![
+            "unknown namespace prefix b",
+            "unknown variable $unknown",
+            "unknown function missing()",
+        ],
+        diagnostics
+    );
+}
+
+#[test]
+fn unresolved_references_are_found_in_branches_that_are_never_taken() {
+    let mut factory = Factory::new();
+    factory.set_optimize(true);
+    let xpath = factory
+        .build("false() and $typo or nosuch:f()")
+        .expect("Unable to build XPath");
+    let diagnostics: Vec<_> = xpath
+        .validate(&Context::new())
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    assert_eq!(
+        vec!["unknown variable $typo", "unknown namespace prefix nosuch"],
+        diagnostics
+    );
+}
+
+#[test]
+fn dependencies_are_found_without_evaluating() {
+    let xpath = Factory::new()
+        .build("sum(/p:order/p:item[@id = $ids]/@price) * $rate")
+        .expect("Unable to build XPath");
+    let names = |names: Vec<OwnedPrefixedName>| -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    };
+
+    assert_eq!(vec!["ids", "rate"], names(xpath.variables()));
+    assert_eq!(vec!["sum"], names(xpath.functions()));
+    assert_eq!(vec!["p"], xpath.namespace_prefixes());
+    assert_eq!(
+        ContextDependence {
+            node: true,
+            position: false,
+            size: false,
+        },
+        xpath.context_dependence()
+    );
+}
+
+#[test]
+fn simplified_xpaths_select_the_same_nodes() {
+    with_document("<a><b>1</b><b>2</b><b>3</b></a>", |doc| {
+        let result = evaluate_xpath(&doc, "string(//b[position() = 1 + 1][true() and .])");
+
+        assert_eq!(Ok(Value::String("2".to_owned())), result);
+    });
+}
+
+struct InlineVariables;
+
+impl Fold for InlineVariables {
+    fn fold_variable(&mut self, name: OwnedPrefixedName) -> Expr {
+        match name.local_part() {
+            "minimum" => Expr::Literal(Literal::Number(2.0)),
+            _ => Expr::Variable(name),
+        }
+    }
+}
+
+#[test]
+fn rewritten_xpaths_can_be_evaluated() {
+    with_document("<a><b n='1'/><b n='2'/><b n='3'/></a>", |doc| {
+        let xpath = Factory::new()
+            .build("count(//b[@n >= $minimum])")
+            .expect("Unable to build XPath")
+            .fold(&mut InlineVariables);
+
+        assert!(xpath.variables().is_empty());
+        assert_eq!(
+            Ok(Value::Number(2.0)),
+            xpath.evaluate(&Context::new(), doc.root())
+        );
+    });
+}
+
+#[test]
+fn conditionals_require_xpath_2_and_evaluate_one_branch() {
+    with_document("<a b='1'/>", |doc| {
+        let xpath = "if (/a/@b) then number(/a/@b) + 1 else $missing";
+        let mut setup = Setup::new();
+        assert!(setup.factory.build(xpath).is_err());
+
+        setup.factory.set_language_level(LanguageLevel::XPath2);
+        assert_eq!(Value::Number(2.0), setup.evaluate(&doc, xpath));
+        assert_eq!(
+            Value::String("none".into()),
+            setup.evaluate(&doc, "if (/a/@c) then f() else 'none'")
+        );
+    });
+}
+
+#[test]
+fn quantified_expressions_test_each_node() {
+    with_document("<order><item price='2'/><item price='0'/></order>", |doc| {
+        let mut setup = Setup::new();
+        setup.factory.set_language_level(LanguageLevel::XPath2);
+        setup.context.set_variable("i", 1.0);
+
+        let evaluate = |xpath| setup.evaluate(&doc, xpath);
+        assert_eq!(
+            Value::Boolean(false),
+            evaluate("every $i in //item satisfies $i/@price > 0")
+        );
+        assert_eq!(
+            Value::Boolean(true),
+            evaluate("some $i in //item satisfies $i/@price > 0")
+        );
+        assert_eq!(
+            Value::Boolean(false),
+            evaluate("some $i in //item satisfies $i/@price > 2")
+        );
+        assert_eq!(
+            Value::Boolean(true),
+            evaluate("every $i in /none satisfies false()")
+        );
+        assert_eq!(
+            Value::Boolean(true),
+            evaluate("(some $i in //item satisfies $i/@price = 0) and $i = 1")
+        );
+    });
+}
+
+#[test]
+fn local_variables_are_bound_by_for_and_let() {
+    with_document(
+        "<a><order id='1'><item/></order><order id='2'><item/><item/></order>\
+         <ref to='2'/><ref to='1'/></a>",
+        |doc| {
+            let mut setup = Setup::new();
+            let xpath = "let $o := //order[@id = //ref[1]/@to] return count($o/item)";
+
+            setup.factory.set_language_level(LanguageLevel::XPath2);
+            assert!(setup.factory.build(xpath).is_err());
+
+            setup.factory.set_language_level(LanguageLevel::XPath3);
+            assert_eq!(Value::Number(2.0), setup.evaluate(&doc, xpath));
+            assert_eq!(
+                Value::Number(3.0),
+                setup.evaluate(
+                    &doc,
+                    "count(for $r in //ref return //order[@id = $r/@to]/item)"
+                )
+            );
+        },
+    );
+}
+
+#[test]
+fn sequences_are_built_from_ranges_and_commas() {
+    with_document("<a><b>3</b><b>1</b></a>", |doc| {
+        let mut setup = Setup::new();
+        assert!(setup.factory.build("1 to 3").is_err());
+        assert!(setup.factory.build("(1, 2)").is_err());
+
+        setup.factory.set_language_level(LanguageLevel::XPath2);
+        let numbers = |n: &[f64]| Value::Sequence(n.iter().cloned().map(Item::Number).collect());
+
+        assert_eq!(numbers(&[1.0, 2.0, 3.0]), setup.evaluate(&doc, "1 to 3"));
+        assert_eq!(numbers(&[]), setup.evaluate(&doc, "3 to 1"));
+        assert_eq!(
+            numbers(&[2.0, 4.0, 6.0]),
+            setup.evaluate(&doc, "for $i in 1 to 3 return $i * 2")
+        );
+        assert_eq!(
+            Value::String("3".into()),
+            setup.evaluate(&doc, "string((//b, 'x'))")
+        );
+        assert_eq!(Value::Number(3.0), setup.evaluate(&doc, "count((//b, /a))"));
+        assert_eq!(Value::Boolean(true), setup.evaluate(&doc, "//b = (0, 1)"));
+        assert_eq!(Value::Boolean(false), setup.evaluate(&doc, "boolean(())"));
+
+        assert_eq!(numbers(&[2.0]), setup.evaluate(&doc, "(1 to 3)[2]"));
+        assert_eq!(numbers(&[1.0]), setup.evaluate(&doc, "(1, 2)[1]"));
+        assert_eq!(
+            numbers(&[4.0, 5.0]),
+            setup.evaluate(&doc, "(1 to 5)[number() > 3]")
+        );
+        assert_eq!(
+            numbers(&[6.0]),
+            setup.evaluate(&doc, "(5, 6)[string() = '6'][last()]")
+        );
+        assert_eq!(
+            Value::String("1".into()),
+            setup.evaluate(&doc, "string((0, //b)[3])")
+        );
+
+        assert_eq!(Value::Number(3.0), setup.evaluate(&doc, "count(1 to 3)"));
+        assert_eq!(Value::Number(3.0), setup.evaluate(&doc, "count((1, 2, 3))"));
+        assert_eq!(Value::Number(0.0), setup.evaluate(&doc, "count(())"));
+        assert_eq!(Value::Number(10.0), setup.evaluate(&doc, "sum(1 to 4)"));
+        assert_eq!(Value::Number(5.0), setup.evaluate(&doc, "sum((1, //b))"));
+
+        let invalid = &["1 to 1 div 0", "1.5 to 3", "number('x') to 3"];
+        for xpath in invalid {
+            assert!(setup.try_evaluate(&doc, xpath).is_err(), "{}", xpath);
+        }
+
+        let error = setup
+            .try_evaluate(&doc, "count(1 to 1000000000000000000)")
+            .unwrap_err();
+        assert_eq!("range has more than 16777216 numbers", error.to_string());
+
+        setup.context.set_limits(EvaluationLimits {
+            max_nodeset_size: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(
+            Some(Limit::NodesetSize),
+            setup.limit_exceeded(&doc, "1 to 3")
+        );
+    });
+}
+
+#[test]
+fn nodesets_can_be_intersected_and_subtracted() {
+    with_document("<a><b x='1'>1</b><b>2</b><c x='3'>3</c></a>", |doc| {
+        let mut setup = Setup::new();
+        assert!(setup.factory.build("//b except //b[@x]").is_err());
+
+        setup.factory.set_language_level(LanguageLevel::XPath2);
+        assert_eq!(
+            Value::String("2".into()),
+            setup.evaluate(&doc, "string(//b except //b[@x])")
+        );
+        assert_eq!(
+            Value::Number(1.0),
+            setup.evaluate(&doc, "count(//b intersect //*[@x])")
+        );
+        assert_eq!(
+            Value::String("13".into()),
+            setup.evaluate(
+                &doc,
+                "concat(//*[@x] except //b[2], (//c | //b) intersect //*[@x][2])"
+            )
+        );
+    });
+}
+
 fn with_document<F>(xml: &str, f: F)
 where
     F: FnOnce(dom::Document<'_>),
@@ -104,11 +751,24 @@ impl<'d> Setup<'d> {
     }
 
     fn evaluate(&self, doc: &'d dom::Document<'d>, xpath: &str) -> Value<'d> {
-        let xpath = self.factory.build(xpath).expect("Unable to build XPath");
-        xpath
-            .evaluate(&self.context, doc.root())
+        self.try_evaluate(doc, xpath)
             .expect("Unable to evaluate XPath")
     }
+
+    fn try_evaluate(
+        &self,
+        doc: &'d dom::Document<'d>,
+        xpath: &str,
+    ) -> Result<Value<'d>, ExecutionError> {
+        let xpath = self.factory.build(xpath).expect("Unable to build XPath");
+        xpath.evaluate(&self.context, doc.root())
+    }
+
+    fn limit_exceeded(&self, doc: &'d dom::Document<'d>, xpath: &str) -> Option<Limit> {
+        self.try_evaluate(doc, xpath)
+            .err()
+            .and_then(|e| e.limit_exceeded())
+    }
 }
 
 struct ConstantValueFunction(f64);